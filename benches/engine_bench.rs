@@ -0,0 +1,83 @@
+use std::collections::{BTreeMap, HashMap};
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use rust_decimal::Decimal;
+use sailors_superstitions::{handle, ClientId, Transaction, TransactionKind, TxId};
+
+fn deposit(client: u16, tx: u32) -> Transaction<Decimal> {
+    Transaction::new(
+        TransactionKind::Deposit {
+            amount: Decimal::new(150, 2),
+        },
+        ClientId(client),
+        TxId(tx),
+    )
+}
+
+// Runs `txs` through `handle` on empty stores
+fn bench_handle(c: &mut Criterion, name: &str, txs: Vec<Transaction<Decimal>>) {
+    let mut group = c.benchmark_group(name);
+    group.throughput(Throughput::Elements(txs.len() as u64));
+    group.sample_size(20);
+    group.bench_function("handle", |b| {
+        b.iter_batched(
+            || (BTreeMap::new(), HashMap::new()),
+            |(mut client_store, mut tx_store)| {
+                for tx in &txs {
+                    let _ = handle(tx, &mut client_store, &mut tx_store);
+                }
+                (client_store, tx_store)
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+fn many_clients(c: &mut Criterion) {
+    let txs = (0..100_000)
+        .map(|tx| deposit((tx % 10_000) as u16, tx))
+        .collect();
+    bench_handle(c, "100k deposits over 10k clients", txs);
+}
+
+fn deposits_then_disputes(c: &mut Criterion) {
+    let deposits = (0..50_000).map(|tx| deposit((tx % 10_000) as u16, tx));
+    let disputes = (0..50_000).map(|tx| {
+        Transaction::new(
+            TransactionKind::Dispute { reason: None },
+            ClientId((tx % 10_000) as u16),
+            TxId(tx),
+        )
+    });
+    bench_handle(
+        c,
+        "50k deposits then 50k disputes",
+        deposits.chain(disputes).collect(),
+    );
+}
+
+// Everything lands on the same client, so its history keeps growing
+fn single_client(c: &mut Criterion) {
+    let txs = (0..100_000)
+        .map(|tx| match tx % 4 {
+            0 | 1 => deposit(1, tx),
+            2 => Transaction::new(
+                TransactionKind::Withdrawal {
+                    amount: Decimal::ONE,
+                },
+                ClientId(1),
+                TxId(tx),
+            ),
+            _ => Transaction::new(
+                TransactionKind::Dispute { reason: None },
+                ClientId(1),
+                TxId(tx - 3),
+            ),
+        })
+        .collect();
+    bench_handle(c, "100k transactions on one client", txs);
+}
+
+criterion_group!(benches, many_clients, deposits_then_disputes, single_client);
+criterion_main!(benches);
@@ -0,0 +1,56 @@
+#![no_main]
+
+use libfuzzer_sys::{arbitrary::Unstructured, fuzz_target};
+use rust_decimal::Decimal;
+use sailors_superstitions::{ClientId, Engine, Transaction, TransactionKind, TxId};
+
+// Turns one tuple into a transaction. The first 16 bytes of `bytes` are the
+// mantissa, shifted down so it always fits in a `Decimal`, the next one is the
+// scale and the last one is the destination of a transfer.
+fn transaction((kind, client, tx, bytes): (u8, u16, u32, [u8; 18])) -> Transaction<Decimal> {
+    let mut mantissa = [0; 16];
+    mantissa.copy_from_slice(&bytes[..16]);
+    let amount = Decimal::from_i128_with_scale(
+        i128::from_le_bytes(mantissa) >> 32,
+        u32::from(bytes[16] % 29),
+    );
+    let kind = match kind % 10 {
+        0 => TransactionKind::Deposit { amount },
+        1 => TransactionKind::Withdrawal { amount },
+        2 => TransactionKind::Fee { amount },
+        3 => TransactionKind::Transfer {
+            to_client: ClientId(u16::from(bytes[17])),
+            amount,
+        },
+        4 => TransactionKind::Dispute { reason: None },
+        5 => TransactionKind::Resolve,
+        6 => TransactionKind::PartialDispute { amount },
+        7 => TransactionKind::Reversal,
+        8 => TransactionKind::Adjustment { amount },
+        _ => TransactionKind::Chargeback,
+    };
+    Transaction::new(kind, ClientId(client), TxId(tx))
+}
+
+fuzz_target!(|data: &[u8]| {
+    // The bytes as a sequence of transactions
+    let mut engine = Engine::new();
+    let mut input = Unstructured::new(data);
+    if let Ok(tuples) = input.arbitrary::<Vec<(u8, u16, u32, [u8; 18])>>() {
+        for tuple in tuples {
+            let _ = engine.process(&transaction(tuple));
+        }
+    }
+
+    // The same bytes as a CSV file
+    if let Ok(text) = std::str::from_utf8(data) {
+        let mut engine = Engine::<Decimal>::new();
+        let mut reader = csv::Reader::from_reader(text.as_bytes());
+        for record in reader.records().flatten() {
+            if let Ok(tx) = Transaction::try_from(record) {
+                let _ = engine.process(&tx);
+            }
+        }
+        let _ = engine.client_snapshots();
+    }
+});
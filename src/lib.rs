@@ -1,49 +1,993 @@
-use core::ops::{AddAssign, SubAssign};
-use std::{borrow::Cow, collections::HashMap};
+#![cfg_attr(not(feature = "std"), no_std)]
+
+#[cfg(not(feature = "std"))]
+extern crate alloc;
+
+#[cfg(not(feature = "std"))]
+use alloc::{
+    borrow::Cow,
+    boxed::Box,
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    format,
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+use core::{
+    fmt::Display,
+    hash::{Hash, Hasher},
+    ops::{AddAssign, SubAssign},
+    str::FromStr,
+};
+#[cfg(feature = "std")]
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    io::{self, Read, Write},
+    sync::{Mutex, MutexGuard},
+};
+
+#[cfg(feature = "std")]
+use csv::StringRecord;
+use num_traits::{CheckedAdd, CheckedSub};
+
+// Map
+//
+// Transactions, assets and error counts are all looked up by key. With `std`
+// that's a `HashMap`; without it, `alloc`'s `BTreeMap` does the same job.
+#[cfg(feature = "std")]
+pub type Map<K, V> = HashMap<K, V>;
+#[cfg(not(feature = "std"))]
+pub type Map<K, V> = BTreeMap<K, V>;
+
+#[cfg(feature = "std")]
+type Set<K> = HashSet<K>;
+#[cfg(not(feature = "std"))]
+type Set<K> = BTreeSet<K>;
+
+#[cfg(feature = "std")]
+fn map_with_capacity<K, V>(capacity: usize) -> Map<K, V> {
+    HashMap::with_capacity(capacity)
+}
+
+// A `BTreeMap` can't preallocate, so the capacity is only a hint
+#[cfg(not(feature = "std"))]
+fn map_with_capacity<K, V>(_capacity: usize) -> Map<K, V> {
+    BTreeMap::new()
+}
+
+// Amount
+//
+// Everything the engine needs from a money type. Overflow is handled through
+// `CheckedAdd` and `CheckedSub`, so anything from `Decimal` to a plain integer
+// works, and `Debug` is only there to put rejected amounts in error messages.
+pub trait Amount:
+    Default + AddAssign + SubAssign + PartialOrd + CheckedAdd + CheckedSub + Copy + core::fmt::Debug
+{
+    fn is_positive(&self) -> bool {
+        *self > Self::default()
+    }
+}
+
+impl<T> Amount for T where
+    T: Default
+        + AddAssign
+        + SubAssign
+        + PartialOrd
+        + CheckedAdd
+        + CheckedSub
+        + Copy
+        + core::fmt::Debug
+{
+}
+
+// Ids
+//
+// Client and transaction ids get their own types so one can't be passed where
+// the other is expected. They print and parse as the plain numbers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(transparent)
+)]
+pub struct ClientId(pub u16);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(transparent)
+)]
+pub struct TxId(pub u32);
+
+impl From<u16> for ClientId {
+    fn from(id: u16) -> Self {
+        ClientId(id)
+    }
+}
+
+impl From<ClientId> for u16 {
+    fn from(id: ClientId) -> Self {
+        id.0
+    }
+}
+
+impl From<u32> for TxId {
+    fn from(id: u32) -> Self {
+        TxId(id)
+    }
+}
+
+impl From<TxId> for u32 {
+    fn from(id: TxId) -> Self {
+        id.0
+    }
+}
+
+impl Display for ClientId {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl Display for TxId {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl FromStr for ClientId {
+    type Err = core::num::ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(ClientId)
+    }
+}
+
+impl FromStr for TxId {
+    type Err = core::num::ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(TxId)
+    }
+}
 
 // Transaction
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Transaction<T> {
     pub kind: TransactionKind<T>,
-    pub client: u16,
-    pub tx: u32,
+    pub client: ClientId,
+    pub tx: TxId,
+    // The batch (usually the input file) this transaction came in with, if any
+    pub source: Option<u32>,
+    // The asset a deposit or withdrawal moves, or `None` for the client's main
+    // balance. Transactions referencing another one always use the referenced
+    // transaction's asset, whatever this says.
+    pub asset: Option<String>,
+    // What the amount is in, or `None` for the engine's default currency. Only
+    // transactions that move money have to match the client's.
+    pub currency: Option<Currency>,
+    // When it happened, in seconds since the Unix epoch, if the input said. The
+    // engine goes by processing order either way; this is only for sorting
+    // inputs that arrive out of order.
+    pub timestamp: Option<u64>,
     status: TransactionStatus,
+    // How much of it was put in dispute, which is all of it unless the dispute
+    // was a partial one
+    disputed: Option<T>,
+    // Why it was disputed, if the dispute said
+    dispute_reason: Option<DisputeReason>,
 }
 
-#[derive(Debug, Clone, Copy)]
+// New kinds keep being added, so matches on this outside the crate need a
+// wildcard arm. The engine rejects any kind it can't handle with
+// `EngineError::UnsupportedKind`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
 pub enum TransactionKind<T> {
-    Deposit { amount: T },
-    Withdrawal { amount: T },
-    Dispute,
+    Deposit {
+        amount: T,
+    },
+    Withdrawal {
+        amount: T,
+    },
+    // A service fee, taken out of the available funds like a withdrawal. It's
+    // stored like one too, so its id can't be reused, but fees are final and
+    // can't be disputed.
+    Fee {
+        amount: T,
+    },
+    // Moves funds from the transaction's client to another one, all at once,
+    // creating the destination if needed. Like fees, transfers can't be disputed.
+    Transfer {
+        to_client: ClientId,
+        amount: T,
+    },
+    // A manual correction to the available funds, crediting a positive amount
+    // and debiting a negative one. The amount can go either way, but a debit
+    // still can't take the client below zero unless the config allows it. Like
+    // a fee, it's stored but can't be disputed.
+    Adjustment {
+        amount: T,
+    },
+    // The reason is only kept for the record, and a dispute goes through the
+    // same way whatever it says
+    Dispute {
+        reason: Option<DisputeReason>,
+    },
+    // Disputes only `amount` of the referenced transaction, which the resolve or
+    // chargeback that follows then sticks to
+    PartialDispute {
+        amount: T,
+    },
     Resolve,
     Chargeback,
+    // Manually undoes the referenced deposit or withdrawal, as long as it isn't
+    // in dispute. Unlike a chargeback, the client stays unlocked.
+    Reversal,
+    // Stands in for a kind the engine doesn't know how to handle yet
+    #[cfg(test)]
+    Unsupported,
 }
 
-#[derive(Debug, Clone, Copy)]
-enum TransactionStatus {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum TransactionStatus {
     Started,
     Disputed,
     Resolved,
     Chargeback,
+    // Undone by a reversal, rather than a chargeback
+    Reversed,
+}
+
+// What a client's money is in. Anything that isn't one of the usual ones is
+// kept by its code, as it was written.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Currency {
+    #[default]
+    Usd,
+    Eur,
+    Gbp,
+    Other(String),
+}
+
+/// Currencies print as their upper case code, and parse whatever the casing.
+///
+/// ```
+/// use sailors_superstitions::Currency;
+///
+/// let currency: Currency = "eur".parse().unwrap();
+/// assert_eq!(currency, Currency::Eur);
+/// assert_eq!(currency.to_string(), "EUR");
+/// let other: Currency = "JPY".parse().unwrap();
+/// assert_eq!(other, Currency::Other("JPY".to_string()));
+/// ```
+impl Display for Currency {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Currency::Usd => f.write_str("USD"),
+            Currency::Eur => f.write_str("EUR"),
+            Currency::Gbp => f.write_str("GBP"),
+            Currency::Other(code) => f.write_str(code),
+        }
+    }
+}
+
+impl FromStr for Currency {
+    type Err = core::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let currency = [Currency::Usd, Currency::Eur, Currency::Gbp]
+            .into_iter()
+            .find(|currency| currency.to_string().eq_ignore_ascii_case(s))
+            .unwrap_or_else(|| Currency::Other(s.to_string()));
+        Ok(currency)
+    }
+}
+
+// Why a client disputed a transaction. Anything that isn't one of the usual
+// reasons is kept as it was written.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum DisputeReason {
+    UnauthorizedCharge,
+    ItemNotReceived,
+    CounterfeitCard,
+    Other(String),
+}
+
+/// Reasons print in snake case and parse whatever their casing, with or
+/// without underscores.
+///
+/// ```
+/// use sailors_superstitions::DisputeReason;
+///
+/// let reason: DisputeReason = "Item_Not_Received".parse().unwrap();
+/// assert_eq!(reason, DisputeReason::ItemNotReceived);
+/// assert_eq!(reason.to_string(), "item_not_received");
+/// let other: DisputeReason = "changed my mind".parse().unwrap();
+/// assert_eq!(other, DisputeReason::Other("changed my mind".to_string()));
+/// ```
+impl Display for DisputeReason {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            DisputeReason::UnauthorizedCharge => f.write_str("unauthorized_charge"),
+            DisputeReason::ItemNotReceived => f.write_str("item_not_received"),
+            DisputeReason::CounterfeitCard => f.write_str("counterfeit_card"),
+            DisputeReason::Other(reason) => f.write_str(reason),
+        }
+    }
+}
+
+impl FromStr for DisputeReason {
+    type Err = core::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let known = [
+            DisputeReason::UnauthorizedCharge,
+            DisputeReason::ItemNotReceived,
+            DisputeReason::CounterfeitCard,
+        ];
+        // "ItemNotReceived" and "item_not_received" are the same reason
+        let squashed = |s: &str| -> String {
+            s.chars()
+                .filter(|c| *c != '_')
+                .map(|c| c.to_ascii_lowercase())
+                .collect()
+        };
+        let reason = known
+            .into_iter()
+            .find(|reason| squashed(&reason.to_string()) == squashed(s))
+            .unwrap_or_else(|| DisputeReason::Other(s.to_string()));
+        Ok(reason)
+    }
 }
 
 impl<T> Transaction<T> {
-    pub fn new(kind: TransactionKind<T>, client: u16, tx: u32) -> Self {
+    pub fn new(kind: TransactionKind<T>, client: ClientId, tx: TxId) -> Self {
         Self {
             kind,
             client,
             tx,
+            source: None,
+            asset: None,
+            currency: None,
+            timestamp: None,
             status: TransactionStatus::Started,
+            disputed: None,
+            dispute_reason: None,
+        }
+    }
+
+    // Where the transaction is in the dispute process
+    pub fn status(&self) -> TransactionStatus {
+        self.status
+    }
+
+    // How much of the transaction was put in dispute, or `None` if it never was
+    pub fn disputed_amount(&self) -> Option<&T> {
+        self.disputed.as_ref()
+    }
+
+    // Why the transaction was put in dispute, if it ever was and the dispute gave
+    // a reason
+    pub fn dispute_reason(&self) -> Option<&DisputeReason> {
+        self.dispute_reason.as_ref()
+    }
+
+    // Moves the transaction's funds in `asset` instead of the main balance
+    pub fn with_asset(mut self, asset: impl Into<String>) -> Self {
+        self.asset = Some(asset.into());
+        self
+    }
+
+    // Makes the transaction's amount one in `currency`
+    pub fn with_currency(mut self, currency: Currency) -> Self {
+        self.currency = Some(currency);
+        self
+    }
+
+    // Stamps the transaction with when it happened, in seconds since the epoch
+    pub fn with_timestamp(mut self, timestamp: u64) -> Self {
+        self.timestamp = Some(timestamp);
+        self
+    }
+
+    // Tags the transaction with the batch it came from
+    pub fn with_source(mut self, source: u32) -> Self {
+        self.source = Some(source);
+        self
+    }
+}
+
+// Transaction Builder
+
+/// Builds a transaction a field at a time, checking that it has everything
+/// `Transaction::new` would need and an amount that makes sense. It's meant for
+/// callers putting transactions together by hand. Most of the tests stick to
+/// `Transaction::new`, which doesn't check anything, so they can feed the engine
+/// transactions it has to reject.
+///
+/// ```
+/// use rust_decimal::Decimal;
+/// use sailors_superstitions::{ClientId, TransactionBuilder, TransactionKind, TxId};
+///
+/// let tx = TransactionBuilder::<Decimal>::deposit(100)
+///     .client(ClientId(1))
+///     .tx_id(TxId(42))
+///     .build()
+///     .unwrap();
+/// assert_eq!(tx.kind, TransactionKind::Deposit { amount: Decimal::from(100) });
+///
+/// // Every transaction needs a client and an id
+/// assert!(TransactionBuilder::<Decimal>::resolve().tx_id(TxId(42)).build().is_err());
+/// // and money only moves one way
+/// let withdrawal = TransactionBuilder::<Decimal>::withdrawal(-5);
+/// assert!(withdrawal.client(ClientId(1)).tx_id(TxId(43)).build().is_err());
+/// ```
+#[derive(Debug, Clone)]
+pub struct TransactionBuilder<T> {
+    kind: Option<TransactionKind<T>>,
+    client: Option<ClientId>,
+    tx: Option<TxId>,
+    source: Option<u32>,
+    asset: Option<String>,
+    currency: Option<Currency>,
+    timestamp: Option<u64>,
+}
+
+impl<T> Default for TransactionBuilder<T> {
+    fn default() -> Self {
+        Self {
+            kind: None,
+            client: None,
+            tx: None,
+            source: None,
+            asset: None,
+            currency: None,
+            timestamp: None,
+        }
+    }
+}
+
+impl<T> TransactionBuilder<T> {
+    // A builder with nothing set, not even the kind
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn deposit(amount: impl Into<T>) -> Self {
+        Self::new().kind(TransactionKind::Deposit {
+            amount: amount.into(),
+        })
+    }
+
+    pub fn withdrawal(amount: impl Into<T>) -> Self {
+        Self::new().kind(TransactionKind::Withdrawal {
+            amount: amount.into(),
+        })
+    }
+
+    pub fn dispute() -> Self {
+        Self::new().kind(TransactionKind::Dispute { reason: None })
+    }
+
+    pub fn resolve() -> Self {
+        Self::new().kind(TransactionKind::Resolve)
+    }
+
+    pub fn chargeback() -> Self {
+        Self::new().kind(TransactionKind::Chargeback)
+    }
+
+    // Any other kind, or a different one than the builder started with
+    pub fn kind(mut self, kind: TransactionKind<T>) -> Self {
+        self.kind = Some(kind);
+        self
+    }
+
+    pub fn client(mut self, client: ClientId) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    pub fn tx_id(mut self, tx: TxId) -> Self {
+        self.tx = Some(tx);
+        self
+    }
+
+    pub fn with_source(mut self, source: u32) -> Self {
+        self.source = Some(source);
+        self
+    }
+
+    pub fn with_asset(mut self, asset: impl Into<String>) -> Self {
+        self.asset = Some(asset.into());
+        self
+    }
+
+    pub fn with_currency(mut self, currency: Currency) -> Self {
+        self.currency = Some(currency);
+        self
+    }
+
+    pub fn with_timestamp(mut self, timestamp: u64) -> Self {
+        self.timestamp = Some(timestamp);
+        self
+    }
+}
+
+impl<T: Amount> TransactionBuilder<T> {
+    // Fails if the kind, client or id is missing, or if the amount of anything
+    // but an adjustment isn't positive, which the engine would reject anyway
+    pub fn build(self) -> Result<Transaction<T>, Cow<'static, str>> {
+        let kind = self.kind.ok_or("transaction has no kind")?;
+        let client = self.client.ok_or("transaction has no client")?;
+        let tx = self.tx.ok_or("transaction has no id")?;
+        let amount = match &kind {
+            TransactionKind::Adjustment { .. } => None,
+            TransactionKind::PartialDispute { amount } => Some(amount),
+            kind => kind.amount(),
+        };
+        if amount.is_some_and(|amount| !amount.is_positive()) {
+            return Err("transaction amount has to be positive".into());
+        }
+        let mut transaction = Transaction::new(kind, client, tx);
+        transaction.source = self.source;
+        transaction.asset = self.asset;
+        transaction.currency = self.currency;
+        transaction.timestamp = self.timestamp;
+        Ok(transaction)
+    }
+}
+
+/// Money moves in or out with a deposit, withdrawal, fee, transfer or
+/// adjustment, and only those have an amount of their own. A partial dispute's
+/// amount is part of an earlier transaction, so it doesn't count.
+///
+/// ```
+/// use sailors_superstitions::{ClientId, Transaction, TransactionKind, TxId};
+///
+/// let mut txs = vec![
+///     Transaction::new(TransactionKind::Deposit { amount: 5 }, ClientId(1), TxId(1)),
+///     Transaction::new(TransactionKind::Withdrawal { amount: 2 }, ClientId(1), TxId(2)),
+///     Transaction::new(TransactionKind::Dispute { reason: None }, ClientId(1), TxId(1)),
+/// ];
+/// let moved: u32 = txs.iter().filter_map(|tx| tx.kind.amount()).sum();
+/// assert_eq!(moved, 7);
+/// assert_eq!(txs.iter().filter(|tx| tx.kind.is_monetary()).count(), 2);
+///
+/// // Amounts can be changed in place, e.g. to scale them
+/// for amount in txs.iter_mut().filter_map(|tx| tx.kind.amount_mut()) {
+///     *amount *= 10;
+/// }
+/// assert_eq!(txs[1].kind, TransactionKind::Withdrawal { amount: 20 });
+/// ```
+impl<T> TransactionKind<T> {
+    pub fn is_monetary(&self) -> bool {
+        self.amount().is_some()
+    }
+
+    pub fn amount(&self) -> Option<&T> {
+        match self {
+            TransactionKind::Deposit { amount }
+            | TransactionKind::Withdrawal { amount }
+            | TransactionKind::Fee { amount }
+            | TransactionKind::Transfer { amount, .. }
+            | TransactionKind::Adjustment { amount } => Some(amount),
+            TransactionKind::Dispute { .. }
+            | TransactionKind::PartialDispute { .. }
+            | TransactionKind::Resolve
+            | TransactionKind::Chargeback
+            | TransactionKind::Reversal => None,
+            #[cfg(test)]
+            TransactionKind::Unsupported => None,
+        }
+    }
+
+    pub fn amount_mut(&mut self) -> Option<&mut T> {
+        match self {
+            TransactionKind::Deposit { amount }
+            | TransactionKind::Withdrawal { amount }
+            | TransactionKind::Fee { amount }
+            | TransactionKind::Transfer { amount, .. }
+            | TransactionKind::Adjustment { amount } => Some(amount),
+            TransactionKind::Dispute { .. }
+            | TransactionKind::PartialDispute { .. }
+            | TransactionKind::Resolve
+            | TransactionKind::Chargeback
+            | TransactionKind::Reversal => None,
+            #[cfg(test)]
+            TransactionKind::Unsupported => None,
+        }
+    }
+}
+
+/// Formats a kind the way it reads in a log line, with the amount in
+/// parentheses when there is one.
+///
+/// ```
+/// use sailors_superstitions::{ClientId, DisputeReason, TransactionKind};
+///
+/// let deposit = TransactionKind::Deposit { amount: 1.5 };
+/// assert_eq!(deposit.to_string(), "deposit(1.5)");
+/// let withdrawal = TransactionKind::Withdrawal { amount: 2 };
+/// assert_eq!(withdrawal.to_string(), "withdrawal(2)");
+/// let fee = TransactionKind::Fee { amount: 0.25 };
+/// assert_eq!(fee.to_string(), "fee(0.25)");
+/// let transfer = TransactionKind::Transfer {
+///     to_client: ClientId(4),
+///     amount: 3,
+/// };
+/// assert_eq!(transfer.to_string(), "transfer(3 to 4)");
+/// let adjustment = TransactionKind::Adjustment { amount: -2 };
+/// assert_eq!(adjustment.to_string(), "adjustment(-2)");
+/// let dispute = TransactionKind::<u32>::Dispute { reason: None };
+/// assert_eq!(dispute.to_string(), "dispute");
+/// let reason = Some(DisputeReason::CounterfeitCard);
+/// let dispute = TransactionKind::<u32>::Dispute { reason };
+/// assert_eq!(dispute.to_string(), "dispute (counterfeit_card)");
+/// let partial = TransactionKind::PartialDispute { amount: 0.5 };
+/// assert_eq!(partial.to_string(), "dispute(0.5)");
+/// assert_eq!(TransactionKind::<u32>::Resolve.to_string(), "resolve");
+/// assert_eq!(TransactionKind::<u32>::Chargeback.to_string(), "chargeback");
+/// assert_eq!(TransactionKind::<u32>::Reversal.to_string(), "reversal");
+/// ```
+impl<T: Display> Display for TransactionKind<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            TransactionKind::Deposit { amount } => write!(f, "deposit({})", amount),
+            TransactionKind::Withdrawal { amount } => write!(f, "withdrawal({})", amount),
+            TransactionKind::Fee { amount } => write!(f, "fee({})", amount),
+            TransactionKind::Transfer { to_client, amount } => {
+                write!(f, "transfer({} to {})", amount, to_client)
+            }
+            TransactionKind::Adjustment { amount } => write!(f, "adjustment({})", amount),
+            TransactionKind::Dispute { reason: None } => f.write_str("dispute"),
+            TransactionKind::Dispute {
+                reason: Some(reason),
+            } => write!(f, "dispute ({})", reason),
+            TransactionKind::PartialDispute { amount } => write!(f, "dispute({})", amount),
+            TransactionKind::Resolve => f.write_str("resolve"),
+            TransactionKind::Chargeback => f.write_str("chargeback"),
+            TransactionKind::Reversal => f.write_str("reversal"),
+            #[cfg(test)]
+            TransactionKind::Unsupported => f.write_str("unsupported"),
+        }
+    }
+}
+
+/// ```
+/// use sailors_superstitions::TransactionStatus;
+///
+/// assert_eq!(TransactionStatus::Started.to_string(), "started");
+/// assert_eq!(TransactionStatus::Disputed.to_string(), "disputed");
+/// assert_eq!(TransactionStatus::Resolved.to_string(), "resolved");
+/// assert_eq!(TransactionStatus::Chargeback.to_string(), "chargeback");
+/// assert_eq!(TransactionStatus::Reversed.to_string(), "reversed");
+/// ```
+impl Display for TransactionStatus {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            TransactionStatus::Started => "started",
+            TransactionStatus::Disputed => "disputed",
+            TransactionStatus::Resolved => "resolved",
+            TransactionStatus::Chargeback => "chargeback",
+            TransactionStatus::Reversed => "reversed",
+        })
+    }
+}
+
+// A transaction is identified by who made it, its id, what it does, which
+// asset and currency it's in and when it happened. The status is deliberately
+// left out, along with the dispute details: they're mutable state that changes
+// as the transaction gets disputed, not part of what the transaction is, so a
+// stored transaction still equals the one that was originally submitted. The
+// source batch is left out too, as it only records where the transaction was
+// read from.
+impl<T: PartialEq> PartialEq for Transaction<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.client == other.client
+            && self.tx == other.tx
+            && self.kind == other.kind
+            && self.asset == other.asset
+            && self.currency == other.currency
+            && self.timestamp == other.timestamp
+    }
+}
+
+impl<T: Eq> Eq for Transaction<T> {}
+
+impl<T: Hash> Hash for Transaction<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.client.hash(state);
+        self.tx.hash(state);
+        self.kind.hash(state);
+        self.asset.hash(state);
+        self.currency.hash(state);
+        self.timestamp.hash(state);
+    }
+}
+
+// Parse Records
+
+// Every field goes through here, so whitespace around any of them is dropped
+// the same way whatever reader the record came from. That includes a stray `\r`
+// from CRLF line endings and a byte order mark left at the start of a file.
+fn field<'a>(value: &[&'a str], index: usize) -> Option<&'a str> {
+    value
+        .get(index)
+        .map(|field| field.trim_start_matches('\u{feff}').trim())
+}
+
+fn parse_value<T: FromStr>(
+    value: &[&str],
+    index: usize,
+    name: &str,
+) -> Result<T, Cow<'static, str>> {
+    parse_str(field(value, index), name)
+}
+
+// Parses a transaction kind from its type, whatever its casing, and the amount,
+// destination and dispute reason that go with it, so inputs other than CSV can
+// share this. Dispute, resolve, chargeback and reversal don't need an amount,
+// only a transfer needs a destination, and a dispute's reason is optional.
+// Fields are parsed as they are, so trim them first.
+pub fn parse_kind<T: FromStr + Default + PartialEq>(
+    kind_str: &str,
+    amount: Option<&str>,
+    to_client: Option<&str>,
+    reason: Option<&str>,
+) -> Result<TransactionKind<T>, Cow<'static, str>> {
+    // We ignore casing in case someone wrote "Deposit" instead of "deposit" and
+    // such. Sadly, we cannot use a match expression for this...
+    let kind = if kind_str.eq_ignore_ascii_case("deposit") {
+        TransactionKind::Deposit {
+            amount: parse_str::<T>(amount, "amount")?,
+        }
+    } else if kind_str.eq_ignore_ascii_case("withdrawal") {
+        TransactionKind::Withdrawal {
+            amount: parse_str::<T>(amount, "amount")?,
+        }
+    } else if kind_str.eq_ignore_ascii_case("fee") {
+        TransactionKind::Fee {
+            amount: parse_str::<T>(amount, "amount")?,
+        }
+    } else if kind_str.eq_ignore_ascii_case("transfer") {
+        TransactionKind::Transfer {
+            to_client: parse_str::<ClientId>(to_client, "to_client")?,
+            amount: parse_str::<T>(amount, "amount")?,
+        }
+    } else if kind_str.eq_ignore_ascii_case("adjustment") {
+        TransactionKind::Adjustment {
+            amount: parse_str::<T>(amount, "amount")?,
+        }
+    } else if kind_str.eq_ignore_ascii_case("dispute") {
+        // A dispute with an amount only disputes that much. Plenty of inputs
+        // fill the column with a zero anyway, which still disputes all of it.
+        let reason = reason
+            .filter(|reason| !reason.is_empty())
+            .map(|reason| parse_str::<DisputeReason>(Some(reason), "reason"))
+            .transpose()?;
+        match amount.filter(|amount| !amount.is_empty()) {
+            Some(_) => match parse_str::<T>(amount, "amount")? {
+                amount if amount == T::default() => TransactionKind::Dispute { reason },
+                amount => TransactionKind::PartialDispute { amount },
+            },
+            None => TransactionKind::Dispute { reason },
+        }
+    } else if kind_str.eq_ignore_ascii_case("resolve") {
+        TransactionKind::Resolve
+    } else if kind_str.eq_ignore_ascii_case("chargeback") {
+        TransactionKind::Chargeback
+    } else if kind_str.eq_ignore_ascii_case("reversal") {
+        TransactionKind::Reversal
+    } else {
+        return Err(format!(r#"found unknown transaction type "{}""#, kind_str).into());
+    };
+    Ok(kind)
+}
+
+fn parse_str<T: FromStr>(field: Option<&str>, name: &str) -> Result<T, Cow<'static, str>> {
+    field
+        .ok_or(format!("could not find {}", name))?
+        .parse::<T>()
+        .map_err(|_| format!("could not parse {}", name).into())
+}
+
+// Column Layout
+//
+// Which column holds which field of a record. The default is the usual
+// `type, client, tx, amount` order, followed by the optional asset column,
+// which transfers use for the destination client and disputes for their reason
+// instead, then the optional currency column, and then the optional timestamp
+// column.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ColumnLayout {
+    pub kind: usize,
+    pub client: usize,
+    pub tx: usize,
+    pub amount: usize,
+    pub asset: usize,
+    pub to_client: usize,
+    pub reason: usize,
+    pub currency: usize,
+    pub timestamp: usize,
+}
+
+impl Default for ColumnLayout {
+    fn default() -> Self {
+        Self {
+            kind: 0,
+            client: 1,
+            tx: 2,
+            amount: 3,
+            asset: 4,
+            to_client: 4,
+            reason: 4,
+            currency: 5,
+            timestamp: 6,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T: FromStr + Default + PartialEq> TryFrom<StringRecord> for Transaction<T> {
+    type Error = Cow<'static, str>;
+
+    fn try_from(value: StringRecord) -> Result<Self, Self::Error> {
+        Self::from_record(&value, &ColumnLayout::default())
+    }
+}
+
+// The same, for fields that didn't come from the `csv` crate, e.g.
+// `["deposit", "1", "2", "3.0"]`
+impl<T: FromStr + Default + PartialEq> TryFrom<&[&str]> for Transaction<T> {
+    type Error = Cow<'static, str>;
+
+    fn try_from(value: &[&str]) -> Result<Self, Self::Error> {
+        Self::from_fields(value, &ColumnLayout::default())
+    }
+}
+
+impl<T: FromStr + Default + PartialEq> Transaction<T> {
+    // Parses a record whose columns are laid out as in `layout`
+    #[cfg(feature = "std")]
+    pub fn from_record(
+        value: &StringRecord,
+        layout: &ColumnLayout,
+    ) -> Result<Self, Cow<'static, str>> {
+        let fields: Vec<&str> = value.iter().collect();
+        Self::from_fields(&fields, layout)
+    }
+
+    // Parses the fields of a record whose columns are laid out as in `layout`
+    pub fn from_fields(value: &[&str], layout: &ColumnLayout) -> Result<Self, Cow<'static, str>> {
+        // Get and parse the transaction kind
+        let kind_str = field(value, layout.kind).ok_or(r#"could not find "type""#)?;
+        let kind = parse_kind(
+            kind_str,
+            field(value, layout.amount),
+            field(value, layout.to_client),
+            field(value, layout.reason),
+        )?;
+        // Get and parse the client id
+        let client = parse_value::<ClientId>(value, layout.client, "client")?;
+        // Get and parse the transaction id
+        let tx = parse_value::<TxId>(value, layout.tx, "tx")?;
+        let mut transaction = Transaction::new(kind, client, tx);
+        // The asset column is optional, and inputs without it stick to the main
+        // balance. Transfers only have one if it's not where their destination is,
+        // and disputes if it's not where their reason is.
+        let shares_asset = match transaction.kind {
+            TransactionKind::Transfer { .. } => layout.asset == layout.to_client,
+            TransactionKind::Dispute { .. } => layout.asset == layout.reason,
+            _ => false,
+        };
+        if !shares_asset {
+            transaction.asset = field(value, layout.asset)
+                .filter(|asset| !asset.is_empty())
+                .map(str::to_string);
+        }
+        transaction.currency = field(value, layout.currency)
+            .filter(|currency| !currency.is_empty())
+            .map(|currency| parse_str::<Currency>(Some(currency), "currency"))
+            .transpose()?;
+        transaction.timestamp = field(value, layout.timestamp)
+            .filter(|timestamp| !timestamp.is_empty())
+            .map(|timestamp| parse_str::<u64>(Some(timestamp), "timestamp"))
+            .transpose()?;
+        Ok(transaction)
+    }
+}
+
+// The reverse of the above, using the same `type, client, tx, amount` column
+// order, plus the asset when there is one, or the destination client for a
+// transfer, then the currency, and then the timestamp. Transactions that
+// reference another one leave the amount empty. This can't fail, so the standard library's
+// blanket impl also gives us `TryFrom<&Transaction<T>>` with `Infallible` as
+// the error.
+#[cfg(feature = "std")]
+impl<T: Display> From<&Transaction<T>> for StringRecord {
+    fn from(value: &Transaction<T>) -> Self {
+        let mut fifth = value.asset.clone();
+        let (kind, amount) = match &value.kind {
+            TransactionKind::Deposit { amount } => ("deposit", amount.to_string()),
+            TransactionKind::Withdrawal { amount } => ("withdrawal", amount.to_string()),
+            TransactionKind::Fee { amount } => ("fee", amount.to_string()),
+            TransactionKind::Transfer { to_client, amount } => {
+                fifth = Some(to_client.to_string());
+                ("transfer", amount.to_string())
+            }
+            TransactionKind::Adjustment { amount } => ("adjustment", amount.to_string()),
+            TransactionKind::Dispute { reason } => {
+                fifth = reason.as_ref().map(ToString::to_string);
+                ("dispute", String::new())
+            }
+            TransactionKind::PartialDispute { amount } => ("dispute", amount.to_string()),
+            TransactionKind::Resolve => ("resolve", String::new()),
+            TransactionKind::Chargeback => ("chargeback", String::new()),
+            TransactionKind::Reversal => ("reversal", String::new()),
+            #[cfg(test)]
+            TransactionKind::Unsupported => ("unsupported", String::new()),
+        };
+        let mut record = StringRecord::from(vec![
+            kind.to_string(),
+            value.client.to_string(),
+            value.tx.to_string(),
+            amount,
+        ]);
+        // Each optional column needs the ones before it, even if they're empty
+        let currency = value.currency.as_ref().map(ToString::to_string);
+        let timestamp = value.timestamp.map(|timestamp| timestamp.to_string());
+        if fifth.is_some() || currency.is_some() || timestamp.is_some() {
+            record.push_field(fifth.as_deref().unwrap_or_default());
+        }
+        if currency.is_some() || timestamp.is_some() {
+            record.push_field(currency.as_deref().unwrap_or_default());
         }
+        if let Some(timestamp) = &timestamp {
+            record.push_field(timestamp);
+        }
+        record
     }
 }
 
 // Client
-#[derive(Debug)]
+//
+// Equal only if every field is, history and its stamps included. Reports that
+// only care about the balances go by `same_balances`.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Client<T> {
     pub available: T,
     pub held: T,
     pub locked: bool,
+    // Ids of the transactions that touched this client, in processing order. A
+    // dispute, resolve or chargeback shows up as the id it references.
+    pub history: Vec<TxId>,
+    // When each entry in `history` was recorded, counting the transactions the
+    // engine had applied by then, so two histories can be merged in the order
+    // things happened. Entries recorded outside an engine count as 0.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub history_seq: Vec<u64>,
+    // Balances in every other asset the client has moved, keyed by symbol
+    pub assets: Map<String, AssetBalance<T>>,
+    // What the client's money is in, set by the first transaction that moves
+    // any. Every one after it has to be in the same currency.
+    pub currency: Option<Currency>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AssetBalance<T> {
+    pub available: T,
+    pub held: T,
 }
 
 impl<T> Default for Client<T>
@@ -55,144 +999,7265 @@ where
             available: T::default(),
             held: T::default(),
             locked: false,
+            history: Vec::new(),
+            history_seq: Vec::new(),
+            assets: Map::new(),
+            currency: None,
         }
     }
 }
 
-// Transaction Handler
-pub fn handle<T>(
-    tx: &Transaction<T>,
-    client_store: &mut HashMap<u16, Client<T>>,
-    tx_store: &mut HashMap<u32, Transaction<T>>,
-) -> Result<(), Cow<'static, str>>
-where
-    T: Default + AddAssign + SubAssign + PartialOrd + Copy + std::fmt::Debug,
-{
-    // Get the client or create a new one if it doesn't exist
-    let client = client_store.entry(tx.client).or_default();
-    // If the client is locked, we can't really do anything with them
-    if client.locked {
-        return Err("client is locked".into());
+impl<T: PartialEq> Client<T> {
+    // Whether the two have the same main balances and lock, which is all the
+    // reports show. History is left out, since the same balances can be reached
+    // in different ways, and so are other assets and the currency.
+    pub fn same_balances(&self, other: &Self) -> bool {
+        self.available == other.available && self.held == other.held && self.locked == other.locked
     }
-    // Process the transaction
-    use TransactionKind::*;
-    match &tx.kind {
-        // We might not need to check anything when depositing money
-        Deposit { amount } => {
-            // Skip duplicate transactions
-            if tx_store.get(&tx.tx).is_some() {
-                return Err(format!("found duplicate transaction {}", tx.tx).into());
-            }
-            client.available += *amount;
-            tx_store.insert(tx.tx, *tx);
+}
+
+impl<T> Client<T> {
+    // A copy of the client without their history
+    fn without_history(&self) -> Self
+    where
+        T: Copy,
+    {
+        Self {
+            available: self.available,
+            held: self.held,
+            locked: self.locked,
+            history: Vec::new(),
+            history_seq: Vec::new(),
+            assets: self.assets.clone(),
+            currency: self.currency.clone(),
         }
-        // When withdrawing money, we need to make sure there's enough money to withdraw
-        Withdrawal { amount } => {
-            // Skip duplicate transactions
-            if tx_store.get(&tx.tx).is_some() {
-                return Err(format!("found duplicate transaction {}", tx.tx).into());
-            }
-            if &client.available < amount {
-                return Err("not enough funds to withdraw".into());
-            } else {
-                client.available -= *amount;
-            }
-            tx_store.insert(tx.tx, *tx);
+    }
+
+    // Each entry in the history along with when it was recorded
+    fn stamped_history(&self) -> impl Iterator<Item = (TxId, u64)> + '_ {
+        let seqs = self
+            .history_seq
+            .iter()
+            .copied()
+            .chain(core::iter::repeat(0));
+        self.history.iter().copied().zip(seqs)
+    }
+}
+
+impl<T: Default + Copy> Client<T> {
+    // Available funds in `asset`, or in the main balance for `None`
+    pub fn available_in(&self, asset: Option<&str>) -> T {
+        self.balance(asset).0
+    }
+
+    // The available and held funds in `asset`
+    fn balance(&self, asset: Option<&str>) -> (T, T) {
+        match asset {
+            None => (self.available, self.held),
+            Some(asset) => self
+                .assets
+                .get(asset)
+                .map_or_else(Default::default, |balance| {
+                    (balance.available, balance.held)
+                }),
         }
-        // All other cases reference a transaction, so we might reuse some code
-        _ => {
-            // First we try to find the transaction, and return an error if it doesn't exist
-            let ref_tx = tx_store.get(&tx.tx).ok_or(format!(
-                r#"could not find referenced transaction "{}""#,
-                tx.tx
-            ))?;
-            // I don't think a client should be able to deal with other clients'
-            // transactions
-            if tx.client != ref_tx.client {
-                return Err("transactions are not from the same client".into());
-            }
-            // Deal with a dispute
-            if matches!(tx.kind, Dispute) {
-                // I don't think we should allow a transaction to be disputed twice
-                if matches!(ref_tx.status, TransactionStatus::Disputed) {
-                    return Err(format!(r#"transaction "{}" already in dispute"#, tx.tx).into());
-                }
-                // Likewise, we should not be able to re-dispute a transaction that has been
-                // resolved
-                if matches!(ref_tx.status, TransactionStatus::Resolved)
-                    || matches!(ref_tx.status, TransactionStatus::Chargeback)
-                {
-                    return Err(format!(r#"transaction "{}" already resolved"#, tx.tx).into());
-                }
-                // Also, a dispute needs to specify a transaction with an amount
-                match ref_tx.kind {
-                    Deposit { amount } | Withdrawal { amount } => {
-                        // Update transaction status and client information
-                        tx_store
-                            .entry(tx.tx)
-                            .and_modify(|t| t.status = TransactionStatus::Disputed);
-                        // XXX: Can a client's available amount go under 0?
-                        client.available -= amount;
-                        client.held += amount;
-                    }
-                    _ => {
-                        return Err(
-                            format!(r#"transaction "{}" does not have an amount"#, tx.tx).into(),
-                        );
-                    }
-                }
-            // Deal with a resolve
-            } else if matches!(tx.kind, Resolve) {
-                // We can only resolve a transaction in dispute
-                if !matches!(ref_tx.status, TransactionStatus::Disputed) {
-                    return Err(format!(r#"transaction "{}" is not in dispute"#, tx.tx).into());
-                }
-                // Also, a resolve needs to specify a transaction with an amount
-                match ref_tx.kind {
-                    Deposit { amount } | Withdrawal { amount } => {
-                        // Update transaction status and client information
-                        tx_store
-                            .entry(tx.tx)
-                            .and_modify(|t| t.status = TransactionStatus::Resolved);
-                        // XXX: Can held go under 0?
-                        client.available += amount;
-                        client.held -= amount;
-                    }
-                    _ => {
-                        return Err(
-                            format!(r#"transaction "{}" does not have an amount"#, tx.tx).into(),
-                        );
-                    }
-                }
-            } else {
-                // We can only resolve a transaction in dispute or resolved
-                if !matches!(ref_tx.status, TransactionStatus::Disputed)
-                    && !matches!(ref_tx.status, TransactionStatus::Resolved)
-                {
-                    return Err(
-                        format!(r#"transaction "{}" is not in dispute/resolved"#, tx.tx).into(),
-                    );
-                }
-                // Also, a chargeback needs to specify a transaction with an amount
-                match ref_tx.kind {
-                    Deposit { amount } | Withdrawal { amount } => {
-                        // Update transaction status and client information
-                        tx_store
-                            .entry(tx.tx)
-                            .and_modify(|t| t.status = TransactionStatus::Chargeback);
-                        // XXX: Can held go under 0?
-                        client.held -= amount;
-                        client.locked = true;
-                    }
-                    _ => {
-                        return Err(
-                            format!(r#"transaction "{}" does not have an amount"#, tx.tx).into(),
-                        );
-                    }
-                }
+    }
+
+    // The available and held funds a transaction in `asset` moves
+    fn balance_mut(&mut self, asset: Option<&str>) -> (&mut T, &mut T) {
+        match asset {
+            None => (&mut self.available, &mut self.held),
+            Some(asset) => {
+                let balance = self.assets.entry(asset.to_string()).or_default();
+                (&mut balance.available, &mut balance.held)
             }
         }
     }
-    // After all is said and done, we can add this transaction to the record
-    Ok(())
+}
+
+impl<T: Copy + core::ops::Add<Output = T>> Client<T> {
+    // Everything the client has in their main balance, whether they can use it
+    // or not
+    pub fn total(&self) -> T {
+        self.available + self.held
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked
+    }
+}
+
+impl<T: PartialOrd + Default> Client<T> {
+    // A client is solvent as long as they're not locked and haven't somehow
+    // ended up with negative available funds
+    pub fn is_solvent(&self) -> bool {
+        self.available >= T::default() && !self.locked
+    }
+}
+
+impl<T: CheckedAdd + Copy> Client<T> {
+    // The same as `total`, or `None` if it doesn't fit in a `T`
+    pub fn checked_total(&self) -> Option<T> {
+        self.available.checked_add(&self.held)
+    }
+}
+
+// Client Snapshot
+//
+// `Client<T>` deliberately doesn't know its own id, so this is the shape we
+// actually emit, whatever the output format is.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ClientSnapshot<T> {
+    pub client_id: ClientId,
+    pub available: T,
+    pub held: T,
+    pub total: T,
+    pub locked: bool,
+}
+
+impl<T: CheckedAdd + Copy> ClientSnapshot<T> {
+    // Fails if the total doesn't fit in a `T`
+    pub fn from_client(id: ClientId, c: &Client<T>) -> Result<Self, EngineError> {
+        Ok(Self {
+            client_id: id,
+            available: c.available,
+            held: c.held,
+            total: c
+                .checked_total()
+                .ok_or(EngineError::Overflow { client: id })?,
+            locked: c.locked,
+        })
+    }
+}
+
+// The columns of a CSV report, which `ClientSnapshot::report_row` fills in
+pub const REPORT_HEADER: [&str; 5] = ["client", "available", "held", "total", "locked"];
+
+impl<T: Display> ClientSnapshot<T> {
+    // This client's row in a CSV report, under `REPORT_HEADER`
+    pub fn report_row(&self) -> [String; 5] {
+        [
+            self.client_id.to_string(),
+            self.available.to_string(),
+            self.held.to_string(),
+            self.total.to_string(),
+            self.locked.to_string(),
+        ]
+    }
+}
+
+/// Formats a snapshot as a row of the `client,available,held,total,locked`
+/// output.
+///
+/// ```
+/// use sailors_superstitions::{ClientId, ClientSnapshot};
+///
+/// let snapshot = ClientSnapshot {
+///     client_id: ClientId(1),
+///     available: 1.5,
+///     held: 0.5,
+///     total: 2.0,
+///     locked: false,
+/// };
+/// assert_eq!(snapshot.to_string(), "1,1.5,0.5,2,false");
+/// ```
+impl<T: Display> Display for ClientSnapshot<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{},{},{},{},{}",
+            self.client_id, self.available, self.held, self.total, self.locked
+        )
+    }
+}
+
+// Statement Entry
+//
+// One line of `Engine::statement`. `delta` is how much the client's available
+// funds moved, so a dispute shows up as a debit and its resolution as a credit,
+// and `running_balance` is the available amount right after it. `status` is the
+// current status of the transaction the entry refers to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StatementEntry<T> {
+    pub tx_id: TxId,
+    pub kind: TransactionKind<T>,
+    pub status: TransactionStatus,
+    pub delta: T,
+    pub running_balance: T,
+}
+
+// Reconciliation Report
+//
+// What `Engine::reconcile` found. Mismatched clients come with this engine's
+// version first and the other one's second.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReconciliationReport<T> {
+    pub matching_clients: Vec<ClientId>,
+    pub mismatched_clients: Vec<(ClientId, Client<T>, Client<T>)>,
+    pub only_in_self: Vec<ClientId>,
+    pub only_in_other: Vec<ClientId>,
+}
+
+impl<T> ReconciliationReport<T> {
+    // Whether both engines have the same clients, all with the same balances
+    pub fn is_consistent(&self) -> bool {
+        self.mismatched_clients.is_empty()
+            && self.only_in_self.is_empty()
+            && self.only_in_other.is_empty()
+    }
+}
+
+// Dispute Summary
+//
+// What `Engine::dispute_summary` adds up across every client. Only disputed
+// transactions that are still stored count.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DisputeSummary<T> {
+    // How much is held for transactions that are in dispute right now
+    pub held: T,
+    // How much was ever charged back
+    pub charged_back: T,
+    // How many transactions were ever put in dispute, settled or not
+    pub disputes: usize,
+    pub chargebacks: usize,
+}
+
+impl<T: Copy> DisputeSummary<T> {
+    // What chargebacks have cost so far
+    pub fn chargeback_exposure(&self) -> T {
+        self.charged_back
+    }
+}
+
+// e.g. "held 1.5, charged back 3, disputes 2, chargebacks 1"
+impl<T: Display> Display for DisputeSummary<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "held {}, charged back {}, disputes {}, chargebacks {}",
+            self.held, self.charged_back, self.disputes, self.chargebacks
+        )
+    }
+}
+
+// Client Snapshots
+//
+// A snapshot of every client, in id order.
+pub fn client_snapshots<T: CheckedAdd + Copy>(
+    client_store: &BTreeMap<ClientId, Client<T>>,
+) -> Result<Vec<ClientSnapshot<T>>, EngineError> {
+    client_store
+        .iter()
+        .map(|(id, client)| ClientSnapshot::from_client(*id, client))
+        .collect()
+}
+
+// Aggregate
+//
+// Sums every client's balances into a single system-wide `Client`, which is
+// locked if any client is, or `None` if any of the sums would overflow. The
+// total has no history of its own.
+pub fn aggregate<T>(client_store: &BTreeMap<ClientId, Client<T>>) -> Option<Client<T>>
+where
+    T: Default + CheckedAdd + Copy,
+{
+    let mut total = Client::<T>::default();
+    for client in client_store.values() {
+        total.available = total.available.checked_add(&client.available)?;
+        total.held = total.held.checked_add(&client.held)?;
+        total.locked |= client.locked;
+        for (asset, balance) in &client.assets {
+            let sum = total.assets.entry(asset.clone()).or_default();
+            sum.available = sum.available.checked_add(&balance.available)?;
+            sum.held = sum.held.checked_add(&balance.held)?;
+        }
+    }
+    Some(total)
+}
+
+// Held Sources
+//
+// Lists every transaction of `client` that is currently in dispute, along with
+// the amount it contributes to the client's held balance, in tx id order.
+pub fn held_sources<T: Copy>(
+    client: ClientId,
+    tx_store: &Map<TxId, Transaction<T>>,
+) -> Vec<(TxId, T)> {
+    let mut sources: Vec<(TxId, T)> = tx_store
+        .values()
+        .filter(|tx| tx.client == client && matches!(tx.status, TransactionStatus::Disputed))
+        .filter_map(|tx| match tx.kind {
+            TransactionKind::Deposit { amount } | TransactionKind::Withdrawal { amount } => {
+                Some((tx.tx, tx.disputed.unwrap_or(amount)))
+            }
+            _ => None,
+        })
+        .collect();
+    sources.sort_unstable_by_key(|(id, _)| *id);
+    sources
+}
+
+// Errors
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EngineError {
+    ClientLocked(ClientId),
+    DuplicateTransaction(TxId),
+    InsufficientFunds(ClientId),
+    NonPositiveAmount(TxId, String),
+    AmountExceedsLimit(TxId, String),
+    TransactionNotFound(TxId),
+    ClientMismatch(TxId),
+    AlreadyDisputed(TxId),
+    AlreadyResolved(TxId),
+    AlreadyChargedBack(TxId),
+    AlreadyReversed(TxId),
+    NotDisputed(TxId, TransactionStatus),
+    NoAmount(TxId),
+    NotDisputable(TxId),
+    DisputeExceedsAmount(TxId),
+    DuplicateTransactionId {
+        tx: TxId,
+        existing_client: ClientId,
+        new_client: ClientId,
+    },
+    Overflow {
+        client: ClientId,
+    },
+    ClientNotFound(ClientId),
+    SelfTransfer(TxId),
+    RateLimitExceeded(ClientId),
+    CurrencyMismatch {
+        expected: Currency,
+        found: Currency,
+    },
+    DisputeWindowExpired(TxId),
+    UnsupportedKind(TxId),
+    // A thread panicked while it had a `SharedEngine` locked, so the engine may
+    // be partway through a change
+    Poisoned,
+    // `process_async`'s blocking task was cancelled, because the runtime shut
+    // down, before it got to the transaction
+    Cancelled,
+}
+
+impl Display for EngineError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        use EngineError::*;
+        match self {
+            ClientLocked(_) => write!(f, "client is locked"),
+            DuplicateTransaction(tx) => write!(f, "found duplicate transaction {}", tx),
+            InsufficientFunds(_) => write!(f, "not enough funds to withdraw"),
+            NonPositiveAmount(tx, amount) => {
+                write!(
+                    f,
+                    r#"transaction "{}" has non-positive amount {}"#,
+                    tx, amount
+                )
+            }
+            AmountExceedsLimit(tx, amount) => {
+                write!(
+                    f,
+                    r#"transaction "{}" amount {} exceeds the limit"#,
+                    tx, amount
+                )
+            }
+            TransactionNotFound(tx) => {
+                write!(f, r#"could not find referenced transaction "{}""#, tx)
+            }
+            ClientMismatch(_) => write!(f, "transactions are not from the same client"),
+            AlreadyDisputed(tx) => write!(f, r#"transaction "{}" already in dispute"#, tx),
+            AlreadyResolved(tx) => write!(f, r#"transaction "{}" already resolved"#, tx),
+            AlreadyChargedBack(tx) => write!(f, r#"transaction "{}" already charged back"#, tx),
+            AlreadyReversed(tx) => write!(f, r#"transaction "{}" already reversed"#, tx),
+            NotDisputed(tx, status) => {
+                write!(f, r#"transaction "{}" is {}, not in dispute"#, tx, status)
+            }
+            NoAmount(tx) => write!(f, r#"transaction "{}" does not have an amount"#, tx),
+            NotDisputable(tx) => write!(f, r#"transaction "{}" can't be disputed"#, tx),
+            DisputeExceedsAmount(tx) => {
+                write!(f, r#"dispute is for more than transaction "{}" moved"#, tx)
+            }
+            DuplicateTransactionId {
+                tx,
+                existing_client,
+                new_client,
+            } => write!(
+                f,
+                "client {} reused transaction {} of client {}",
+                new_client, tx, existing_client
+            ),
+            Overflow { client } => write!(f, "balance of client {} would overflow", client),
+            ClientNotFound(client) => write!(f, "could not find client {}", client),
+            SelfTransfer(tx) => write!(f, r#"transaction "{}" transfers to its own client"#, tx),
+            RateLimitExceeded(client) => {
+                write!(f, "client {} has made too many transactions", client)
+            }
+            CurrencyMismatch { expected, found } => {
+                write!(f, "expected an amount in {}, got {}", expected, found)
+            }
+            DisputeWindowExpired(tx) => {
+                write!(f, r#"transaction "{}" is too old to dispute"#, tx)
+            }
+            UnsupportedKind(tx) => {
+                write!(
+                    f,
+                    r#"transaction "{}" is of a kind that isn't supported"#,
+                    tx
+                )
+            }
+            Poisoned => write!(f, "engine was poisoned by a thread that panicked"),
+            Cancelled => write!(f, "processing was cancelled before it started"),
+        }
+    }
+}
+
+impl EngineError {
+    // A short, stable name for the kind of error, for counting them
+    pub fn category(&self) -> &'static str {
+        use EngineError::*;
+        match self {
+            ClientLocked(_) => "client_locked",
+            DuplicateTransaction(_) => "duplicate_transaction",
+            InsufficientFunds(_) => "insufficient_funds",
+            NonPositiveAmount(..) => "non_positive_amount",
+            AmountExceedsLimit(..) => "amount_exceeds_limit",
+            TransactionNotFound(_) => "transaction_not_found",
+            ClientMismatch(_) => "client_mismatch",
+            AlreadyDisputed(_) => "already_disputed",
+            AlreadyResolved(_) => "already_resolved",
+            AlreadyChargedBack(_) => "already_charged_back",
+            AlreadyReversed(_) => "already_reversed",
+            NotDisputed(..) => "not_disputed",
+            NoAmount(_) => "no_amount",
+            NotDisputable(_) => "not_disputable",
+            DisputeExceedsAmount(_) => "dispute_exceeds_amount",
+            DuplicateTransactionId { .. } => "duplicate_transaction_id",
+            Overflow { .. } => "overflow",
+            ClientNotFound(_) => "client_not_found",
+            SelfTransfer(_) => "self_transfer",
+            RateLimitExceeded(_) => "rate_limit_exceeded",
+            CurrencyMismatch { .. } => "currency_mismatch",
+            DisputeWindowExpired(_) => "dispute_window_expired",
+            UnsupportedKind(_) => "unsupported_kind",
+            Poisoned => "poisoned",
+            Cancelled => "cancelled",
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for EngineError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        None
+    }
+}
+
+// Engine Config
+//
+// Every behavioral knob lives here, so new ones can be added without breaking
+// anyone who just uses `Engine::new()`.
+#[derive(Debug, Clone)]
+pub struct EngineConfig<T> {
+    // Only a hint: clients are kept in a `BTreeMap`, which doesn't preallocate
+    pub initial_client_capacity: usize,
+    pub initial_tx_capacity: usize,
+    // Let withdrawals take a client's available funds below zero
+    pub allow_negative_available: bool,
+    // Stop processing a batch at the first error instead of skipping the record
+    pub strict: bool,
+    // Reject any deposit or withdrawal above this amount
+    pub max_amount: Option<T>,
+    // Keep at most this many audit entries, and as many merges and unlocks,
+    // dropping the oldest ones first. Zero turns all three logs off, and `None`,
+    // the default, keeps every entry.
+    pub max_audit_entries: Option<usize>,
+    // Forget a settled (resolved or charged back) transaction once more than
+    // this many others have gone through since it was last touched. Disputes of
+    // a forgotten transaction fail as if it never existed, but its id still
+    // can't be used again.
+    pub retention_window: Option<usize>,
+    // Reject a client's transactions once this many have gone through, until
+    // `Engine::reset_rate_limit_counters` starts a new window
+    pub max_transactions_per_client: Option<usize>,
+    // Only reject an id as a duplicate if one of the last this many stored
+    // transactions had it. Older ones are forgotten as they fall out of the
+    // window, so disputes refer to the new one from then on, but one that's been
+    // disputed is kept, and its id stays taken.
+    pub dedup_window: Option<usize>,
+    // Keep only this many of the most recently stored transactions, evicting the
+    // oldest first. Disputed ones are never evicted, so until they're settled
+    // there can be more. Like with the retention window, an evicted transaction
+    // can't be disputed any more, but its id stays taken.
+    pub tx_history_limit: Option<usize>,
+    // Lock a client whose transaction is charged back. Without it, the funds
+    // still go, but the client can carry on.
+    pub lock_on_chargeback: bool,
+    // What a transaction's amount is in when it doesn't say
+    pub default_currency: Currency,
+    // Reject a dispute timestamped more than this many seconds after the
+    // transaction it disputes. Disputes go through if either one has no
+    // timestamp.
+    pub dispute_window_secs: Option<u64>,
+}
+
+impl<T> Default for EngineConfig<T> {
+    fn default() -> Self {
+        Self {
+            initial_client_capacity: 0,
+            initial_tx_capacity: 0,
+            allow_negative_available: false,
+            strict: false,
+            max_amount: None,
+            max_audit_entries: None,
+            retention_window: None,
+            max_transactions_per_client: None,
+            dedup_window: None,
+            tx_history_limit: None,
+            lock_on_chargeback: true,
+            default_currency: Currency::Usd,
+            dispute_window_secs: None,
+        }
+    }
+}
+
+impl<T> EngineConfig<T> {
+    pub fn initial_client_capacity(mut self, capacity: usize) -> Self {
+        self.initial_client_capacity = capacity;
+        self
+    }
+
+    pub fn initial_tx_capacity(mut self, capacity: usize) -> Self {
+        self.initial_tx_capacity = capacity;
+        self
+    }
+
+    pub fn allow_negative_available(mut self, allow: bool) -> Self {
+        self.allow_negative_available = allow;
+        self
+    }
+
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    pub fn max_amount(mut self, amount: T) -> Self {
+        self.max_amount = Some(amount);
+        self
+    }
+
+    pub fn max_audit_entries(mut self, entries: usize) -> Self {
+        self.max_audit_entries = Some(entries);
+        self
+    }
+
+    pub fn retention_window(mut self, window: usize) -> Self {
+        self.retention_window = Some(window);
+        self
+    }
+
+    pub fn max_transactions_per_client(mut self, max: usize) -> Self {
+        self.max_transactions_per_client = Some(max);
+        self
+    }
+
+    pub fn dedup_window(mut self, window: usize) -> Self {
+        self.dedup_window = Some(window);
+        self
+    }
+
+    pub fn tx_history_limit(mut self, limit: usize) -> Self {
+        self.tx_history_limit = Some(limit);
+        self
+    }
+
+    pub fn lock_on_chargeback(mut self, lock: bool) -> Self {
+        self.lock_on_chargeback = lock;
+        self
+    }
+
+    pub fn default_currency(mut self, currency: Currency) -> Self {
+        self.default_currency = currency;
+        self
+    }
+
+    pub fn dispute_window_secs(mut self, secs: u64) -> Self {
+        self.dispute_window_secs = Some(secs);
+        self
+    }
+}
+
+// Metrics
+//
+// How many transactions of each kind an engine has applied and rejected, and
+// what it rejected them for.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Counter {
+    pub applied: u64,
+    pub rejected: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Metrics {
+    pub deposits: Counter,
+    pub withdrawals: Counter,
+    pub fees: Counter,
+    pub transfers: Counter,
+    pub adjustments: Counter,
+    pub disputes: Counter,
+    pub resolves: Counter,
+    pub chargebacks: Counter,
+    pub reversals: Counter,
+    // Rejections keyed by `EngineError::category`
+    pub errors: Map<&'static str, u64>,
+}
+
+impl Metrics {
+    fn record<T>(&mut self, kind: &TransactionKind<T>, result: &Result<Outcome, EngineError>) {
+        let counter = match kind {
+            TransactionKind::Deposit { .. } => &mut self.deposits,
+            TransactionKind::Withdrawal { .. } => &mut self.withdrawals,
+            TransactionKind::Fee { .. } => &mut self.fees,
+            TransactionKind::Transfer { .. } => &mut self.transfers,
+            TransactionKind::Adjustment { .. } => &mut self.adjustments,
+            TransactionKind::Dispute { .. } | TransactionKind::PartialDispute { .. } => {
+                &mut self.disputes
+            }
+            TransactionKind::Resolve => &mut self.resolves,
+            TransactionKind::Chargeback => &mut self.chargebacks,
+            TransactionKind::Reversal => &mut self.reversals,
+            // Only the error counts, with no kind to count it under
+            #[cfg(test)]
+            TransactionKind::Unsupported => {
+                if let Err(e) = result {
+                    *self.errors.entry(e.category()).or_default() += 1;
+                }
+                return;
+            }
+        };
+        match result {
+            Ok(_) => counter.applied += 1,
+            Err(e) => {
+                counter.rejected += 1;
+                *self.errors.entry(e.category()).or_default() += 1;
+            }
+        }
+    }
+}
+
+// Prints everything on one line, e.g. "deposits 2/1, withdrawals 0/0, ..." with
+// applied/rejected counts, followed by the errors in alphabetical order
+impl Display for Metrics {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let counters = [
+            ("deposits", self.deposits),
+            ("withdrawals", self.withdrawals),
+            ("fees", self.fees),
+            ("transfers", self.transfers),
+            ("adjustments", self.adjustments),
+            ("disputes", self.disputes),
+            ("resolves", self.resolves),
+            ("chargebacks", self.chargebacks),
+            ("reversals", self.reversals),
+        ];
+        for (i, (name, counter)) in counters.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{} {}/{}", name, counter.applied, counter.rejected)?;
+        }
+        let mut errors: Vec<_> = self.errors.iter().collect();
+        errors.sort_unstable();
+        for (category, count) in errors {
+            write!(f, ", {} {}", category, count)?;
+        }
+        Ok(())
+    }
+}
+
+// Audit Log
+//
+// What a single transaction did to a single client. A transfer touches two
+// clients, so it leaves an entry for each. `timestamp_seq` only ever goes up,
+// even across `Engine::replay`, so entries can be ordered without a clock.
+// `kind` is what was processed, so a dispute gets its own entry apart from the
+// deposit it refers to, and `status` is the status it left that transaction in.
+// The deltas are how much the main balances moved, and `before` and `after` are
+// the client on either side of it. Those leave out the history, which would
+// otherwise make every entry bigger than the last.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AuditEntry<T> {
+    pub tx_id: TxId,
+    pub client_id: ClientId,
+    pub kind: TransactionKind<T>,
+    pub status: TransactionStatus,
+    pub available_delta: Delta<T>,
+    pub held_delta: Delta<T>,
+    pub before: Client<T>,
+    pub after: Client<T>,
+    pub timestamp_seq: u64,
+}
+
+// Merge Entry
+//
+// What `Engine::merge_clients` did, which isn't down to any one transaction.
+// `moved` is the client that was merged away, as they were, and `before` and
+// `after` are the client they were merged into. `timestamp_seq` counts along
+// with the audit entries, so the two can be put back in order.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MergeEntry<T> {
+    pub from: ClientId,
+    pub into: ClientId,
+    pub moved: Client<T>,
+    pub before: Client<T>,
+    pub after: Client<T>,
+    pub timestamp_seq: u64,
+}
+
+// Unlock Entry
+//
+// A client unlocked by `Engine::unlock_client`, and what they looked like just
+// before. Counted along with the audit entries, like merges.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct UnlockEntry<T> {
+    pub client: ClientId,
+    pub before: Client<T>,
+    pub timestamp_seq: u64,
+}
+
+// Ledger
+//
+// The audit log itself. Entries are only ever appended, except that a bounded
+// ledger drops its oldest ones to make room. They're dropped in batches, once
+// there are twice as many as are kept, so a full ledger doesn't shift every
+// entry along on each append.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Ledger<T> {
+    entries: Vec<AuditEntry<T>>,
+    merges: Vec<MergeEntry<T>>,
+    unlocks: Vec<UnlockEntry<T>>,
+    seq: u64,
+    max_entries: Option<usize>,
+}
+
+impl<T> Ledger<T> {
+    pub fn new(max_entries: Option<usize>) -> Self {
+        Self {
+            entries: Vec::new(),
+            merges: Vec::new(),
+            unlocks: Vec::new(),
+            seq: 0,
+            max_entries,
+        }
+    }
+
+    pub fn entries(&self) -> &[AuditEntry<T>] {
+        self.newest(&self.entries)
+    }
+
+    pub fn merges(&self) -> &[MergeEntry<T>] {
+        self.newest(&self.merges)
+    }
+
+    pub fn unlocks(&self) -> &[UnlockEntry<T>] {
+        self.newest(&self.unlocks)
+    }
+
+    // The entries of `log` a bounded ledger still keeps
+    fn newest<'a, E>(&self, log: &'a [E]) -> &'a [E] {
+        let max = self.max_entries.unwrap_or(usize::MAX);
+        &log[log.len().saturating_sub(max)..]
+    }
+
+    // Appends to one of the logs, dropping a batch of its oldest entries once
+    // it's full
+    fn push<E>(log: &mut Vec<E>, entry: E, max_entries: Option<usize>) {
+        if max_entries == Some(0) {
+            return;
+        }
+        log.push(entry);
+        if let Some(max) = max_entries {
+            if log.len() >= max.saturating_mul(2) {
+                log.drain(..log.len() - max);
+            }
+        }
+    }
+
+    fn record(
+        &mut self,
+        tx: &Transaction<T>,
+        client_id: ClientId,
+        status: TransactionStatus,
+        (available_delta, held_delta): (Delta<T>, Delta<T>),
+        before: Client<T>,
+        after: Client<T>,
+    ) where
+        T: Amount,
+    {
+        self.seq += 1;
+        if self.max_entries == Some(0) {
+            return;
+        }
+        let entry = AuditEntry {
+            tx_id: tx.tx,
+            client_id,
+            kind: tx.kind.clone(),
+            status,
+            available_delta,
+            held_delta,
+            before,
+            after,
+            timestamp_seq: self.seq,
+        };
+        Self::push(&mut self.entries, entry, self.max_entries);
+    }
+
+    fn record_merge(
+        &mut self,
+        from: ClientId,
+        into: ClientId,
+        moved: Client<T>,
+        before: Client<T>,
+        after: Client<T>,
+    ) {
+        self.seq += 1;
+        let entry = MergeEntry {
+            from,
+            into,
+            moved,
+            before,
+            after,
+            timestamp_seq: self.seq,
+        };
+        Self::push(&mut self.merges, entry, self.max_entries);
+    }
+
+    fn record_unlock(&mut self, client: ClientId, before: Client<T>) {
+        self.seq += 1;
+        let entry = UnlockEntry {
+            client,
+            before,
+            timestamp_seq: self.seq,
+        };
+        Self::push(&mut self.unlocks, entry, self.max_entries);
+    }
+
+    // Drops every entry, but keeps counting from where it was
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.merges.clear();
+        self.unlocks.clear();
+    }
+}
+
+// Retention
+//
+// When each stored transaction was last touched, counting in transactions
+// processed, so the settled ones that have fallen out of the retention window
+// can be evicted oldest first. A transaction touched again just gets a newer entry;
+// the stale one is skipped when it comes up.
+#[derive(Debug, Clone, Default)]
+struct Retention {
+    seq: u64,
+    queue: VecDeque<(u64, TxId)>,
+    last_touched: Map<TxId, u64>,
+}
+
+impl Retention {
+    fn touch<T>(
+        &mut self,
+        id: TxId,
+        window: usize,
+        evicted: &mut Evicted,
+        client_store: &mut impl ClientStore<T>,
+        tx_store: &mut impl TxStore<T>,
+    ) {
+        self.seq += 1;
+        self.queue.push_back((self.seq, id));
+        self.last_touched.insert(id, self.seq);
+        while let Some(&(touched, id)) = self.queue.front() {
+            if touched + window as u64 >= self.seq {
+                break;
+            }
+            self.queue.pop_front();
+            if self.last_touched.get(&id) != Some(&touched) {
+                continue;
+            }
+            // Only a settled transaction is done with. One that's under dispute
+            // still has funds held against it, and one that hasn't been disputed
+            // yet still could be, so they go round again.
+            match tx_store.get(id).map(|tx| tx.status) {
+                Some(
+                    TransactionStatus::Resolved
+                    | TransactionStatus::Chargeback
+                    | TransactionStatus::Reversed,
+                ) => {
+                    evicted.evict(id, client_store, tx_store);
+                    self.last_touched.remove(&id);
+                }
+                Some(_) => {
+                    self.queue.push_back((self.seq, id));
+                    self.last_touched.insert(id, self.seq);
+                }
+                None => {
+                    self.last_touched.remove(&id);
+                }
+            }
+        }
+    }
+}
+
+// Tx History
+//
+// Every stored transaction in the order it was stored, so the oldest can be
+// evicted once there are too many. Like with retention, a transaction stored
+// again under the same id gets a newer entry, and the stale one is skipped.
+#[derive(Debug, Clone, Default)]
+struct TxHistory {
+    seq: u64,
+    order: VecDeque<(u64, TxId)>,
+    latest: Map<TxId, u64>,
+}
+
+impl TxHistory {
+    fn push<T>(
+        &mut self,
+        id: TxId,
+        limit: usize,
+        evicted: &mut Evicted,
+        client_store: &mut impl ClientStore<T>,
+        tx_store: &mut impl TxStore<T>,
+    ) {
+        self.seq += 1;
+        self.order.push_back((self.seq, id));
+        self.latest.insert(id, self.seq);
+        let mut i = 0;
+        while self.latest.len() > limit && i < self.order.len() {
+            let (stored, id) = self.order[i];
+            if self.latest.get(&id) != Some(&stored) {
+                self.order.remove(i);
+                continue;
+            }
+            // Held funds still depend on a disputed transaction, so it stays where
+            // it is and the next oldest goes instead
+            match tx_store.get(id) {
+                Some(tx) if tx.status == TransactionStatus::Disputed => i += 1,
+                _ => {
+                    self.order.remove(i);
+                    self.latest.remove(&id);
+                    evicted.evict(id, client_store, tx_store);
+                }
+            }
+        }
+    }
+}
+
+// Evicted
+//
+// The ids of every transaction retention or the tx history limit has evicted.
+// Only the id is kept, so a replayed deposit is still turned away as a
+// duplicate rather than credited again. Audit entries of an evicted
+// transaction stay, since they don't need it to make sense.
+#[derive(Debug, Clone, Default)]
+struct Evicted {
+    ids: Set<TxId>,
+}
+
+impl Evicted {
+    fn contains(&self, id: TxId) -> bool {
+        self.ids.contains(&id)
+    }
+
+    fn evict<T>(
+        &mut self,
+        id: TxId,
+        client_store: &mut impl ClientStore<T>,
+        tx_store: &mut impl TxStore<T>,
+    ) {
+        if forget(id, client_store, tx_store) {
+            self.ids.insert(id);
+        }
+    }
+}
+
+// Drops the stored transaction `id`, and every mention of it in the history of
+// the clients it moved funds for, so nothing is left pointing at it. Returns
+// whether there was anything to drop.
+fn forget<T>(
+    id: TxId,
+    client_store: &mut impl ClientStore<T>,
+    tx_store: &mut impl TxStore<T>,
+) -> bool {
+    let Some(tx) = tx_store.remove(id) else {
+        return false;
+    };
+    let mut parties = vec![tx.client];
+    if let TransactionKind::Transfer { to_client, .. } = tx.kind {
+        parties.push(to_client);
+    }
+    for party in parties {
+        if let Some(client) = client_store.get_mut(party) {
+            let (history, seqs) = client
+                .stamped_history()
+                .filter(|(tx_id, _)| *tx_id != id)
+                .unzip();
+            (client.history, client.history_seq) = (history, seqs);
+        }
+    }
+    true
+}
+
+// Dedup Window
+//
+// The ids of the last few transactions that were stored, oldest first, with a
+// set alongside for quick lookups. Ids in here are unique, since a duplicate of
+// one of them is rejected before it gets stored. A transaction is forgotten as
+// its id falls out, unless it's been disputed, in which case it stays stored
+// and keeps its id for good.
+#[derive(Debug, Clone)]
+struct DedupWindow {
+    capacity: usize,
+    recent: VecDeque<TxId>,
+    seen: Set<TxId>,
+}
+
+impl DedupWindow {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            recent: VecDeque::with_capacity(capacity),
+            seen: Set::new(),
+        }
+    }
+
+    fn contains(&self, id: TxId) -> bool {
+        self.seen.contains(&id)
+    }
+
+    fn push<T>(
+        &mut self,
+        id: TxId,
+        client_store: &mut impl ClientStore<T>,
+        tx_store: &mut impl TxStore<T>,
+    ) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.recent.len() == self.capacity {
+            if let Some(oldest) = self.recent.pop_front() {
+                self.seen.remove(&oldest);
+                let started = tx_store
+                    .get(oldest)
+                    .is_some_and(|tx| tx.status == TransactionStatus::Started);
+                if started {
+                    forget(oldest, client_store, tx_store);
+                }
+            }
+        }
+        self.recent.push_back(id);
+        self.seen.insert(id);
+    }
+}
+
+// Stores
+//
+// Where the engine keeps clients and transactions. Anything that can look them
+// up by id will do, so a database or disk-backed store can stand in for the
+// maps the engine uses by default.
+pub trait ClientStore<T> {
+    fn get(&self, id: ClientId) -> Option<&Client<T>>;
+    fn get_mut(&mut self, id: ClientId) -> Option<&mut Client<T>>;
+    fn insert(&mut self, id: ClientId, client: Client<T>);
+    // The client with this id, created with nothing if they're new
+    fn entry(&mut self, id: ClientId) -> &mut Client<T>
+    where
+        T: Default;
+}
+
+pub trait TxStore<T> {
+    fn get(&self, id: TxId) -> Option<&Transaction<T>>;
+    fn get_mut(&mut self, id: TxId) -> Option<&mut Transaction<T>>;
+    fn insert(&mut self, id: TxId, tx: Transaction<T>);
+    fn remove(&mut self, id: TxId) -> Option<Transaction<T>>;
+}
+
+impl<T> ClientStore<T> for BTreeMap<ClientId, Client<T>> {
+    fn get(&self, id: ClientId) -> Option<&Client<T>> {
+        BTreeMap::get(self, &id)
+    }
+
+    fn get_mut(&mut self, id: ClientId) -> Option<&mut Client<T>> {
+        BTreeMap::get_mut(self, &id)
+    }
+
+    fn insert(&mut self, id: ClientId, client: Client<T>) {
+        BTreeMap::insert(self, id, client);
+    }
+
+    fn entry(&mut self, id: ClientId) -> &mut Client<T>
+    where
+        T: Default,
+    {
+        BTreeMap::entry(self, id).or_default()
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T> ClientStore<T> for HashMap<ClientId, Client<T>> {
+    fn get(&self, id: ClientId) -> Option<&Client<T>> {
+        HashMap::get(self, &id)
+    }
+
+    fn get_mut(&mut self, id: ClientId) -> Option<&mut Client<T>> {
+        HashMap::get_mut(self, &id)
+    }
+
+    fn insert(&mut self, id: ClientId, client: Client<T>) {
+        HashMap::insert(self, id, client);
+    }
+
+    fn entry(&mut self, id: ClientId) -> &mut Client<T>
+    where
+        T: Default,
+    {
+        HashMap::entry(self, id).or_default()
+    }
+}
+
+impl<T> TxStore<T> for BTreeMap<TxId, Transaction<T>> {
+    fn get(&self, id: TxId) -> Option<&Transaction<T>> {
+        BTreeMap::get(self, &id)
+    }
+
+    fn get_mut(&mut self, id: TxId) -> Option<&mut Transaction<T>> {
+        BTreeMap::get_mut(self, &id)
+    }
+
+    fn insert(&mut self, id: TxId, tx: Transaction<T>) {
+        BTreeMap::insert(self, id, tx);
+    }
+
+    fn remove(&mut self, id: TxId) -> Option<Transaction<T>> {
+        BTreeMap::remove(self, &id)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T> TxStore<T> for HashMap<TxId, Transaction<T>> {
+    fn get(&self, id: TxId) -> Option<&Transaction<T>> {
+        HashMap::get(self, &id)
+    }
+
+    fn get_mut(&mut self, id: TxId) -> Option<&mut Transaction<T>> {
+        HashMap::get_mut(self, &id)
+    }
+
+    fn insert(&mut self, id: TxId, tx: Transaction<T>) {
+        HashMap::insert(self, id, tx);
+    }
+
+    fn remove(&mut self, id: TxId) -> Option<Transaction<T>> {
+        HashMap::remove(self, &id)
+    }
+}
+
+// Engine
+//
+// Owns both stores and the config, so callers don't have to thread them
+// through every call to `handle`. Clients are kept in id order, so anything
+// that lists them is the same from one run to the next.
+// `with_stores` runs it on stores of the caller's own instead, though most
+// reporting is left to the default ones.
+#[derive(Debug)]
+pub struct Engine<T, CS = BTreeMap<ClientId, Client<T>>, TS = Map<TxId, Transaction<T>>> {
+    config: EngineConfig<T>,
+    client_store: CS,
+    tx_store: TS,
+    metrics: Metrics,
+    // Errors from transactions fed in through `Extend`, which has no other way
+    // of handing them back
+    errors: Vec<EngineError>,
+    ledger: Ledger<T>,
+    retention: Retention,
+    tx_history: TxHistory,
+    evicted: Evicted,
+    // How many transactions each client has made in the current rate limit
+    // window
+    per_client_count: Map<ClientId, usize>,
+    dedup: Option<DedupWindow>,
+    on_transaction: Option<Hook<T>>,
+    // How many transactions have been applied, which is what client histories
+    // are stamped with
+    seq: u64,
+}
+
+// Called after every transaction `process` goes through, with the client it
+// left behind, or the error it failed with
+pub type TransactionHook<T> = Box<dyn Fn(&Transaction<T>, Result<&Client<T>, &EngineError>) + Send>;
+
+// Closures have no `Debug`, so this prints a placeholder in their place
+struct Hook<T>(TransactionHook<T>);
+
+impl<T> core::fmt::Debug for Hook<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("Hook")
+    }
+}
+
+impl<T> Default for Engine<T> {
+    fn default() -> Self {
+        Self::with_config(EngineConfig::default())
+    }
+}
+
+impl<T, CS, TS> Engine<T, CS, TS>
+where
+    CS: ClientStore<T>,
+    TS: TxStore<T>,
+{
+    // An engine on top of stores of the caller's own, which may already hold
+    // clients and transactions
+    pub fn with_stores(config: EngineConfig<T>, client_store: CS, tx_store: TS) -> Self {
+        Self {
+            client_store,
+            tx_store,
+            metrics: Metrics::default(),
+            errors: Vec::new(),
+            ledger: Ledger::new(config.max_audit_entries),
+            retention: Retention::default(),
+            tx_history: TxHistory::default(),
+            evicted: Evicted::default(),
+            per_client_count: Map::new(),
+            dedup: config.dedup_window.map(DedupWindow::new),
+            on_transaction: None,
+            seq: 0,
+            config,
+        }
+    }
+
+    // Replaces whatever hook was set before. Forks don't get a copy of it.
+    pub fn set_on_transaction(&mut self, hook: TransactionHook<T>) {
+        self.on_transaction = Some(Hook(hook));
+    }
+
+    pub fn config(&self) -> &EngineConfig<T> {
+        &self.config
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    // Errors collected by `extend` so far
+    pub fn errors(&self) -> &[EngineError] {
+        &self.errors
+    }
+
+    // Hands back the errors collected by `extend`, leaving none behind
+    pub fn take_errors(&mut self) -> Vec<EngineError> {
+        core::mem::take(&mut self.errors)
+    }
+
+    // Every change `process` made to a client, oldest first
+    pub fn audit(&self) -> &[AuditEntry<T>] {
+        self.ledger.entries()
+    }
+
+    // Every merge of one client into another, oldest first
+    pub fn merges(&self) -> &[MergeEntry<T>] {
+        self.ledger.merges()
+    }
+
+    // Every client unlocked by hand, oldest first
+    pub fn unlocks(&self) -> &[UnlockEntry<T>] {
+        self.ledger.unlocks()
+    }
+
+    pub fn client_store(&self) -> &CS {
+        &self.client_store
+    }
+
+    pub fn tx_store(&self) -> &TS {
+        &self.tx_store
+    }
+
+    // A single client, whatever store they're kept in
+    pub fn client(&self, id: ClientId) -> Option<&Client<T>> {
+        self.client_store.get(id)
+    }
+
+    pub fn contains_client(&self, id: ClientId) -> bool {
+        self.client(id).is_some()
+    }
+
+    // Runs every check `process` would, without changing anything
+    pub fn validate(&self, tx: &Transaction<T>) -> Result<(), EngineError>
+    where
+        T: Amount,
+    {
+        self.check_rate_limit(tx.client)?;
+        self.check_evicted(tx)?;
+        decide_in_stores(tx, &self.config, &self.client_store, self.ref_tx(tx)).map(|_| ())
+    }
+
+    // The stored transaction `tx` refers to, or, for a new one, the one it would
+    // be a duplicate of. With a dedup window, a new transaction can only be a
+    // duplicate of a recent one, or of one that's been disputed, which is never
+    // replaced.
+    fn ref_tx(&self, tx: &Transaction<T>) -> Option<&Transaction<T>> {
+        let stored = self.tx_store.get(tx.tx);
+        match &self.dedup {
+            Some(dedup) if stores_new_id(&tx.kind) && !dedup.contains(tx.tx) => {
+                stored.filter(|stored| stored.status != TransactionStatus::Started)
+            }
+            _ => stored,
+        }
+    }
+
+    // An evicted id is still a duplicate, for as long as the dedup window (if
+    // any) would have caught it
+    fn check_evicted(&self, tx: &Transaction<T>) -> Result<(), EngineError> {
+        let in_window = self
+            .dedup
+            .as_ref()
+            .is_none_or(|dedup| dedup.contains(tx.tx));
+        if stores_new_id(&tx.kind) && in_window && self.evicted.contains(tx.tx) {
+            return Err(EngineError::DuplicateTransaction(tx.tx));
+        }
+        Ok(())
+    }
+
+    fn check_rate_limit(&self, client: ClientId) -> Result<(), EngineError> {
+        match self.config.max_transactions_per_client {
+            Some(max) if self.per_client_count.get(&client).copied().unwrap_or(0) >= max => {
+                Err(EngineError::RateLimitExceeded(client))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Starts a new rate limit window, with every client back to no transactions
+    pub fn reset_rate_limit_counters(&mut self) {
+        self.per_client_count.clear();
+    }
+
+    // Applies a single transaction. On error the engine is left exactly as it was
+    // before the call.
+    pub fn process(&mut self, tx: &Transaction<T>) -> Result<Outcome, EngineError>
+    where
+        T: Amount,
+    {
+        let mut touched = vec![tx.client];
+        if let TransactionKind::Transfer { to_client, .. } = tx.kind {
+            touched.push(to_client);
+        }
+        let before: Vec<_> = touched
+            .iter()
+            .map(|client| {
+                self.client_store
+                    .get(*client)
+                    .map_or_else(Client::default, Client::without_history)
+            })
+            .collect();
+        let mut deltas = Vec::new();
+        let result = self
+            .check_rate_limit(tx.client)
+            .and_then(|()| self.check_evicted(tx))
+            .and_then(|()| decide_in_stores(tx, &self.config, &self.client_store, self.ref_tx(tx)))
+            .and_then(|events| {
+                for client in &touched {
+                    deltas.push(net_deltas(&events, *client)?);
+                }
+                Ok(events)
+            });
+        let result = result.map(|events| {
+            apply(&events, &mut self.client_store, &mut self.tx_store);
+            Outcome::of(&tx.kind)
+        });
+        self.metrics.record(&tx.kind, &result);
+        if result.is_ok() {
+            self.seq += 1;
+            for client in &touched {
+                if let Some(client) = self.client_store.get_mut(*client) {
+                    client.history_seq.resize(client.history.len(), self.seq);
+                }
+            }
+            *self.per_client_count.entry(tx.client).or_default() += 1;
+            if let Some(dedup) = self.dedup.as_mut().filter(|_| stores_new_id(&tx.kind)) {
+                dedup.push(tx.tx, &mut self.client_store, &mut self.tx_store);
+            }
+            if let Some(limit) = self
+                .config
+                .tx_history_limit
+                .filter(|_| stores_new_id(&tx.kind))
+            {
+                self.tx_history.push(
+                    tx.tx,
+                    limit,
+                    &mut self.evicted,
+                    &mut self.client_store,
+                    &mut self.tx_store,
+                );
+            }
+            for ((client, deltas), before) in touched.into_iter().zip(deltas).zip(before) {
+                self.log_change(tx, client, deltas, before);
+            }
+            if let Some(window) = self.config.retention_window {
+                self.retention.touch(
+                    tx.tx,
+                    window,
+                    &mut self.evicted,
+                    &mut self.client_store,
+                    &mut self.tx_store,
+                );
+            }
+        }
+        if let Some(Hook(hook)) = &self.on_transaction {
+            match &result {
+                Ok(_) => {
+                    if let Some(client) = self.client_store.get(tx.client) {
+                        hook(tx, Ok(client));
+                    }
+                }
+                Err(e) => hook(tx, Err(e)),
+            }
+        }
+        result
+    }
+
+    // Logs what `tx` did to `client_id`
+    fn log_change(
+        &mut self,
+        tx: &Transaction<T>,
+        client_id: ClientId,
+        deltas: (Delta<T>, Delta<T>),
+        before: Client<T>,
+    ) where
+        T: Amount,
+    {
+        let Some(after) = self
+            .client_store
+            .get(client_id)
+            .map(Client::without_history)
+        else {
+            return;
+        };
+        let status = self
+            .tx_store
+            .get(tx.tx)
+            .map_or(TransactionStatus::Started, |stored| stored.status);
+        self.ledger
+            .record(tx, client_id, status, deltas, before, after);
+    }
+
+    // Applies every transaction in `txs`, stopping at the first error. Unlike
+    // `extend`, this does so whatever the config says, and hands the error back.
+    pub fn extend_strict<I>(&mut self, txs: I) -> Result<(), EngineError>
+    where
+        I: IntoIterator<Item = Transaction<T>>,
+        T: Amount,
+    {
+        for tx in txs {
+            self.process(&tx)?;
+        }
+        Ok(())
+    }
+}
+
+// Puts the engine `process_async` moved away back in its place, however the
+// future ends
+#[cfg(feature = "tokio")]
+struct Restore<'a, T> {
+    engine: &'a mut Engine<T>,
+    returned: std::sync::mpsc::Receiver<Engine<T>>,
+}
+
+#[cfg(feature = "tokio")]
+impl<T> Drop for Restore<'_, T> {
+    fn drop(&mut self) {
+        let returned = match self.returned.try_recv() {
+            Ok(engine) => Ok(engine),
+            // Still on the blocking thread, so the future was dropped part way
+            // through. On a multi-threaded runtime the wait is handed over so
+            // the worker's other tasks can move elsewhere meanwhile.
+            Err(std::sync::mpsc::TryRecvError::Empty) => {
+                let multi_thread = tokio::runtime::Handle::try_current().is_ok_and(|runtime| {
+                    runtime.runtime_flavor() == tokio::runtime::RuntimeFlavor::MultiThread
+                });
+                if multi_thread {
+                    tokio::task::block_in_place(|| self.returned.recv())
+                } else {
+                    self.returned.recv()
+                }
+            }
+            Err(std::sync::mpsc::TryRecvError::Disconnected) => return,
+        };
+        if let Ok(engine) = returned {
+            *self.engine = engine;
+        }
+    }
+}
+
+// Holds the engine on the blocking thread and sends it back when dropped, so
+// it comes back whether the task finishes, panics or never runs at all
+#[cfg(feature = "tokio")]
+struct SendBack<T> {
+    engine: Engine<T>,
+    send: std::sync::mpsc::SyncSender<Engine<T>>,
+}
+
+#[cfg(feature = "tokio")]
+impl<T> Drop for SendBack<T> {
+    fn drop(&mut self) {
+        let _ = self.send.send(core::mem::take(&mut self.engine));
+    }
+}
+
+impl<T> Engine<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_config(config: EngineConfig<T>) -> Self {
+        let tx_store = map_with_capacity(config.initial_tx_capacity);
+        Self::with_stores(config, BTreeMap::new(), tx_store)
+    }
+
+    pub fn clients(&self) -> &BTreeMap<ClientId, Client<T>> {
+        &self.client_store
+    }
+
+    // Every stored transaction `client` made, in tx id order: deposits,
+    // withdrawals, fees, adjustments and transfers they sent. A transfer they
+    // received is the sender's, so it isn't included.
+    pub fn transaction_history(&self, client: ClientId) -> Vec<&Transaction<T>> {
+        let mut history: Vec<_> = self
+            .tx_store
+            .values()
+            .filter(|tx| tx.client == client)
+            .collect();
+        history.sort_unstable_by_key(|tx| tx.tx);
+        history
+    }
+
+    // Everything that moved `client`'s main balance, in the order it was
+    // processed, or `None` if the client has never been seen. The history only
+    // keeps transaction ids, so the n-th time an id shows up tells us what
+    // happened to it: first the transaction itself, then its dispute, then
+    // whatever settled the dispute. An evicted transaction drops out of the
+    // history, so with eviction on, the running balance only adds up what's
+    // still stored.
+    pub fn statement(&self, client: ClientId) -> Option<Vec<StatementEntry<T>>>
+    where
+        T: Amount,
+    {
+        let id = client;
+        let client = self.client_store.get(&client)?;
+        let mut seen: Map<TxId, usize> = Map::new();
+        let (mut available, mut held) = (T::default(), T::default());
+        let mut entries = Vec::with_capacity(client.history.len());
+        for tx_id in &client.history {
+            let Some(tx) = self.tx_store.get(tx_id) else {
+                continue;
+            };
+            if tx.asset.is_some() {
+                continue;
+            }
+            let times = seen.entry(*tx_id).or_default();
+            *times += 1;
+            let full = match tx.kind {
+                TransactionKind::Deposit { amount } | TransactionKind::Withdrawal { amount } => {
+                    Some(amount)
+                }
+                _ => None,
+            };
+            // Settling a dispute moves whatever was disputed
+            let referenced = match *times {
+                3 => tx.disputed.or(full),
+                _ => full,
+            };
+            // A reversal settles the transaction for good, and is the only thing that
+            // can follow it without a dispute or after a resolve
+            let withdrawal = matches!(tx.kind, TransactionKind::Withdrawal { .. });
+            let (kind, effect) = match (*times, &tx.kind, referenced) {
+                (1, TransactionKind::Deposit { amount }, _) => {
+                    (tx.kind.clone(), Effect::Deposit(*amount))
+                }
+                (1, TransactionKind::Withdrawal { amount }, _) => {
+                    (tx.kind.clone(), Effect::Withdrawal(*amount))
+                }
+                (1, TransactionKind::Fee { amount }, _) => (tx.kind.clone(), Effect::Fee(*amount)),
+                (1, TransactionKind::Adjustment { amount }, _) => {
+                    (tx.kind.clone(), Effect::Adjustment(*amount))
+                }
+                (1, TransactionKind::Transfer { to_client, amount }, _) if *to_client == id => {
+                    (tx.kind.clone(), Effect::Deposit(*amount))
+                }
+                (1, TransactionKind::Transfer { to_client, amount }, _) => (
+                    tx.kind.clone(),
+                    Effect::Transfer {
+                        to_client: *to_client,
+                        amount: *amount,
+                    },
+                ),
+                (2, _, Some(amount)) if tx.disputed.is_none() => (
+                    TransactionKind::Reversal,
+                    Effect::Reversal { amount, withdrawal },
+                ),
+                (4, _, Some(amount)) => (
+                    TransactionKind::Reversal,
+                    Effect::Reversal { amount, withdrawal },
+                ),
+                (2, _, Some(amount)) => match tx.disputed {
+                    Some(partial) if partial != amount => (
+                        TransactionKind::PartialDispute { amount: partial },
+                        Effect::Dispute(partial),
+                    ),
+                    _ => (
+                        TransactionKind::Dispute {
+                            reason: tx.dispute_reason.clone(),
+                        },
+                        Effect::Dispute(amount),
+                    ),
+                },
+                (3, _, Some(amount)) if tx.status == TransactionStatus::Chargeback => (
+                    TransactionKind::Chargeback,
+                    Effect::Chargeback {
+                        amount,
+                        withdrawal,
+                        lock: true,
+                    },
+                ),
+                (3, _, Some(amount)) => (TransactionKind::Resolve, Effect::Resolve(amount)),
+                _ => continue,
+            };
+            let before = available;
+            (available, held) = effect.balances(available, held)?;
+            entries.push(StatementEntry {
+                tx_id: *tx_id,
+                kind,
+                status: tx.status,
+                delta: available.checked_sub(&before)?,
+                running_balance: available,
+            });
+        }
+        Some(entries)
+    }
+
+    // Predicts what `client` would look like after `pending`, without touching
+    // the engine. Pending transactions for other clients are ignored. If any of
+    // them would fail, the errors are handed back along with their index in
+    // `pending` instead.
+    pub fn project_balance(
+        &self,
+        client: ClientId,
+        pending: &[Transaction<T>],
+    ) -> Result<Client<T>, Vec<(usize, EngineError)>>
+    where
+        T: Amount,
+    {
+        // A scratch copy of just the state the projection can run into: the client,
+        // the transactions they already have, and any stored transaction a pending
+        // one clashes with or refers to
+        let mut client_store = BTreeMap::new();
+        let mut tx_store = Map::new();
+        if let Some(existing) = self.client_store.get(&client) {
+            for id in &existing.history {
+                if let Some(stored) = self.tx_store.get(id) {
+                    tx_store.insert(*id, stored.clone());
+                }
+            }
+            client_store.insert(client, existing.clone());
+        }
+        for tx in pending {
+            if let Some(stored) = self.tx_store.get(&tx.tx) {
+                tx_store.insert(tx.tx, stored.clone());
+            }
+        }
+        let mut errors = Vec::new();
+        for (index, tx) in pending.iter().enumerate() {
+            if tx.client != client {
+                continue;
+            }
+            if let Err(e) = handle_with_config(tx, &self.config, &mut client_store, &mut tx_store) {
+                errors.push((index, e));
+            }
+        }
+        if errors.is_empty() {
+            Ok(client_store.remove(&client).unwrap_or_default())
+        } else {
+            Err(errors)
+        }
+    }
+
+    // A snapshot of every client, ordered by id
+    pub fn client_snapshots(&self) -> Result<Vec<ClientSnapshot<T>>, EngineError>
+    where
+        T: CheckedAdd + Copy,
+    {
+        client_snapshots(&self.client_store)
+    }
+
+    // Writes every client in ascending id order under a header, the same way the
+    // binary's default report does
+    #[cfg(feature = "std")]
+    pub fn export_csv<W: Write>(&self, writer: W) -> Result<(), csv::Error>
+    where
+        T: Display + CheckedAdd + Copy,
+    {
+        let snapshots = self
+            .client_snapshots()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let mut wtr = csv::Writer::from_writer(writer);
+        wtr.write_record(REPORT_HEADER)?;
+        for snapshot in snapshots {
+            wtr.write_record(snapshot.report_row())?;
+        }
+        wtr.flush()?;
+        Ok(())
+    }
+
+    // The same, as a JSON array of client snapshots
+    #[cfg(feature = "serde")]
+    pub fn export_json<W: Write>(&self, writer: W) -> serde_json::Result<()>
+    where
+        T: serde::Serialize + CheckedAdd + Copy,
+    {
+        let snapshots = self
+            .client_snapshots()
+            .map_err(<serde_json::Error as serde::ser::Error>::custom)?;
+        serde_json::to_writer(writer, &snapshots)
+    }
+
+    // Every client, ordered by id
+    pub fn clients_sorted(&self) -> Vec<(ClientId, &Client<T>)> {
+        self.client_store.iter().map(|(id, c)| (*id, c)).collect()
+    }
+
+    pub fn transactions(&self) -> &Map<TxId, Transaction<T>> {
+        &self.tx_store
+    }
+
+    // Every locked client, in ascending id order
+    pub fn locked_clients(&self) -> impl Iterator<Item = (ClientId, &Client<T>)> {
+        self.client_store
+            .iter()
+            .filter(|(_, client)| client.locked)
+            .map(|(id, client)| (*id, client))
+    }
+
+    // The ids of every client that isn't solvent, in ascending order
+    pub fn insolvent_clients(&self) -> Vec<ClientId>
+    where
+        T: PartialOrd + Default,
+    {
+        self.client_store
+            .iter()
+            .filter(|(_, client)| !client.is_solvent())
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
+    // The fraction of a client's deposits and withdrawals that were charged
+    // back, or `None` for an unknown client or one with neither. A reversed
+    // transaction counts as not charged back, and forgotten ones aren't counted
+    // at all.
+    pub fn chargeback_ratio(&self, client: ClientId) -> Option<f64> {
+        let history = &self.client_store.get(&client)?.history;
+        let mut seen = Set::new();
+        let (mut monetary, mut charged_back) = (0u32, 0u32);
+        for tx in history.iter().filter(|tx| seen.insert(**tx)) {
+            let Some(tx) = self.tx_store.get(tx) else {
+                continue;
+            };
+            if tx.client == client
+                && matches!(
+                    tx.kind,
+                    TransactionKind::Deposit { .. } | TransactionKind::Withdrawal { .. }
+                )
+            {
+                monetary += 1;
+                if tx.status == TransactionStatus::Chargeback {
+                    charged_back += 1;
+                }
+            }
+        }
+        (monetary > 0).then(|| f64::from(charged_back) / f64::from(monetary))
+    }
+
+    // The ids of every client whose chargeback ratio is above `threshold`, in
+    // ascending order
+    pub fn high_risk_clients(&self, threshold: f64) -> Vec<ClientId> {
+        self.client_store
+            .keys()
+            .copied()
+            .filter(|id| {
+                self.chargeback_ratio(*id)
+                    .is_some_and(|ratio| ratio > threshold)
+            })
+            .collect()
+    }
+
+    // Totals of every dispute and chargeback across all clients, going by the
+    // state each disputed transaction ended up in. A dispute that was resolved
+    // and then reversed still counts as a dispute, but not as a chargeback.
+    pub fn dispute_summary(&self) -> Result<DisputeSummary<T>, EngineError>
+    where
+        T: Default + CheckedAdd + Copy,
+    {
+        let mut summary = DisputeSummary::<T>::default();
+        for tx in self.tx_store.values() {
+            let Some(disputed) = tx.disputed else {
+                continue;
+            };
+            let overflow = EngineError::Overflow { client: tx.client };
+            summary.disputes += 1;
+            match tx.status {
+                TransactionStatus::Disputed => {
+                    summary.held = summary.held.checked_add(&disputed).ok_or(overflow)?;
+                }
+                TransactionStatus::Chargeback => {
+                    summary.chargebacks += 1;
+                    summary.charged_back = summary
+                        .charged_back
+                        .checked_add(&disputed)
+                        .ok_or(overflow)?;
+                }
+                TransactionStatus::Started
+                | TransactionStatus::Resolved
+                | TransactionStatus::Reversed => {}
+            }
+        }
+        Ok(summary)
+    }
+
+    // Compares every client here with the one under the same id in `other`, e.g.
+    // to check that an engine restored from a snapshot caught up with the one it
+    // was taken from. Every list is in ascending id order.
+    pub fn reconcile(&self, other: &Engine<T>) -> ReconciliationReport<T>
+    where
+        T: PartialEq + Clone,
+    {
+        let mut report = ReconciliationReport {
+            matching_clients: Vec::new(),
+            mismatched_clients: Vec::new(),
+            only_in_self: Vec::new(),
+            only_in_other: Vec::new(),
+        };
+        for (id, client) in &self.client_store {
+            match other.client_store.get(id) {
+                Some(theirs) if theirs.same_balances(client) => report.matching_clients.push(*id),
+                Some(theirs) => {
+                    report
+                        .mismatched_clients
+                        .push((*id, client.clone(), theirs.clone()));
+                }
+                None => report.only_in_self.push(*id),
+            }
+        }
+        report.only_in_other = other
+            .client_store
+            .keys()
+            .filter(|id| !self.client_store.contains_key(id))
+            .copied()
+            .collect();
+        report
+    }
+
+    // Folds `from` into `into`: their balances, in every asset, are added to
+    // `into`'s, their transactions are moved over and `from` is gone for good.
+    // Neither client can be locked, and both have to be in the same currency if
+    // they have one yet. Transactions are keyed by id alone, so moving them
+    // can't clash with any of `into`'s, and transfers to `from` become transfers
+    // to `into`. The two histories are interleaved in the order they happened.
+    pub fn merge_clients(&mut self, from: ClientId, into: ClientId) -> Result<(), EngineError>
+    where
+        T: Amount,
+    {
+        if from == into {
+            return Ok(());
+        }
+        let source = self
+            .client_store
+            .get(&from)
+            .ok_or(EngineError::ClientNotFound(from))?;
+        let target = self
+            .client_store
+            .get(&into)
+            .ok_or(EngineError::ClientNotFound(into))?;
+        if source.locked {
+            return Err(EngineError::ClientLocked(from));
+        }
+        if target.locked {
+            return Err(EngineError::ClientLocked(into));
+        }
+        if let (Some(expected), Some(found)) = (&target.currency, &source.currency) {
+            if expected != found {
+                return Err(EngineError::CurrencyMismatch {
+                    expected: expected.clone(),
+                    found: found.clone(),
+                });
+            }
+        }
+        let overflow = EngineError::Overflow { client: into };
+        let mut merged = target.clone();
+        merged.available = merged
+            .available
+            .checked_add(&source.available)
+            .ok_or(overflow.clone())?;
+        merged.held = merged
+            .held
+            .checked_add(&source.held)
+            .ok_or(overflow.clone())?;
+        for (asset, balance) in &source.assets {
+            let entry = merged.assets.entry(asset.clone()).or_default();
+            entry.available = entry
+                .available
+                .checked_add(&balance.available)
+                .ok_or(overflow.clone())?;
+            entry.held = entry
+                .held
+                .checked_add(&balance.held)
+                .ok_or(overflow.clone())?;
+        }
+        // A transfer between the two would be one to itself once they're merged,
+        // moving nothing, so it's left out of the history rather than showing up
+        // on both sides
+        let between: Set<TxId> = self
+            .tx_store
+            .iter()
+            .filter(|(_, tx)| match tx.kind {
+                TransactionKind::Transfer { to_client, .. } => {
+                    (tx.client, to_client) == (from, into) || (tx.client, to_client) == (into, from)
+                }
+                _ => false,
+            })
+            .map(|(id, _)| *id)
+            .collect();
+        let mut history: Vec<_> = merged
+            .stamped_history()
+            .chain(source.stamped_history())
+            .filter(|(id, _)| !between.contains(id))
+            .collect();
+        // Stable, so `into`'s entries come first when there's nothing to go by
+        history.sort_by_key(|(_, seq)| *seq);
+        (merged.history, merged.history_seq) = history.into_iter().unzip();
+        merged.currency = merged.currency.or_else(|| source.currency.clone());
+
+        let before = target.clone();
+        let moved = self.client_store.remove(&from).unwrap_or_default();
+        self.client_store.insert(into, merged.clone());
+        for tx in self.tx_store.values_mut() {
+            if tx.client == from {
+                tx.client = into;
+            }
+            if let TransactionKind::Transfer { to_client, .. } = &mut tx.kind {
+                if *to_client == from {
+                    *to_client = into;
+                }
+            }
+        }
+        if let Some(count) = self.per_client_count.remove(&from) {
+            *self.per_client_count.entry(into).or_default() += count;
+        }
+        self.ledger.record_merge(from, into, moved, before, merged);
+        Ok(())
+    }
+
+    // Lets a locked client make transactions again, e.g. once a chargeback has
+    // been looked into. Only the lock goes: the funds the chargeback took stay
+    // gone. Unlocking a client who isn't locked does nothing, and isn't logged.
+    pub fn unlock_client(&mut self, client: ClientId) -> Result<(), EngineError>
+    where
+        T: Clone,
+    {
+        let existing = self
+            .client_store
+            .get_mut(&client)
+            .ok_or(EngineError::ClientNotFound(client))?;
+        if existing.locked {
+            let before = existing.clone();
+            existing.locked = false;
+            self.ledger.record_unlock(client, before);
+        }
+        Ok(())
+    }
+
+    // Copies out everything needed to pick up where this engine left off
+    pub fn snapshot(&self) -> EngineSnapshot<T>
+    where
+        T: Clone,
+    {
+        EngineSnapshot {
+            client_store: self.client_store.clone(),
+            tx_store: self.tx_store.clone(),
+        }
+    }
+
+    // The stores and config, for `EngineState::apply` to try transactions on
+    pub fn state(&self) -> EngineState<T>
+    where
+        T: Clone,
+    {
+        EngineState {
+            client_store: self.client_store.clone(),
+            tx_store: self.tx_store.clone(),
+            config: self.config.clone(),
+        }
+    }
+
+    // Rebuilds an engine, with the default config, from a snapshot. Histories
+    // carry on from the latest stamp in it.
+    pub fn restore(snapshot: EngineSnapshot<T>) -> Self {
+        let seq = snapshot
+            .client_store
+            .values()
+            .flat_map(|client| client.history_seq.iter().copied())
+            .max()
+            .unwrap_or(0);
+        Self {
+            seq,
+            ..Self::with_stores(
+                EngineConfig::default(),
+                snapshot.client_store,
+                snapshot.tx_store,
+            )
+        }
+    }
+
+    // A copy of the stores, with the same config, to try transactions out on
+    // without touching this engine, e.g. for a dry run. Metrics, errors and the
+    // audit log start over.
+    pub fn fork(&self) -> Self
+    where
+        T: Clone,
+    {
+        Self {
+            config: self.config.clone(),
+            ledger: Ledger::new(self.config.max_audit_entries),
+            retention: self.retention.clone(),
+            tx_history: self.tx_history.clone(),
+            evicted: self.evicted.clone(),
+            per_client_count: self.per_client_count.clone(),
+            dedup: self.dedup.clone(),
+            seq: self.seq,
+            ..Self::restore(self.snapshot())
+        }
+    }
+
+    // What the stores would look like after `tx`, going by this engine's config,
+    // dedup window and rate limits, without touching the engine. It forks the
+    // whole engine every time, so it's for trying things out rather than
+    // processing.
+    pub fn preview(&self, tx: &Transaction<T>) -> Result<EngineSnapshot<T>, EngineError>
+    where
+        T: Amount,
+    {
+        let mut fork = self.fork();
+        fork.process(tx)?;
+        Ok(fork.snapshot())
+    }
+
+    // Writes both stores out as JSON, statuses included, so processing can pick up
+    // again later with `load`
+    #[cfg(feature = "serde")]
+    pub fn save<W: Write>(&self, writer: W) -> serde_json::Result<()>
+    where
+        T: serde::Serialize,
+    {
+        let snapshot = SnapshotRef {
+            client_store: &self.client_store,
+            tx_store: &self.tx_store,
+        };
+        serde_json::to_writer(writer, &snapshot)
+    }
+
+    // Reads back an engine written by `save`, with the default config
+    #[cfg(feature = "serde")]
+    pub fn load<R: Read>(reader: R) -> serde_json::Result<Self>
+    where
+        T: serde::de::DeserializeOwned,
+    {
+        serde_json::from_reader(reader).map(Self::restore)
+    }
+
+    // Same as `process`, but runs on tokio's blocking thread pool so it can be
+    // awaited from async code. `spawn_blocking` needs everything it touches to be
+    // owned, so the engine's state is moved onto the blocking thread and sent
+    // back once it's done, even if a hook panics on the way. If the returned
+    // future is dropped before then, the drop waits for it to come back, which
+    // is never long since processing doesn't wait on anything. A truly async
+    // store backend would do away with all of this.
+    #[cfg(feature = "tokio")]
+    pub async fn process_async(&mut self, tx: Transaction<T>) -> Result<Outcome, EngineError>
+    where
+        T: Amount + Send + 'static,
+    {
+        let (send, returned) = std::sync::mpsc::sync_channel(1);
+        let mut back = SendBack {
+            engine: core::mem::take(self),
+            send,
+        };
+        let restore = Restore {
+            engine: self,
+            returned,
+        };
+        let result = tokio::task::spawn_blocking(move || {
+            std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| back.engine.process(&tx)))
+        })
+        .await;
+        // The engine is back in place before any panic carries on
+        drop(restore);
+        match result {
+            Ok(Ok(result)) => result,
+            Ok(Err(panic)) => std::panic::resume_unwind(panic),
+            Err(e) if e.is_cancelled() => Err(EngineError::Cancelled),
+            Err(e) => std::panic::resume_unwind(e.into_panic()),
+        }
+    }
+
+    // Reads CSV records, with a header, from an async source and applies each one
+    // as soon as it's been read. Only the reading is async: processing happens
+    // right there on the calling task, as it's quick and never waits on
+    // anything. As with `extend`, failed transactions end up in `errors`, and a
+    // strict engine stops at the first one. Like the binary, records that can't
+    // be parsed are skipped, and handed back with their line number and what was
+    // wrong with them, except that a strict engine fails on the first one. Input
+    // that can't be read at all always fails.
+    #[cfg(feature = "tokio")]
+    pub async fn run_async<R>(&mut self, reader: R) -> io::Result<Vec<(u64, Cow<'static, str>)>>
+    where
+        R: tokio::io::AsyncRead + Unpin + Send,
+        T: Amount + FromStr,
+    {
+        // The asset or transfer destination column is optional on every row
+        let mut rdr = csv_async::AsyncReaderBuilder::new()
+            .flexible(true)
+            .create_reader(reader);
+        let mut record = csv_async::StringRecord::new();
+        let mut unparsable = Vec::new();
+        while rdr.read_record(&mut record).await? {
+            let line = record.position().map_or(0, |position| position.line());
+            let tx = match Transaction::try_from(record.iter().collect::<StringRecord>()) {
+                Ok(tx) => tx,
+                Err(e) if self.config.strict => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+                }
+                Err(e) => {
+                    unparsable.push((line, e));
+                    continue;
+                }
+            };
+            if let Err(e) = self.process(&tx) {
+                self.errors.push(e);
+                if self.config.strict {
+                    break;
+                }
+            }
+        }
+        Ok(unparsable)
+    }
+
+    // Throws away all derived state and rebuilds it from `txs`, in the order
+    // given. The transaction store is rebuilt along with the balances, since
+    // keeping the old one around would turn every replayed deposit into a
+    // duplicate. Transactions that fail are skipped and handed back, unless the
+    // engine is strict, in which case replaying stops at the first error.
+    pub fn replay<I>(&mut self, txs: I) -> Result<Vec<EngineError>, EngineError>
+    where
+        I: IntoIterator<Item = Transaction<T>>,
+        T: Amount,
+    {
+        self.client_store.clear();
+        self.tx_store.clear();
+        self.metrics = Metrics::default();
+        self.errors.clear();
+        self.ledger.clear();
+        self.retention = Retention::default();
+        self.tx_history = TxHistory::default();
+        self.evicted = Evicted::default();
+        self.per_client_count.clear();
+        self.dedup = self.config.dedup_window.map(DedupWindow::new);
+        self.seq = 0;
+        let mut skipped = Vec::new();
+        // Replayed transactions start over, whatever state they were stored in,
+        // since they're stored afresh
+        for tx in txs {
+            match self.process(&tx) {
+                Ok(_) => {}
+                Err(e) if self.config.strict => return Err(e),
+                Err(e) => skipped.push(e),
+            }
+        }
+        Ok(skipped)
+    }
+}
+
+// `Extend::extend` can't return anything, so errors end up in `Engine::errors`.
+// A strict engine still stops at the first one, but callers that need to know
+// right away should use `Engine::extend_strict` instead.
+impl<T, CS, TS> Extend<Transaction<T>> for Engine<T, CS, TS>
+where
+    T: Amount,
+    CS: ClientStore<T>,
+    TS: TxStore<T>,
+{
+    fn extend<I: IntoIterator<Item = Transaction<T>>>(&mut self, txs: I) {
+        for tx in txs {
+            if let Err(e) = self.process(&tx) {
+                self.errors.push(e);
+                if self.config.strict {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+// Shared Engine
+//
+// An engine that can be handed to several threads at once. Everything goes
+// through a single lock: sharding by client wouldn't be sound, since transfers
+// touch two clients and transaction ids have to be unique across all of them.
+#[cfg(feature = "std")]
+#[derive(Debug, Default)]
+pub struct SharedEngine<T> {
+    engine: Mutex<Engine<T>>,
+}
+
+#[cfg(feature = "std")]
+impl<T> SharedEngine<T> {
+    pub fn new(engine: Engine<T>) -> Self {
+        Self {
+            engine: Mutex::new(engine),
+        }
+    }
+
+    pub fn process(&self, tx: &Transaction<T>) -> Result<Outcome, EngineError>
+    where
+        T: Amount,
+    {
+        self.lock()?.process(tx)
+    }
+
+    // Holds the lock for as long as the guard lives, e.g. to read balances
+    // without another thread changing them halfway through. Once a thread has
+    // panicked while holding it, nothing can tell how far its changes got, so
+    // this fails from then on.
+    pub fn lock(&self) -> Result<MutexGuard<'_, Engine<T>>, EngineError> {
+        self.engine.lock().map_err(|_| EngineError::Poisoned)
+    }
+
+    pub fn into_inner(self) -> Result<Engine<T>, EngineError> {
+        self.engine.into_inner().map_err(|_| EngineError::Poisoned)
+    }
+}
+
+// Engine Snapshot
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct EngineSnapshot<T> {
+    pub client_store: BTreeMap<ClientId, Client<T>>,
+    pub tx_store: Map<TxId, Transaction<T>>,
+}
+
+// Same layout as `EngineSnapshot`, borrowing the stores so saving doesn't need
+// to clone them
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize)]
+struct SnapshotRef<'a, T> {
+    client_store: &'a BTreeMap<ClientId, Client<T>>,
+    tx_store: &'a Map<TxId, Transaction<T>>,
+}
+
+// Engine State
+//
+// Both stores and the config they're processed under, so transactions can be
+// tried out without an engine. Whatever else an engine keeps track of over a
+// run, like dedup windows, rate limits and metrics, is left out.
+#[derive(Debug, Clone)]
+pub struct EngineState<T> {
+    pub client_store: BTreeMap<ClientId, Client<T>>,
+    pub tx_store: Map<TxId, Transaction<T>>,
+    pub config: EngineConfig<T>,
+}
+
+impl<T: Amount> EngineState<T> {
+    // What this state would be after `tx`, leaving it as it is. It clones both
+    // stores every time, so it's for testing and what-ifs rather than
+    // processing.
+    pub fn apply(&self, tx: &Transaction<T>) -> Result<Self, EngineError> {
+        let mut engine = Engine {
+            config: self.config.clone(),
+            ..Engine::restore(EngineSnapshot {
+                client_store: self.client_store.clone(),
+                tx_store: self.tx_store.clone(),
+            })
+        };
+        engine.process(tx)?;
+        Ok(Self {
+            client_store: engine.client_store,
+            tx_store: engine.tx_store,
+            config: engine.config,
+        })
+    }
+}
+
+// Outcome
+//
+// What an applied transaction did, so callers can react to a chargeback, say,
+// without looking at the stores. A chargeback is the only transaction that can
+// lock a client, and only while `EngineConfig::lock_on_chargeback` is set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    // Funds were moved in, out or between clients
+    Applied,
+    Disputed,
+    Resolved,
+    ChargedBack,
+    Reversed,
+}
+
+impl Outcome {
+    fn of<T>(kind: &TransactionKind<T>) -> Self {
+        match kind {
+            TransactionKind::Deposit { .. }
+            | TransactionKind::Withdrawal { .. }
+            | TransactionKind::Fee { .. }
+            | TransactionKind::Transfer { .. }
+            | TransactionKind::Adjustment { .. } => Outcome::Applied,
+            TransactionKind::Dispute { .. } | TransactionKind::PartialDispute { .. } => {
+                Outcome::Disputed
+            }
+            TransactionKind::Resolve => Outcome::Resolved,
+            TransactionKind::Chargeback => Outcome::ChargedBack,
+            TransactionKind::Reversal => Outcome::Reversed,
+            #[cfg(test)]
+            TransactionKind::Unsupported => unreachable!("unsupported kinds are always rejected"),
+        }
+    }
+}
+
+// Transaction Handler
+pub fn handle<T, CS, TS>(
+    tx: &Transaction<T>,
+    client_store: &mut CS,
+    tx_store: &mut TS,
+) -> Result<Outcome, EngineError>
+where
+    T: Amount,
+    CS: ClientStore<T>,
+    TS: TxStore<T>,
+{
+    handle_with_config(tx, &EngineConfig::default(), client_store, tx_store)
+}
+
+// Same as `handle`, for each of `txs` in order. The results line up with `txs`,
+// and a failed transaction doesn't stop the ones after it.
+pub fn handle_many<T, CS, TS>(
+    txs: &[Transaction<T>],
+    client_store: &mut CS,
+    tx_store: &mut TS,
+) -> Vec<Result<Outcome, EngineError>>
+where
+    T: Amount,
+    CS: ClientStore<T>,
+    TS: TxStore<T>,
+{
+    txs.iter()
+        .map(|tx| handle(tx, client_store, tx_store))
+        .collect()
+}
+
+// Same as `handle`, but following the rules in `config`
+pub fn handle_with_config<T, CS, TS>(
+    tx: &Transaction<T>,
+    config: &EngineConfig<T>,
+    client_store: &mut CS,
+    tx_store: &mut TS,
+) -> Result<Outcome, EngineError>
+where
+    T: Amount,
+    CS: ClientStore<T>,
+    TS: TxStore<T>,
+{
+    let events = decide_in_stores(tx, config, client_store, tx_store.get(tx.tx))?;
+    apply(&events, client_store, tx_store);
+    Ok(Outcome::of(&tx.kind))
+}
+
+// Domain Event
+//
+// A single change to the stores. `decide` works out which of these a transaction
+// causes without touching anything, and `apply` writes them out, so the same
+// events can be logged, tested or replayed on their own. Balance changes carry
+// their direction, so an amount type that can't go below zero works too.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum DomainEvent<T> {
+    AvailableChanged {
+        client: ClientId,
+        asset: Option<String>,
+        delta: Delta<T>,
+    },
+    HeldChanged {
+        client: ClientId,
+        asset: Option<String>,
+        delta: Delta<T>,
+    },
+    ClientLocked {
+        client: ClientId,
+    },
+    HistoryRecorded {
+        client: ClientId,
+        tx_id: TxId,
+    },
+    TransactionStored {
+        tx: Transaction<T>,
+    },
+    TransactionStatusUpdated {
+        tx_id: TxId,
+        status: TransactionStatus,
+    },
+    DisputedAmountRecorded {
+        tx_id: TxId,
+        amount: T,
+    },
+    DisputeReasonRecorded {
+        tx_id: TxId,
+        reason: DisputeReason,
+    },
+    CurrencyEstablished {
+        client: ClientId,
+        currency: Currency,
+    },
+}
+
+// Which way a balance moves, and by how much
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Delta<T> {
+    Credit(T),
+    Debit(T),
+}
+
+impl<T: Amount> Delta<T> {
+    fn apply_to(self, balance: &mut T) {
+        match self {
+            Delta::Credit(amount) => *balance += amount,
+            Delta::Debit(amount) => *balance -= amount,
+        }
+    }
+
+    // Both moves together. Opposite ones never overflow, since the smaller is
+    // taken off the larger.
+    fn checked_add(self, other: Self) -> Option<Self> {
+        use Delta::{Credit, Debit};
+        match (self, other) {
+            (Credit(a), Credit(b)) => a.checked_add(&b).map(Credit),
+            (Debit(a), Debit(b)) => a.checked_add(&b).map(Debit),
+            (Credit(a), Debit(b)) | (Debit(b), Credit(a)) => {
+                if a < b {
+                    b.checked_sub(&a).map(Debit)
+                } else {
+                    a.checked_sub(&b).map(Credit)
+                }
+            }
+        }
+    }
+}
+
+// How far `events` move the main available and held balances of `client`
+fn net_deltas<T: Amount>(
+    events: &[DomainEvent<T>],
+    client: ClientId,
+) -> Result<(Delta<T>, Delta<T>), EngineError> {
+    let mut available = Delta::Credit(T::default());
+    let mut held = Delta::Credit(T::default());
+    for event in events {
+        let (total, delta) = match event {
+            DomainEvent::AvailableChanged {
+                client: id,
+                asset: None,
+                delta,
+            } if *id == client => (&mut available, delta),
+            DomainEvent::HeldChanged {
+                client: id,
+                asset: None,
+                delta,
+            } if *id == client => (&mut held, delta),
+            _ => continue,
+        };
+        *total = total
+            .checked_add(*delta)
+            .ok_or(EngineError::Overflow { client })?;
+    }
+    Ok((available, held))
+}
+
+// What `tx` would do to `client`, with the default config. `ref_tx` is whatever
+// is already stored under the id of `tx`: the transaction a dispute, resolve or
+// chargeback refers to, or a duplicate. A transfer is decided as if it went to
+// a new client; use `decide_with_config` to pass along one who already exists.
+pub fn decide<T: Amount>(
+    tx: &Transaction<T>,
+    client: &Client<T>,
+    ref_tx: Option<&Transaction<T>>,
+) -> Result<Vec<DomainEvent<T>>, EngineError> {
+    decide_with_config(tx, &EngineConfig::default(), client, ref_tx, None)
+}
+
+// Same as `decide`, but following the rules in `config`. `destination` is the
+// client a transfer goes to, if they exist.
+pub fn decide_with_config<T: Amount>(
+    tx: &Transaction<T>,
+    config: &EngineConfig<T>,
+    client: &Client<T>,
+    ref_tx: Option<&Transaction<T>>,
+    destination: Option<&Client<T>>,
+) -> Result<Vec<DomainEvent<T>>, EngineError> {
+    let effect = check(tx, config, client, ref_tx, destination)?;
+    let mut events = effect.events(tx, effect_asset(tx, &effect, ref_tx))?;
+    // The first money a client moves decides their currency, whichever end of a
+    // transfer they're on
+    if tx.kind.is_monetary() {
+        let currency = tx.currency.as_ref().unwrap_or(&config.default_currency);
+        let mut parties = vec![(tx.client, Some(client))];
+        if let TransactionKind::Transfer { to_client, .. } = tx.kind {
+            parties.push((to_client, destination));
+        }
+        for (id, party) in parties {
+            if party.is_none_or(|party| party.currency.is_none()) {
+                events.push(DomainEvent::CurrencyEstablished {
+                    client: id,
+                    currency: currency.clone(),
+                });
+            }
+        }
+    }
+    Ok(events)
+}
+
+// Looks up everything `tx` depends on in the stores, and decides on it. A client
+// who hasn't been seen yet is decided on as an empty one.
+fn decide_in_stores<T: Amount>(
+    tx: &Transaction<T>,
+    config: &EngineConfig<T>,
+    client_store: &impl ClientStore<T>,
+    ref_tx: Option<&Transaction<T>>,
+) -> Result<Vec<DomainEvent<T>>, EngineError> {
+    let new_client = Client::default();
+    let client = client_store.get(tx.client).unwrap_or(&new_client);
+    let destination = match tx.kind {
+        TransactionKind::Transfer { to_client, .. } => client_store.get(to_client),
+        _ => None,
+    };
+    decide_with_config(tx, config, client, ref_tx, destination)
+}
+
+// Whether `kind` gets stored under its own id, rather than referring to one
+fn stores_new_id<T>(kind: &TransactionKind<T>) -> bool {
+    kind.is_monetary()
+}
+
+// Writes out events in order. A client is only created here, once we know their
+// first transaction goes through. Events from `decide` never overflow, so
+// anything else is on the caller.
+pub fn apply<T: Amount>(
+    events: &[DomainEvent<T>],
+    client_store: &mut impl ClientStore<T>,
+    tx_store: &mut impl TxStore<T>,
+) {
+    for event in events {
+        match event {
+            DomainEvent::AvailableChanged {
+                client,
+                asset,
+                delta,
+            } => {
+                let client = client_store.entry(*client);
+                delta.apply_to(client.balance_mut(asset.as_deref()).0);
+            }
+            DomainEvent::HeldChanged {
+                client,
+                asset,
+                delta,
+            } => {
+                let client = client_store.entry(*client);
+                delta.apply_to(client.balance_mut(asset.as_deref()).1);
+            }
+            DomainEvent::ClientLocked { client } => {
+                client_store.entry(*client).locked = true;
+            }
+            DomainEvent::HistoryRecorded { client, tx_id } => {
+                client_store.entry(*client).history.push(*tx_id);
+            }
+            DomainEvent::TransactionStored { tx } => {
+                tx_store.insert(tx.tx, tx.clone());
+            }
+            DomainEvent::TransactionStatusUpdated { tx_id, status } => {
+                set_status(tx_store, *tx_id, *status);
+            }
+            DomainEvent::DisputedAmountRecorded { tx_id, amount } => {
+                if let Some(tx) = tx_store.get_mut(*tx_id) {
+                    tx.disputed = Some(*amount);
+                }
+            }
+            DomainEvent::DisputeReasonRecorded { tx_id, reason } => {
+                if let Some(tx) = tx_store.get_mut(*tx_id) {
+                    tx.dispute_reason = Some(reason.clone());
+                }
+            }
+            DomainEvent::CurrencyEstablished { client, currency } => {
+                client_store.entry(*client).currency = Some(currency.clone());
+            }
+        }
+    }
+}
+
+// What an accepted transaction is going to do to the stores
+#[derive(Clone, Copy)]
+enum Effect<T> {
+    Deposit(T),
+    Withdrawal(T),
+    Fee(T),
+    Transfer {
+        to_client: ClientId,
+        amount: T,
+    },
+    Adjustment(T),
+    Dispute(T),
+    Resolve(T),
+    Chargeback {
+        amount: T,
+        withdrawal: bool,
+        lock: bool,
+    },
+    Reversal {
+        amount: T,
+        withdrawal: bool,
+    },
+}
+
+// Every check a transaction has to pass, without writing anything. Keeping
+// this apart from `apply` means an error always leaves both stores exactly as
+// they were, and lets `Engine::validate` share the exact same rules.
+fn check<T>(
+    tx: &Transaction<T>,
+    config: &EngineConfig<T>,
+    client: &Client<T>,
+    ref_tx: Option<&Transaction<T>>,
+    destination: Option<&Client<T>>,
+) -> Result<Effect<T>, EngineError>
+where
+    T: Amount,
+{
+    let effect = check_rules(tx, config, client, ref_tx, destination)?;
+    // Last of all, the new balances have to fit
+    let asset = effect_asset(tx, &effect, ref_tx);
+    let (available, held) = client.balance(asset.as_deref());
+    if effect.balances(available, held).is_none() {
+        return Err(EngineError::Overflow { client: tx.client });
+    }
+    if let Effect::Transfer { to_client, amount } = effect {
+        let available =
+            destination.map_or_else(T::default, |client| client.available_in(asset.as_deref()));
+        if available.checked_add(&amount).is_none() {
+            return Err(EngineError::Overflow { client: to_client });
+        }
+    }
+    Ok(effect)
+}
+
+fn check_rules<T>(
+    tx: &Transaction<T>,
+    config: &EngineConfig<T>,
+    client: &Client<T>,
+    ref_tx: Option<&Transaction<T>>,
+    destination: Option<&Client<T>>,
+) -> Result<Effect<T>, EngineError>
+where
+    T: Amount,
+{
+    // If the client is locked, we can't really do anything with them
+    if client.locked {
+        return Err(EngineError::ClientLocked(tx.client));
+    }
+    // Money can only move in the currency it's already in
+    if tx.kind.is_monetary() {
+        let currency = tx.currency.as_ref().unwrap_or(&config.default_currency);
+        check_currency(client, currency)?;
+        destination.map_or(Ok(()), |destination| check_currency(destination, currency))?;
+    }
+    use TransactionKind::*;
+    match &tx.kind {
+        // We might not need to check anything when depositing money, other than the
+        // amount actually being money
+        Deposit { amount } => {
+            check_amount(tx.tx, amount, config)?;
+            check_fresh_id(tx, ref_tx)?;
+            Ok(Effect::Deposit(*amount))
+        }
+        // When withdrawing money, we need to make sure there's enough money to withdraw
+        Withdrawal { amount } => {
+            check_amount(tx.tx, amount, config)?;
+            check_fresh_id(tx, ref_tx)?;
+            debit(
+                tx.client,
+                client.available_in(tx.asset.as_deref()),
+                *amount,
+                config,
+            )?;
+            Ok(Effect::Withdrawal(*amount))
+        }
+        // A fee is checked just like a withdrawal
+        Fee { amount } => {
+            check_amount(tx.tx, amount, config)?;
+            check_fresh_id(tx, ref_tx)?;
+            debit(
+                tx.client,
+                client.available_in(tx.asset.as_deref()),
+                *amount,
+                config,
+            )?;
+            Ok(Effect::Fee(*amount))
+        }
+        // Both ends of a transfer have to be unlocked, and the client sending it has
+        // to be able to afford it. A destination we haven't seen yet is created.
+        Transfer { to_client, amount } => {
+            check_amount(tx.tx, amount, config)?;
+            if *to_client == tx.client {
+                return Err(EngineError::SelfTransfer(tx.tx));
+            }
+            check_fresh_id(tx, ref_tx)?;
+            if destination.is_some_and(|destination| destination.locked) {
+                return Err(EngineError::ClientLocked(*to_client));
+            }
+            debit(
+                tx.client,
+                client.available_in(tx.asset.as_deref()),
+                *amount,
+                config,
+            )?;
+            Ok(Effect::Transfer {
+                to_client: *to_client,
+                amount: *amount,
+            })
+        }
+        // Adjustments are trusted to be right, whichever way they go, but a
+        // negative one is a debit like a withdrawal
+        Adjustment { amount } => {
+            check_fresh_id(tx, ref_tx)?;
+            if *amount < T::default() {
+                let taken = T::default()
+                    .checked_sub(amount)
+                    .ok_or(EngineError::Overflow { client: tx.client })?;
+                debit(
+                    tx.client,
+                    client.available_in(tx.asset.as_deref()),
+                    taken,
+                    config,
+                )?;
+            }
+            Ok(Effect::Adjustment(*amount))
+        }
+        // All other cases reference a transaction, so we might reuse some code
+        Dispute { .. } | PartialDispute { .. } | Resolve | Chargeback | Reversal => {
+            // First we try to find the transaction, and return an error if it doesn't exist
+            let ref_tx = ref_tx.ok_or(EngineError::TransactionNotFound(tx.tx))?;
+            // I don't think a client should be able to deal with other clients'
+            // transactions
+            if tx.client != ref_tx.client {
+                return Err(EngineError::ClientMismatch(tx.tx));
+            }
+            // Also, whatever we do next needs to reference a transaction with an amount
+            let amount = match ref_tx.kind {
+                Deposit { amount } | Withdrawal { amount } => amount,
+                Fee { .. } | Transfer { .. } | Adjustment { .. } => {
+                    return Err(EngineError::NotDisputable(tx.tx))
+                }
+                _ => return Err(EngineError::NoAmount(tx.tx)),
+            };
+            // Deal with a dispute, which can be for less than the whole amount but
+            // never for more
+            let disputed = match tx.kind {
+                Dispute { .. } => Some(amount),
+                PartialDispute { amount: partial } => {
+                    check_amount(tx.tx, &partial, config)?;
+                    if partial > amount {
+                        return Err(EngineError::DisputeExceedsAmount(tx.tx));
+                    }
+                    Some(partial)
+                }
+                _ => None,
+            };
+            if let Some(amount) = disputed {
+                // A dispute timestamped before what it disputes is as early as it gets
+                let expired = config.dispute_window_secs.is_some_and(|window| {
+                    tx.timestamp
+                        .zip(ref_tx.timestamp)
+                        .is_some_and(|(dispute, disputed)| {
+                            dispute.saturating_sub(disputed) > window
+                        })
+                });
+                if expired {
+                    return Err(EngineError::DisputeWindowExpired(tx.tx));
+                }
+                // The status of a stored transaction only ever moves forward:
+                //
+                //   Started --dispute--> Disputed --resolve----> Resolved
+                //                                 --chargeback-> Chargeback
+                //   Started, Resolved --reversal--> Reversed
+                //
+                // so only a transaction that was never disputed can be disputed. Once a
+                // dispute has been settled either way it stays settled.
+                match ref_tx.status {
+                    TransactionStatus::Started => {
+                        let available = client.available_in(ref_tx.asset.as_deref());
+                        debit(tx.client, available, amount, config)?;
+                        Ok(Effect::Dispute(amount))
+                    }
+                    TransactionStatus::Disputed => Err(EngineError::AlreadyDisputed(tx.tx)),
+                    TransactionStatus::Resolved => Err(EngineError::AlreadyResolved(tx.tx)),
+                    TransactionStatus::Chargeback => Err(EngineError::AlreadyChargedBack(tx.tx)),
+                    TransactionStatus::Reversed => Err(EngineError::AlreadyReversed(tx.tx)),
+                }
+            // Deal with a reversal, which undoes the whole transaction. One in dispute
+            // has to be settled first.
+            } else if matches!(tx.kind, Reversal) {
+                match ref_tx.status {
+                    TransactionStatus::Started | TransactionStatus::Resolved => {
+                        // Undoing a deposit takes the money back out
+                        let withdrawal = matches!(ref_tx.kind, Withdrawal { .. });
+                        if !withdrawal {
+                            let available = client.available_in(ref_tx.asset.as_deref());
+                            debit(tx.client, available, amount, config)?;
+                        }
+                        Ok(Effect::Reversal { amount, withdrawal })
+                    }
+                    TransactionStatus::Disputed => Err(EngineError::AlreadyDisputed(tx.tx)),
+                    TransactionStatus::Chargeback => Err(EngineError::AlreadyChargedBack(tx.tx)),
+                    TransactionStatus::Reversed => Err(EngineError::AlreadyReversed(tx.tx)),
+                }
+            // Deal with a resolve
+            } else if matches!(tx.kind, Resolve) {
+                // We can only resolve a transaction in dispute
+                if !matches!(ref_tx.status, TransactionStatus::Disputed) {
+                    return Err(EngineError::NotDisputed(tx.tx, ref_tx.status));
+                }
+                Ok(Effect::Resolve(ref_tx.disputed.unwrap_or(amount)))
+            } else {
+                // We can only charge back a transaction in dispute. A resolved dispute has
+                // already been settled, so it can't be reopened for a chargeback
+                if !matches!(ref_tx.status, TransactionStatus::Disputed) {
+                    return Err(EngineError::NotDisputed(tx.tx, ref_tx.status));
+                }
+                Ok(Effect::Chargeback {
+                    amount: ref_tx.disputed.unwrap_or(amount),
+                    withdrawal: matches!(ref_tx.kind, Withdrawal { .. }),
+                    lock: config.lock_on_chargeback,
+                })
+            }
+        }
+        // Every kind is handled above, but this keeps a kind that isn't from
+        // slipping through as some other one
+        #[allow(unreachable_patterns)]
+        _ => Err(EngineError::UnsupportedKind(tx.tx)),
+    }
+}
+
+impl<T: CheckedAdd + CheckedSub + Copy> Effect<T> {
+    // The available and held funds once this effect is applied to them, or `None`
+    // if either would overflow
+    fn balances(&self, available: T, held: T) -> Option<(T, T)> {
+        match *self {
+            Effect::Deposit(amount) | Effect::Adjustment(amount) => {
+                Some((available.checked_add(&amount)?, held))
+            }
+            Effect::Withdrawal(amount) | Effect::Fee(amount) | Effect::Transfer { amount, .. } => {
+                Some((available.checked_sub(&amount)?, held))
+            }
+            // XXX: Can a client's available amount go under 0?
+            Effect::Dispute(amount) => {
+                Some((available.checked_sub(&amount)?, held.checked_add(&amount)?))
+            }
+            // XXX: Can held go under 0?
+            Effect::Resolve(amount) => {
+                Some((available.checked_add(&amount)?, held.checked_sub(&amount)?))
+            }
+            // Charging back a deposit just drops the held funds, but charging
+            // back a withdrawal reverses it: the held funds are released and the
+            // withdrawn amount is credited back on top
+            Effect::Chargeback {
+                amount, withdrawal, ..
+            } => {
+                let available = if withdrawal {
+                    available.checked_add(&amount)?.checked_add(&amount)?
+                } else {
+                    available
+                };
+                Some((available, held.checked_sub(&amount)?))
+            }
+            Effect::Reversal { amount, withdrawal } => {
+                let available = if withdrawal {
+                    available.checked_add(&amount)?
+                } else {
+                    available.checked_sub(&amount)?
+                };
+                Some((available, held))
+            }
+        }
+    }
+}
+
+impl<T: Amount> Effect<T> {
+    // The events that make up this effect, in the order they're applied
+    fn events(
+        self,
+        tx: &Transaction<T>,
+        asset: Option<String>,
+    ) -> Result<Vec<DomainEvent<T>>, EngineError> {
+        let client = tx.client;
+        use Delta::{Credit, Debit};
+        let available = |delta| DomainEvent::AvailableChanged {
+            client,
+            asset: asset.clone(),
+            delta,
+        };
+        let held = |delta| DomainEvent::HeldChanged {
+            client,
+            asset: asset.clone(),
+            delta,
+        };
+        let status = |status| DomainEvent::TransactionStatusUpdated {
+            tx_id: tx.tx,
+            status,
+        };
+        // A transaction is stored as it came in, without any dispute a copy of it
+        // might carry, e.g. when it's being replayed
+        let stored = || DomainEvent::TransactionStored {
+            tx: Transaction {
+                status: TransactionStatus::Started,
+                disputed: None,
+                dispute_reason: None,
+                ..tx.clone()
+            },
+        };
+        let mut events = vec![DomainEvent::HistoryRecorded {
+            client,
+            tx_id: tx.tx,
+        }];
+        match self {
+            Effect::Deposit(amount) => events.extend([available(Credit(amount)), stored()]),
+            Effect::Withdrawal(amount) => events.extend([available(Debit(amount)), stored()]),
+            Effect::Fee(amount) => events.extend([available(Debit(amount)), stored()]),
+            // A correction can go either way, on amount types that can be negative
+            Effect::Adjustment(amount) => {
+                let delta = if amount < T::default() {
+                    Debit(
+                        T::default()
+                            .checked_sub(&amount)
+                            .ok_or(EngineError::Overflow { client })?,
+                    )
+                } else {
+                    Credit(amount)
+                };
+                events.extend([available(delta), stored()]);
+            }
+            // The other end of a transfer gets credited too
+            Effect::Transfer { to_client, amount } => events.extend([
+                available(Debit(amount)),
+                stored(),
+                DomainEvent::HistoryRecorded {
+                    client: to_client,
+                    tx_id: tx.tx,
+                },
+                DomainEvent::AvailableChanged {
+                    client: to_client,
+                    asset: asset.clone(),
+                    delta: Credit(amount),
+                },
+            ]),
+            Effect::Dispute(amount) => {
+                events.extend([
+                    available(Debit(amount)),
+                    held(Credit(amount)),
+                    status(TransactionStatus::Disputed),
+                    DomainEvent::DisputedAmountRecorded {
+                        tx_id: tx.tx,
+                        amount,
+                    },
+                ]);
+                if let TransactionKind::Dispute {
+                    reason: Some(reason),
+                } = &tx.kind
+                {
+                    events.push(DomainEvent::DisputeReasonRecorded {
+                        tx_id: tx.tx,
+                        reason: reason.clone(),
+                    });
+                }
+            }
+            Effect::Resolve(amount) => events.extend([
+                available(Credit(amount)),
+                held(Debit(amount)),
+                status(TransactionStatus::Resolved),
+            ]),
+            Effect::Chargeback {
+                amount,
+                withdrawal,
+                lock,
+            } => {
+                if withdrawal {
+                    let credit = amount
+                        .checked_add(&amount)
+                        .ok_or(EngineError::Overflow { client })?;
+                    events.push(available(Credit(credit)));
+                }
+                events.push(held(Debit(amount)));
+                if lock {
+                    events.push(DomainEvent::ClientLocked { client });
+                }
+                events.push(status(TransactionStatus::Chargeback));
+            }
+            Effect::Reversal { amount, withdrawal } => {
+                let delta = if withdrawal {
+                    Credit(amount)
+                } else {
+                    Debit(amount)
+                };
+                events.extend([available(delta), status(TransactionStatus::Reversed)]);
+            }
+        }
+        Ok(events)
+    }
+}
+
+// Disputes and such move funds in the asset of the transaction they reference
+fn effect_asset<T>(
+    tx: &Transaction<T>,
+    effect: &Effect<T>,
+    ref_tx: Option<&Transaction<T>>,
+) -> Option<String> {
+    match effect {
+        Effect::Deposit(_)
+        | Effect::Withdrawal(_)
+        | Effect::Fee(_)
+        | Effect::Transfer { .. }
+        | Effect::Adjustment(_) => tx.asset.clone(),
+        _ => ref_tx.and_then(|stored| stored.asset.clone()),
+    }
+}
+
+fn set_status<T>(tx_store: &mut impl TxStore<T>, tx: TxId, status: TransactionStatus) {
+    if let Some(t) = tx_store.get_mut(tx) {
+        t.status = status;
+    }
+}
+
+// Skip duplicate transactions. Transaction ids are meant to be unique across
+// every client and kind, so reusing an id for anything other than the same kind
+// of transaction for the same client gets its own error.
+fn check_fresh_id<T>(
+    tx: &Transaction<T>,
+    ref_tx: Option<&Transaction<T>>,
+) -> Result<(), EngineError> {
+    match ref_tx {
+        None => Ok(()),
+        Some(existing)
+            if existing.client != tx.client
+                || core::mem::discriminant(&existing.kind) != core::mem::discriminant(&tx.kind) =>
+        {
+            Err(EngineError::DuplicateTransactionId {
+                tx: tx.tx,
+                existing_client: existing.client,
+                new_client: tx.client,
+            })
+        }
+        Some(_) => Err(EngineError::DuplicateTransaction(tx.tx)),
+    }
+}
+
+// A client without a currency yet takes whatever comes first
+fn check_currency<T>(client: &Client<T>, currency: &Currency) -> Result<(), EngineError> {
+    match &client.currency {
+        Some(expected) if expected != currency => Err(EngineError::CurrencyMismatch {
+            expected: expected.clone(),
+            found: currency.clone(),
+        }),
+        _ => Ok(()),
+    }
+}
+
+// Whether `amount` can be taken out of `balance`. Withdrawals, fees, transfers
+// and disputes all go through here, so none of them take available funds below
+// zero unless the config allows it.
+fn debit<T: Amount>(
+    client: ClientId,
+    balance: T,
+    amount: T,
+    config: &EngineConfig<T>,
+) -> Result<(), EngineError> {
+    if !config.allow_negative_available && balance < amount {
+        return Err(EngineError::InsufficientFunds(client));
+    }
+    balance
+        .checked_sub(&amount)
+        .map(|_| ())
+        .ok_or(EngineError::Overflow { client })
+}
+
+// Deposits and withdrawals have to move a positive amount, and no more than the
+// configured maximum
+fn check_amount<T: Amount>(
+    tx: TxId,
+    amount: &T,
+    config: &EngineConfig<T>,
+) -> Result<(), EngineError> {
+    if !amount.is_positive() {
+        return Err(EngineError::NonPositiveAmount(tx, format!("{:?}", amount)));
+    }
+    if let Some(max) = &config.max_amount {
+        if amount > max {
+            return Err(EngineError::AmountExceedsLimit(tx, format!("{:?}", amount)));
+        }
+    }
+    Ok(())
+}
+
+// Persistence
+//
+// Snapshots are a stream of length-prefixed records: a little-endian `u32` byte
+// length followed by that many bytes of comma-separated UTF-8. Each client and
+// each transaction is its own record, so neither side ever needs to hold more
+// than one record in memory at a time. The first record says which version of
+// the layout the rest of them are in, and any version but the current one is
+// turned away rather than read as if it were. Free text, like asset names,
+// currency codes and dispute reasons, is escaped so a comma in it can't pass
+// for the end of a field. No record can be longer than
+// `MAX_RECORD_LEN`, so a corrupt length can't make the reader allocate more
+// than that.
+
+#[cfg(feature = "std")]
+const MAX_RECORD_LEN: usize = 16 * 1024 * 1024;
+
+// Bumped whenever a field is added, moved or changes meaning
+#[cfg(feature = "std")]
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+#[cfg(feature = "std")]
+fn write_record<W: Write>(writer: &mut W, record: &str) -> io::Result<()> {
+    let len = u32::try_from(record.len())
+        .ok()
+        .filter(|len| *len as usize <= MAX_RECORD_LEN)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "record is too long"))?;
+    writer.write_all(&len.to_le_bytes())?;
+    writer.write_all(record.as_bytes())
+}
+
+// Returns `false` when the reader ends cleanly between two records
+#[cfg(feature = "std")]
+fn read_record<R: Read>(reader: &mut R, buf: &mut String) -> io::Result<bool> {
+    let mut len = [0; 4];
+    loop {
+        match reader.read(&mut len[..1]) {
+            Ok(0) => return Ok(false),
+            Ok(_) => break,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    reader.read_exact(&mut len[1..])?;
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_RECORD_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "record is too long",
+        ));
+    }
+    // Only as much is allocated as actually turns up
+    let mut bytes = Vec::new();
+    reader.take(len as u64).read_to_end(&mut bytes)?;
+    if bytes.len() < len {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "record is cut short",
+        ));
+    }
+    buf.clear();
+    buf.push_str(
+        std::str::from_utf8(&bytes)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "record is not utf-8"))?,
+    );
+    Ok(true)
+}
+
+#[cfg(feature = "std")]
+fn invalid_data(what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("could not parse {}", what),
+    )
+}
+
+#[cfg(feature = "std")]
+fn parse_field<T: FromStr>(field: Option<&str>, name: &str) -> io::Result<T> {
+    field
+        .ok_or_else(|| invalid_data(name))?
+        .parse::<T>()
+        .map_err(|_| invalid_data(name))
+}
+
+// Commas and percent signs are written as `%2C` and `%25`
+#[cfg(feature = "std")]
+fn escape_field(field: &str) -> Cow<'_, str> {
+    if field.contains([',', '%']) {
+        field.replace('%', "%25").replace(',', "%2C").into()
+    } else {
+        field.into()
+    }
+}
+
+#[cfg(feature = "std")]
+fn parse_escaped<T: FromStr>(field: Option<&str>, name: &str) -> io::Result<T> {
+    let field = field.ok_or_else(|| invalid_data(name))?;
+    let mut unescaped = String::with_capacity(field.len());
+    let mut rest = field;
+    while let Some(at) = rest.find('%') {
+        unescaped.push_str(&rest[..at]);
+        match rest.get(at + 1..at + 3) {
+            Some("25") => unescaped.push('%'),
+            Some("2C") => unescaped.push(','),
+            _ => return Err(invalid_data(name)),
+        }
+        rest = &rest[at + 3..];
+    }
+    unescaped.push_str(rest);
+    parse_field(Some(&unescaped), name)
+}
+
+/// Streams every client and every transaction to `writer`, one record at a time.
+#[cfg(feature = "std")]
+pub fn snapshot_to_writer<T, W>(
+    client_store: &BTreeMap<ClientId, Client<T>>,
+    tx_store: &Map<TxId, Transaction<T>>,
+    mut writer: W,
+) -> io::Result<()>
+where
+    T: Display,
+    W: Write,
+{
+    write_record(&mut writer, &format!("snapshot,{}", SNAPSHOT_VERSION))?;
+    for (id, client) in client_store {
+        let currency = client
+            .currency
+            .as_ref()
+            .map(|c| escape_field(&c.to_string()).into_owned())
+            .unwrap_or_default();
+        let mut record = format!(
+            "client,{},{},{},{},{}",
+            id, client.available, client.held, client.locked, currency
+        );
+        // Each history entry is `id:seq`, or just the id if it was never stamped
+        for (i, tx) in client.history.iter().enumerate() {
+            match client.history_seq.get(i) {
+                Some(seq) => record.push_str(&format!(",{}:{}", tx, seq)),
+                None => record.push_str(&format!(",{}", tx)),
+            }
+        }
+        write_record(&mut writer, &record)?;
+        // Each asset balance follows the client it belongs to
+        for (asset, balance) in &client.assets {
+            let record = format!(
+                "asset,{},{},{},{}",
+                id,
+                escape_field(asset),
+                balance.available,
+                balance.held
+            );
+            write_record(&mut writer, &record)?;
+        }
+    }
+    for (id, tx) in tx_store {
+        let kind = match &tx.kind {
+            TransactionKind::Deposit { amount } => format!("deposit,{}", amount),
+            TransactionKind::Withdrawal { amount } => format!("withdrawal,{}", amount),
+            TransactionKind::Fee { amount } => format!("fee,{}", amount),
+            TransactionKind::Transfer { to_client, amount } => {
+                format!("transfer,{},{}", to_client, amount)
+            }
+            TransactionKind::Adjustment { amount } => format!("adjustment,{}", amount),
+            TransactionKind::Dispute { .. } => "dispute".to_string(),
+            TransactionKind::PartialDispute { amount } => format!("dispute,{}", amount),
+            TransactionKind::Resolve => "resolve".to_string(),
+            TransactionKind::Chargeback => "chargeback".to_string(),
+            TransactionKind::Reversal => "reversal".to_string(),
+            #[cfg(test)]
+            TransactionKind::Unsupported => "unsupported".to_string(),
+        };
+        let source = tx.source.map(|s| s.to_string()).unwrap_or_default();
+        let asset = escape_field(tx.asset.as_deref().unwrap_or_default());
+        let currency = tx
+            .currency
+            .as_ref()
+            .map(|c| escape_field(&c.to_string()).into_owned())
+            .unwrap_or_default();
+        let timestamp = tx.timestamp.map(|t| t.to_string()).unwrap_or_default();
+        let disputed = tx
+            .disputed
+            .as_ref()
+            .map(|d| d.to_string())
+            .unwrap_or_default();
+        let reason = tx
+            .dispute_reason
+            .as_ref()
+            .map(|r| escape_field(&r.to_string()).into_owned())
+            .unwrap_or_default();
+        let record = format!(
+            "tx,{},{},{},{},{},{},{},{},{},{}",
+            id, tx.client, source, asset, currency, timestamp, tx.status, disputed, reason, kind
+        );
+        write_record(&mut writer, &record)?;
+    }
+    writer.flush()
+}
+
+/// Rebuilds both stores from a stream written by [`snapshot_to_writer`].
+#[allow(clippy::type_complexity)]
+#[cfg(feature = "std")]
+pub fn snapshot_from_reader<T, R>(
+    mut reader: R,
+) -> io::Result<(BTreeMap<ClientId, Client<T>>, Map<TxId, Transaction<T>>)>
+where
+    T: FromStr,
+    R: Read,
+{
+    let mut client_store = BTreeMap::new();
+    let mut tx_store = Map::new();
+    let mut record = String::new();
+    if !read_record(&mut reader, &mut record)? {
+        return Err(invalid_data("snapshot version"));
+    }
+    let mut fields = record.split(',');
+    if fields.next() != Some("snapshot") || fields.clone().count() != 1 {
+        return Err(invalid_data("snapshot version"));
+    }
+    let version = parse_field::<u32>(fields.next(), "snapshot version")?;
+    if version != SNAPSHOT_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unsupported snapshot version {}", version),
+        ));
+    }
+    while read_record(&mut reader, &mut record)? {
+        let mut fields = record.split(',');
+        match fields.next() {
+            Some("client") => {
+                let id = parse_field::<ClientId>(fields.next(), "client id")?;
+                let mut client = Client {
+                    available: parse_field::<T>(fields.next(), "available")?,
+                    held: parse_field::<T>(fields.next(), "held")?,
+                    locked: parse_field::<bool>(fields.next(), "locked")?,
+                    currency: match fields.next() {
+                        Some("") => None,
+                        field => Some(parse_escaped::<Currency>(field, "currency")?),
+                    },
+                    history: Vec::new(),
+                    history_seq: Vec::new(),
+                    assets: Map::new(),
+                };
+                // Whatever is left over is the history
+                for field in fields {
+                    let (tx, seq) = match field.split_once(':') {
+                        Some((tx, seq)) => (tx, parse_field::<u64>(Some(seq), "history")?),
+                        None => (field, 0),
+                    };
+                    client
+                        .history
+                        .push(parse_field::<TxId>(Some(tx), "history")?);
+                    client.history_seq.push(seq);
+                }
+                client_store.insert(id, client);
+            }
+            Some("asset") => {
+                let id = parse_field::<ClientId>(fields.next(), "client id")?;
+                let asset = parse_escaped::<String>(fields.next(), "asset")?;
+                let balance = AssetBalance {
+                    available: parse_field::<T>(fields.next(), "available")?,
+                    held: parse_field::<T>(fields.next(), "held")?,
+                };
+                let client = client_store
+                    .get_mut(&id)
+                    .ok_or_else(|| invalid_data("asset client"))?;
+                client.assets.insert(asset, balance);
+            }
+            Some("tx") => {
+                let id = parse_field::<TxId>(fields.next(), "tx id")?;
+                let client = parse_field::<ClientId>(fields.next(), "client id")?;
+                let source = match fields.next() {
+                    Some("") => None,
+                    field => Some(parse_field::<u32>(field, "source")?),
+                };
+                let asset = match fields.next() {
+                    Some("") => None,
+                    field => Some(parse_escaped::<String>(field, "asset")?),
+                };
+                let currency = match fields.next() {
+                    Some("") => None,
+                    field => Some(parse_escaped::<Currency>(field, "currency")?),
+                };
+                let timestamp = match fields.next() {
+                    Some("") => None,
+                    field => Some(parse_field::<u64>(field, "timestamp")?),
+                };
+                let status = match fields.next() {
+                    Some("started") => TransactionStatus::Started,
+                    Some("disputed") => TransactionStatus::Disputed,
+                    Some("resolved") => TransactionStatus::Resolved,
+                    Some("chargeback") => TransactionStatus::Chargeback,
+                    Some("reversed") => TransactionStatus::Reversed,
+                    _ => return Err(invalid_data("status")),
+                };
+                let disputed = match fields.next() {
+                    Some("") => None,
+                    field => Some(parse_field::<T>(field, "disputed amount")?),
+                };
+                let reason = match fields.next() {
+                    Some("") => None,
+                    field => Some(parse_escaped::<DisputeReason>(field, "dispute reason")?),
+                };
+                let kind = match fields.next() {
+                    Some("deposit") => TransactionKind::Deposit {
+                        amount: parse_field::<T>(fields.next(), "amount")?,
+                    },
+                    Some("withdrawal") => TransactionKind::Withdrawal {
+                        amount: parse_field::<T>(fields.next(), "amount")?,
+                    },
+                    Some("fee") => TransactionKind::Fee {
+                        amount: parse_field::<T>(fields.next(), "amount")?,
+                    },
+                    Some("transfer") => TransactionKind::Transfer {
+                        to_client: parse_field::<ClientId>(fields.next(), "to client")?,
+                        amount: parse_field::<T>(fields.next(), "amount")?,
+                    },
+                    Some("adjustment") => TransactionKind::Adjustment {
+                        amount: parse_field::<T>(fields.next(), "amount")?,
+                    },
+                    Some("dispute") => match fields.next() {
+                        None => TransactionKind::Dispute { reason: None },
+                        field => TransactionKind::PartialDispute {
+                            amount: parse_field::<T>(field, "amount")?,
+                        },
+                    },
+                    Some("resolve") => TransactionKind::Resolve,
+                    Some("chargeback") => TransactionKind::Chargeback,
+                    Some("reversal") => TransactionKind::Reversal,
+                    _ => return Err(invalid_data("kind")),
+                };
+                let mut tx = Transaction::new(kind, client, id);
+                tx.source = source;
+                tx.asset = asset;
+                tx.currency = currency;
+                tx.timestamp = timestamp;
+                tx.status = status;
+                tx.disputed = disputed;
+                tx.dispute_reason = reason;
+                tx_store.insert(id, tx);
+            }
+            _ => return Err(invalid_data("record")),
+        }
+    }
+    Ok((client_store, tx_store))
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use rust_decimal::Decimal;
+
+    #[test]
+    fn test_snapshot_round_trip() -> Result<(), Box<dyn std::error::Error>> {
+        let mut client_store = BTreeMap::new();
+        let mut tx_store = HashMap::new();
+        for id in 0..3000u16 {
+            let tx = u32::from(id);
+            let amount = Decimal::new(i64::from(id) + 1, 2);
+            let mut deposit =
+                Transaction::new(TransactionKind::Deposit { amount }, ClientId(id), TxId(tx));
+            // Only some of them come from a known batch
+            if id % 3 == 0 {
+                deposit = deposit.with_source(u32::from(id % 5));
+            }
+            handle(&deposit, &mut client_store, &mut tx_store)?;
+            // And some of them hold another asset on top
+            if id % 4 == 0 {
+                let deposit = Transaction::new(
+                    TransactionKind::Deposit { amount },
+                    ClientId(id),
+                    TxId(tx + 3000),
+                )
+                .with_asset(if id % 8 == 0 { "BTC" } else { "EUR" });
+                handle(&deposit, &mut client_store, &mut tx_store)?;
+            }
+            // Leave a few of them in dispute so statuses and held amounts get
+            // exercised, some of them only partly
+            if id % 7 == 0 {
+                let kind = if id % 4 == 0 {
+                    TransactionKind::Dispute {
+                        reason: Some(DisputeReason::ItemNotReceived),
+                    }
+                } else if id % 2 == 0 {
+                    TransactionKind::Dispute { reason: None }
+                } else {
+                    TransactionKind::PartialDispute {
+                        amount: Decimal::new(1, 2),
+                    }
+                };
+                handle(
+                    &Transaction::new(kind, ClientId(id), TxId(tx)),
+                    &mut client_store,
+                    &mut tx_store,
+                )?;
+            }
+        }
+        let mut buf = Vec::new();
+        snapshot_to_writer(&client_store, &tx_store, &mut buf)?;
+        let (restored_clients, restored_txs) = snapshot_from_reader::<Decimal, _>(buf.as_slice())?;
+
+        assert_eq!(restored_clients.len(), client_store.len());
+        for (id, client) in &client_store {
+            let restored = &restored_clients[id];
+            assert_eq!(restored.available, client.available);
+            assert_eq!(restored.held, client.held);
+            assert_eq!(restored.locked, client.locked);
+            assert_eq!(restored.history, client.history);
+            assert!(restored.stamped_history().eq(client.stamped_history()));
+            assert_eq!(restored.assets, client.assets);
+        }
+        assert_eq!(restored_txs.len(), tx_store.len());
+        for (id, tx) in &tx_store {
+            let restored = &restored_txs[id];
+            assert_eq!(restored, tx);
+            assert_eq!(restored.status, tx.status);
+            assert_eq!(restored.source, tx.source);
+            assert_eq!(restored.asset, tx.asset);
+            assert_eq!(restored.dispute_reason, tx.dispute_reason);
+            assert_eq!(restored.disputed, tx.disputed);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_record_limits() {
+        let mut buf = String::new();
+        // A length that's too long is turned away before anything is read into it
+        let mut huge = u32::MAX.to_le_bytes().to_vec();
+        huge.extend_from_slice(b"client");
+        let err = read_record(&mut huge.as_slice(), &mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        // A record that ends early, or a length that does
+        let mut short = 10u32.to_le_bytes().to_vec();
+        short.extend_from_slice(b"client");
+        let err = read_record(&mut short.as_slice(), &mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        let err = read_record(&mut [1u8, 0].as_slice(), &mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        // A reader that gets interrupted, and hands over one byte at a time
+        struct Trickle<'a> {
+            bytes: &'a [u8],
+            interrupt: bool,
+        }
+        impl Read for Trickle<'_> {
+            fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+                self.interrupt = !self.interrupt;
+                if self.interrupt {
+                    return Err(io::ErrorKind::Interrupted.into());
+                }
+                let n = self.bytes.len().min(out.len()).min(1);
+                out[..n].copy_from_slice(&self.bytes[..n]);
+                self.bytes = &self.bytes[n..];
+                Ok(n)
+            }
+        }
+        let mut record = Vec::new();
+        write_record(&mut record, "client,1").unwrap();
+        let mut reader = Trickle {
+            bytes: &record,
+            interrupt: false,
+        };
+        assert!(read_record(&mut reader, &mut buf).unwrap());
+        assert_eq!(buf, "client,1");
+        assert!(!read_record(&mut reader, &mut buf).unwrap());
+    }
+
+    #[test]
+    fn test_client_total() -> Result<(), EngineError> {
+        use TransactionKind::*;
+        let amount = |n| Decimal::new(n, 0);
+        let mut engine = Engine::new();
+        engine.process(&Transaction::new(
+            Deposit { amount: amount(10) },
+            ClientId(1),
+            TxId(1),
+        ))?;
+        engine.process(&Transaction::new(
+            Deposit { amount: amount(4) },
+            ClientId(1),
+            TxId(2),
+        ))?;
+        let client = &engine.clients()[&ClientId(1)];
+        assert_eq!(client.total(), amount(14));
+        // An open dispute only moves funds from available to held
+        engine.process(&Transaction::new(
+            Dispute { reason: None },
+            ClientId(1),
+            TxId(2),
+        ))?;
+        let client = &engine.clients()[&ClientId(1)];
+        assert_eq!(client.held, amount(4));
+        assert_eq!(client.total(), client.available + client.held);
+        assert_eq!(client.total(), amount(14));
+        assert_eq!(client.checked_total(), Some(amount(14)));
+        assert!(!client.is_locked());
+        Ok(())
+    }
+
+    #[test]
+    fn test_insolvent_clients() -> Result<(), EngineError> {
+        use TransactionKind::*;
+        let amount = |n| Decimal::new(n, 0);
+        let config = EngineConfig::default().allow_negative_available(true);
+        let mut engine = Engine::with_config(config);
+        for (kind, client, tx) in [
+            (Deposit { amount: amount(5) }, 1, 1),
+            (Withdrawal { amount: amount(8) }, 1, 2),
+            (Deposit { amount: amount(5) }, 2, 3),
+            (Deposit { amount: amount(5) }, 3, 4),
+            (Dispute { reason: None }, 3, 4),
+            (Chargeback, 3, 4),
+        ] {
+            engine.process(&Transaction::new(kind, ClientId(client), TxId(tx)))?;
+        }
+        // Overdrawn or locked, but a client with nothing at all is fine
+        assert!(!engine.clients()[&ClientId(1)].is_solvent());
+        assert!(engine.clients()[&ClientId(2)].is_solvent());
+        assert!(Client::<Decimal>::default().is_solvent());
+        assert_eq!(engine.insolvent_clients(), [ClientId(1), ClientId(3)]);
+        let locked: Vec<_> = engine.locked_clients().map(|(id, _)| id).collect();
+        assert_eq!(locked, [ClientId(3)]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_client_lookup() -> Result<(), EngineError> {
+        use TransactionKind::*;
+        let amount = |n| Decimal::new(n, 0);
+        let mut engine = Engine::new();
+        engine.extend_strict([
+            Transaction::new(Deposit { amount: amount(5) }, ClientId(1), TxId(1)),
+            Transaction::new(Withdrawal { amount: amount(2) }, ClientId(1), TxId(2)),
+        ])?;
+        let client = engine.client(ClientId(1)).map(|c| (c.available, c.held));
+        assert_eq!(client, Some((amount(3), amount(0))));
+        assert!(engine.contains_client(ClientId(1)));
+        assert!(engine.client(ClientId(2)).is_none());
+        assert!(!engine.contains_client(ClientId(2)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_export() -> Result<(), Box<dyn std::error::Error>> {
+        use TransactionKind::*;
+        let amount = |n| Decimal::new(n, 1);
+        let mut engine = Engine::new();
+        engine.extend_strict([
+            Transaction::new(Deposit { amount: amount(15) }, ClientId(2), TxId(1)),
+            Transaction::new(Deposit { amount: amount(20) }, ClientId(1), TxId(2)),
+            Transaction::new(Dispute { reason: None }, ClientId(2), TxId(1)),
+            Transaction::new(Chargeback, ClientId(2), TxId(1)),
+        ])?;
+        let mut csv = Vec::new();
+        engine.export_csv(&mut csv)?;
+        assert_eq!(
+            String::from_utf8(csv)?,
+            "client,available,held,total,locked\n1,2.0,0,2.0,false\n2,0.0,0.0,0.0,true\n"
+        );
+        let mut csv = Vec::new();
+        Engine::<Decimal>::new().export_csv(&mut csv)?;
+        assert_eq!(csv, b"client,available,held,total,locked\n");
+
+        #[cfg(feature = "serde")]
+        {
+            let mut json = Vec::new();
+            engine.export_json(&mut json)?;
+            let clients: Vec<serde_json::Value> = serde_json::from_slice(&json)?;
+            assert_eq!(clients.len(), 2);
+            assert_eq!(clients[0]["client_id"], 1);
+            assert_eq!(clients[1]["locked"], true);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_chargeback_ratio() -> Result<(), EngineError> {
+        use TransactionKind::*;
+        let amount = |n| Decimal::new(n, 0);
+        let mut engine = Engine::new();
+        engine.extend_strict([
+            Transaction::new(Deposit { amount: amount(5) }, ClientId(1), TxId(1)),
+            Transaction::new(Deposit { amount: amount(3) }, ClientId(1), TxId(2)),
+            Transaction::new(Dispute { reason: None }, ClientId(1), TxId(1)),
+            Transaction::new(Chargeback, ClientId(1), TxId(1)),
+            Transaction::new(Deposit { amount: amount(4) }, ClientId(2), TxId(3)),
+            Transaction::new(Withdrawal { amount: amount(1) }, ClientId(2), TxId(4)),
+            Transaction::new(Dispute { reason: None }, ClientId(2), TxId(4)),
+            Transaction::new(Resolve, ClientId(2), TxId(4)),
+            // Neither does a reversal, which isn't a chargeback
+            Transaction::new(Reversal, ClientId(2), TxId(4)),
+            // Transfers don't count for either end
+            Transaction::new(
+                Transfer {
+                    to_client: ClientId(3),
+                    amount: amount(1),
+                },
+                ClientId(2),
+                TxId(5),
+            ),
+        ])?;
+        assert_eq!(engine.chargeback_ratio(ClientId(1)), Some(0.5));
+        assert_eq!(engine.chargeback_ratio(ClientId(2)), Some(0.0));
+        assert_eq!(engine.chargeback_ratio(ClientId(3)), None);
+        assert_eq!(engine.chargeback_ratio(ClientId(4)), None);
+        assert_eq!(engine.high_risk_clients(0.25), [ClientId(1)]);
+        assert!(engine.high_risk_clients(0.5).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_dispute_summary() -> Result<(), EngineError> {
+        use TransactionKind::*;
+        let amount = |n| Decimal::new(n, 0);
+        let mut engine = Engine::new();
+        engine.extend_strict([
+            Transaction::new(Deposit { amount: amount(5) }, ClientId(1), TxId(1)),
+            Transaction::new(Deposit { amount: amount(3) }, ClientId(2), TxId(2)),
+            Transaction::new(Deposit { amount: amount(4) }, ClientId(3), TxId(3)),
+            Transaction::new(Deposit { amount: amount(6) }, ClientId(3), TxId(4)),
+        ])?;
+        let summary = engine.dispute_summary()?;
+        assert_eq!(summary, DisputeSummary::default());
+        assert_eq!(summary.chargeback_exposure(), amount(0));
+
+        engine.extend_strict([
+            Transaction::new(Dispute { reason: None }, ClientId(1), TxId(1)),
+            Transaction::new(Chargeback, ClientId(1), TxId(1)),
+            Transaction::new(PartialDispute { amount: amount(2) }, ClientId(2), TxId(2)),
+            Transaction::new(Dispute { reason: None }, ClientId(3), TxId(3)),
+            Transaction::new(Resolve, ClientId(3), TxId(3)),
+            // Neither one is a chargeback, and only the one that was disputed
+            // first counts as a dispute
+            Transaction::new(Reversal, ClientId(3), TxId(3)),
+            Transaction::new(Reversal, ClientId(3), TxId(4)),
+        ])?;
+        let summary = engine.dispute_summary()?;
+        assert_eq!(
+            summary,
+            DisputeSummary {
+                held: amount(2),
+                charged_back: amount(5),
+                disputes: 3,
+                chargebacks: 1,
+            }
+        );
+        assert_eq!(summary.chargeback_exposure(), amount(5));
+        assert_eq!(
+            summary.to_string(),
+            "held 2, charged back 5, disputes 3, chargebacks 1"
+        );
+
+        // Too much held to add up
+        let mut engine = Engine::new();
+        for id in [1, 2] {
+            engine.extend_strict([
+                Transaction::new(
+                    Deposit {
+                        amount: Decimal::MAX,
+                    },
+                    ClientId(id),
+                    TxId(id.into()),
+                ),
+                Transaction::new(Dispute { reason: None }, ClientId(id), TxId(id.into())),
+            ])?;
+        }
+        assert!(matches!(
+            engine.dispute_summary(),
+            Err(EngineError::Overflow { .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_clients() -> Result<(), EngineError> {
+        use TransactionKind::*;
+        let amount = |n| Decimal::new(n, 0);
+        let mut engine = Engine::new();
+        for (kind, client, tx) in [
+            (Deposit { amount: amount(3) }, 2, 2),
+            (Deposit { amount: amount(5) }, 1, 1),
+            (Deposit { amount: amount(4) }, 2, 3),
+            (Dispute { reason: None }, 2, 3),
+            (Deposit { amount: amount(1) }, 3, 4),
+            (Dispute { reason: None }, 3, 4),
+            (Chargeback, 3, 4),
+            (Deposit { amount: amount(2) }, 4, 6),
+            (
+                Transfer {
+                    to_client: ClientId(2),
+                    amount: amount(2),
+                },
+                4,
+                7,
+            ),
+        ] {
+            engine.process(&Transaction::new(kind, ClientId(client), TxId(tx)))?;
+        }
+        let asset = Transaction::new(Deposit { amount: amount(7) }, ClientId(2), TxId(5));
+        engine.process(&asset.with_asset("BTC"))?;
+
+        // Either side being locked stops the merge, and so does a missing one
+        assert_eq!(
+            engine.merge_clients(ClientId(3), ClientId(1)),
+            Err(EngineError::ClientLocked(ClientId(3)))
+        );
+        assert_eq!(
+            engine.merge_clients(ClientId(1), ClientId(3)),
+            Err(EngineError::ClientLocked(ClientId(3)))
+        );
+        assert_eq!(
+            engine.merge_clients(ClientId(9), ClientId(1)),
+            Err(EngineError::ClientNotFound(ClientId(9)))
+        );
+        engine.merge_clients(ClientId(1), ClientId(1))?;
+        assert!(engine.merges().is_empty());
+
+        engine.merge_clients(ClientId(2), ClientId(1))?;
+        assert!(!engine.clients().contains_key(&ClientId(2)));
+        let merged = &engine.clients()[&ClientId(1)];
+        assert_eq!(merged.available, amount(10));
+        assert_eq!(merged.held, amount(4));
+        assert_eq!(merged.available_in(Some("BTC")), amount(7));
+        // In the order it all happened, not one client's after the other's
+        assert_eq!(
+            merged.history,
+            [TxId(2), TxId(1), TxId(3), TxId(3), TxId(7), TxId(5)]
+        );
+        assert!(merged.history_seq.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert!(engine
+            .transactions()
+            .values()
+            .all(|tx| tx.client != ClientId(2)));
+        assert!(matches!(
+            engine.transactions()[&TxId(7)].kind,
+            Transfer {
+                to_client: ClientId(1),
+                ..
+            }
+        ));
+
+        // The dispute moved over with its transaction
+        engine.process(&Transaction::new(Resolve, ClientId(1), TxId(3)))?;
+        assert_eq!(engine.clients()[&ClientId(1)].available, amount(14));
+
+        let merge = &engine.merges()[0];
+        assert_eq!((merge.from, merge.into), (ClientId(2), ClientId(1)));
+        assert_eq!(merge.moved.available, amount(5));
+        assert_eq!(merge.before.available, amount(5));
+        assert_eq!(merge.after.available, amount(10));
+        assert!(merge.timestamp_seq > engine.audit()[10].timestamp_seq);
+        assert!(merge.timestamp_seq < engine.audit()[11].timestamp_seq);
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_clients_transfer_between() -> Result<(), EngineError> {
+        use TransactionKind::*;
+        let amount = |n| Decimal::new(n, 0);
+        let mut engine = Engine::new();
+        engine.extend_strict([
+            Transaction::new(Deposit { amount: amount(10) }, ClientId(1), TxId(1)),
+            Transaction::new(Deposit { amount: amount(5) }, ClientId(2), TxId(2)),
+            Transaction::new(
+                Transfer {
+                    to_client: ClientId(1),
+                    amount: amount(3),
+                },
+                ClientId(2),
+                TxId(3),
+            ),
+        ])?;
+        engine.merge_clients(ClientId(2), ClientId(1))?;
+        let merged = &engine.clients()[&ClientId(1)];
+        assert_eq!(merged.available, amount(15));
+        // The transfer is to itself now, so it's dropped rather than counted twice
+        assert_eq!(merged.history, [TxId(1), TxId(2)]);
+        let statement = engine.statement(ClientId(1)).unwrap();
+        assert_eq!(
+            statement.last().map(|entry| entry.running_balance),
+            Some(amount(15))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_reconcile() -> Result<(), EngineError> {
+        use TransactionKind::*;
+        let amount = |n| Decimal::new(n, 0);
+        let txs = [
+            Transaction::new(Deposit { amount: amount(5) }, ClientId(1), TxId(1)),
+            Transaction::new(Deposit { amount: amount(3) }, ClientId(2), TxId(2)),
+            Transaction::new(Dispute { reason: None }, ClientId(2), TxId(2)),
+        ];
+        let mut engine = Engine::new();
+        engine.extend_strict(txs.clone())?;
+        let mut restored = Engine::restore(engine.snapshot());
+        let report = engine.reconcile(&restored);
+        assert!(report.is_consistent());
+        assert_eq!(report.matching_clients, [ClientId(1), ClientId(2)]);
+
+        // The same balances reached another way still match
+        let mut replayed = Engine::new();
+        replayed.extend_strict(txs)?;
+        assert!(engine.reconcile(&replayed).is_consistent());
+
+        restored.extend_strict([
+            Transaction::new(Withdrawal { amount: amount(1) }, ClientId(1), TxId(3)),
+            Transaction::new(Deposit { amount: amount(1) }, ClientId(3), TxId(4)),
+        ])?;
+        engine.process(&Transaction::new(
+            Deposit { amount: amount(1) },
+            ClientId(4),
+            TxId(5),
+        ))?;
+        let report = engine.reconcile(&restored);
+        assert!(!report.is_consistent());
+        assert_eq!(report.matching_clients, [ClientId(2)]);
+        let (id, ours, theirs) = &report.mismatched_clients[0];
+        assert_eq!(*id, ClientId(1));
+        assert_eq!((ours.available, theirs.available), (amount(5), amount(4)));
+        assert_eq!(report.only_in_self, [ClientId(4)]);
+        assert_eq!(report.only_in_other, [ClientId(3)]);
+        // Only the balances have to match, not the whole client
+        let client = &engine.clients()[&ClientId(2)];
+        let mut other = client.clone();
+        other.history.clear();
+        assert_ne!(&other, client);
+        assert!(other.same_balances(client));
+        Ok(())
+    }
+
+    #[test]
+    fn test_client_snapshot_total() -> Result<(), EngineError> {
+        let client = Client {
+            available: Decimal::new(15, 1),
+            held: Decimal::new(25, 1),
+            locked: true,
+            history: Vec::new(),
+            history_seq: Vec::new(),
+            assets: HashMap::new(),
+            currency: None,
+        };
+        let snapshot = ClientSnapshot::from_client(ClientId(7), &client)?;
+        assert_eq!(snapshot.client_id, ClientId(7));
+        assert_eq!(snapshot.total, Decimal::new(4, 0));
+        assert!(snapshot.locked);
+        assert_eq!(snapshot.to_string(), "7,1.5,2.5,4.0,true");
+
+        let mut engine = Engine::new();
+        for (client, tx) in [(3, 1), (1, 2)] {
+            let deposit = TransactionKind::Deposit {
+                amount: Decimal::ONE,
+            };
+            engine.process(&Transaction::new(deposit, ClientId(client), TxId(tx)))?;
+        }
+        let ids: Vec<_> = engine
+            .client_snapshots()?
+            .iter()
+            .map(|s| s.client_id)
+            .collect();
+        assert_eq!(ids, [ClientId(1), ClientId(3)]);
+        Ok(())
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn test_string_record_round_trip(
+            deposit: bool,
+            client: u16,
+            tx: u32,
+            num in 0..i64::MAX,
+            scale in 0..=8u32,
+        ) {
+            let amount = Decimal::new(num, scale);
+            let kind = if deposit {
+                TransactionKind::Deposit { amount }
+            } else {
+                TransactionKind::Withdrawal { amount }
+            };
+            let original = Transaction::new(kind, ClientId(client), TxId(tx));
+            let record = StringRecord::from(&original);
+            let parsed = Transaction::<Decimal>::try_from(record).unwrap();
+            proptest::prop_assert_eq!(parsed, original);
+        }
+    }
+
+    #[test]
+    fn test_string_record_with_bom_and_crlf() {
+        let record = StringRecord::from(vec!["\u{feff}deposit", "1", "2", "1.5\r"]);
+        let tx = Transaction::<Decimal>::try_from(record).unwrap();
+        assert_eq!(
+            tx.kind,
+            TransactionKind::Deposit {
+                amount: Decimal::new(15, 1)
+            }
+        );
+    }
+
+    #[test]
+    fn test_from_fields() -> Result<(), Cow<'static, str>> {
+        use TransactionKind::*;
+        let fields: &[&str] = &[" deposit", "1 ", "2", "1.5", "BTC", "eur"];
+        let tx = Transaction::<Decimal>::try_from(fields)?;
+        assert_eq!(
+            tx,
+            Transaction::new(
+                Deposit {
+                    amount: Decimal::new(15, 1)
+                },
+                ClientId(1),
+                TxId(2)
+            )
+            .with_asset("BTC")
+            .with_currency(Currency::Eur)
+        );
+
+        // Missing fields are only a problem for the kinds that need them
+        let fields: &[&str] = &["resolve", "1", "2"];
+        let tx = Transaction::<Decimal>::try_from(fields)?;
+        assert_eq!(tx.kind, Resolve);
+        let fields: &[&str] = &["deposit", "1", "2"];
+        assert!(Transaction::<Decimal>::try_from(fields).is_err());
+        let fields: &[&str] = &["deposit", "one", "2", "1.0"];
+        assert!(Transaction::<Decimal>::try_from(fields).is_err());
+
+        // The timestamp comes after the asset and currency, even when they're empty
+        let fields: &[&str] = &["deposit", "1", "3", "1.0", "", "", "1700000000"];
+        let tx = Transaction::<Decimal>::try_from(fields)?;
+        assert_eq!(tx.timestamp, Some(1_700_000_000));
+        assert_eq!(StringRecord::from(&tx), StringRecord::from(fields.to_vec()));
+        let fields: &[&str] = &["deposit", "1", "3", "1.0", "", "", "yesterday"];
+        assert!(Transaction::<Decimal>::try_from(fields).is_err());
+
+        // Same as a record with the same fields, in any layout
+        let layout = ColumnLayout {
+            kind: 3,
+            client: 2,
+            tx: 1,
+            amount: 0,
+            ..ColumnLayout::default()
+        };
+        let fields = ["2.0", "7", "4", "withdrawal"];
+        let record = StringRecord::from(fields.to_vec());
+        assert_eq!(
+            Transaction::<Decimal>::from_fields(&fields, &layout)?,
+            Transaction::<Decimal>::from_record(&record, &layout)?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_padded_fields() -> Result<(), Box<dyn std::error::Error>> {
+        use TransactionKind::*;
+        let data = "  type , client , tx , amount , asset
+  deposit ,  1 , 1 ,  2.5 ,
+ Withdrawal , 1 , 2 , 1.0 ,  USD
+   dispute , 1 , 1 , ,
+ transfer  , 1 , 3 , 0.5 , 2
+";
+        let mut rdr = csv::ReaderBuilder::new()
+            .trim(csv::Trim::None)
+            .from_reader(data.as_bytes());
+        let txs = rdr
+            .records()
+            .map(|record| Ok(Transaction::<Decimal>::try_from(record?)?))
+            .collect::<Result<Vec<_>, Box<dyn std::error::Error>>>()?;
+        let kinds: Vec<_> = txs.iter().map(|tx| tx.kind.clone()).collect();
+        assert_eq!(
+            kinds,
+            [
+                Deposit {
+                    amount: Decimal::new(25, 1)
+                },
+                Withdrawal {
+                    amount: Decimal::ONE
+                },
+                Dispute { reason: None },
+                Transfer {
+                    amount: Decimal::new(5, 1),
+                    to_client: ClientId(2)
+                },
+            ]
+        );
+        assert!(txs.iter().all(|tx| tx.client == ClientId(1)));
+        assert_eq!(txs[1].asset.as_deref(), Some("USD"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_dispute_reason() -> Result<(), Box<dyn std::error::Error>> {
+        let mut engine = Engine::<Decimal>::new();
+        for row in [
+            vec!["deposit", "1", "1", "10"],
+            vec!["dispute", "1", "1", "", "Unauthorized_Charge"],
+        ] {
+            engine.process(&Transaction::try_from(StringRecord::from(row))?)?;
+        }
+        let reason = Some(DisputeReason::UnauthorizedCharge);
+        assert_eq!(
+            engine.transactions()[&TxId(1)].dispute_reason(),
+            reason.as_ref()
+        );
+        let kinds: Vec<_> = engine
+            .statement(ClientId(1))
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.kind)
+            .collect();
+        assert_eq!(kinds[1], TransactionKind::Dispute { reason });
+        // Without the column there's no reason, and one that isn't known is kept
+        // as it was
+        let record = StringRecord::from(vec!["dispute", "1", "2", "0"]);
+        assert_eq!(
+            Transaction::<Decimal>::try_from(record)?.kind,
+            TransactionKind::Dispute { reason: None }
+        );
+        let record = StringRecord::from(vec!["dispute", "1", "2", "", "wrong size"]);
+        let tx = Transaction::<Decimal>::try_from(record)?;
+        assert_eq!(
+            tx.kind,
+            TransactionKind::Dispute {
+                reason: Some(DisputeReason::Other("wrong size".to_string()))
+            }
+        );
+        assert_eq!(tx.asset, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_currency() -> Result<(), Box<dyn std::error::Error>> {
+        use TransactionKind::*;
+        let amount = |n| Decimal::new(n, 0);
+        let mut engine =
+            Engine::with_config(EngineConfig::default().default_currency(Currency::Eur));
+        engine.extend_strict([
+            // The first one decides, whether it says so or not
+            Transaction::new(Deposit { amount: amount(5) }, ClientId(1), TxId(1)),
+            Transaction::new(Withdrawal { amount: amount(1) }, ClientId(1), TxId(2))
+                .with_currency(Currency::Eur),
+            Transaction::new(Deposit { amount: amount(5) }, ClientId(2), TxId(3))
+                .with_currency(Currency::Gbp),
+            // Disputes don't move money of their own, so they can't mismatch
+            Transaction::new(Dispute { reason: None }, ClientId(2), TxId(3))
+                .with_currency(Currency::Usd),
+            Transaction::new(Resolve, ClientId(2), TxId(3)),
+        ])?;
+        assert_eq!(engine.clients()[&ClientId(1)].currency, Some(Currency::Eur));
+        assert_eq!(engine.clients()[&ClientId(2)].currency, Some(Currency::Gbp));
+        let mismatch = EngineError::CurrencyMismatch {
+            expected: Currency::Eur,
+            found: Currency::Usd,
+        };
+        let usd = Transaction::new(Deposit { amount: amount(1) }, ClientId(1), TxId(4))
+            .with_currency(Currency::Usd);
+        assert_eq!(engine.process(&usd), Err(mismatch));
+        // Both ends of a transfer have to match
+        let transfer = Transaction::new(
+            Transfer {
+                to_client: ClientId(2),
+                amount: amount(1),
+            },
+            ClientId(1),
+            TxId(5),
+        );
+        assert_eq!(
+            engine.process(&transfer),
+            Err(EngineError::CurrencyMismatch {
+                expected: Currency::Gbp,
+                found: Currency::Eur,
+            })
+        );
+        assert_eq!(
+            engine.merge_clients(ClientId(2), ClientId(1)),
+            Err(EngineError::CurrencyMismatch {
+                expected: Currency::Eur,
+                found: Currency::Gbp,
+            })
+        );
+        // A new client takes the sender's currency
+        let transfer = Transaction::new(
+            Transfer {
+                to_client: ClientId(3),
+                amount: amount(1),
+            },
+            ClientId(1),
+            TxId(5),
+        );
+        engine.process(&transfer)?;
+        assert_eq!(engine.clients()[&ClientId(3)].currency, Some(Currency::Eur));
+
+        // The currency column comes after the asset one
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .trim(csv::Trim::All)
+            .from_reader("deposit, 4, 6, 1.0, , gbp\ndeposit, 4, 7, 1.0\n".as_bytes());
+        let mut records = reader.records();
+        let gbp = Transaction::<Decimal>::try_from(records.next().unwrap()?)?;
+        assert_eq!(gbp.currency, Some(Currency::Gbp));
+        assert_eq!(gbp.asset, None);
+        assert_eq!(
+            StringRecord::from(&gbp),
+            csv::StringRecord::from(vec!["deposit", "4", "6", "1.0", "", "GBP"])
+        );
+        let plain = Transaction::<Decimal>::try_from(records.next().unwrap()?)?;
+        assert_eq!(plain.currency, None);
+        engine.process(&gbp)?;
+        assert_eq!(
+            engine.process(&plain),
+            Err(EngineError::CurrencyMismatch {
+                expected: Currency::Gbp,
+                found: Currency::Eur,
+            })
+        );
+
+        // And survives a snapshot
+        let mut buf = Vec::new();
+        snapshot_to_writer(engine.clients(), engine.transactions(), &mut buf)?;
+        let (clients, txs) = snapshot_from_reader::<Decimal, _>(buf.as_slice())?;
+        assert_eq!(clients[&ClientId(4)].currency, Some(Currency::Gbp));
+        assert_eq!(txs[&TxId(6)].currency, Some(Currency::Gbp));
+        assert_eq!(txs[&TxId(1)].currency, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_kind() {
+        use TransactionKind::*;
+        let amount = |n| Decimal::new(n, 1);
+        assert_eq!(
+            parse_kind("Deposit", Some("1.5"), None, None),
+            Ok(Deposit { amount: amount(15) })
+        );
+        assert_eq!(
+            parse_kind("transfer", Some("0.5"), Some("7"), None),
+            Ok(Transfer {
+                amount: amount(5),
+                to_client: ClientId(7)
+            })
+        );
+        assert_eq!(
+            parse_kind::<Decimal>("CHARGEBACK", None, None, None),
+            Ok(Chargeback)
+        );
+        assert_eq!(
+            parse_kind::<Decimal>("dispute", Some(""), None, None),
+            Ok(Dispute { reason: None })
+        );
+        assert_eq!(
+            parse_kind("dispute", Some("0.1"), None, None),
+            Ok(PartialDispute { amount: amount(1) })
+        );
+        assert_eq!(
+            parse_kind::<Decimal>("withdrawal", None, None, None),
+            Err("could not find amount".into())
+        );
+        assert_eq!(
+            parse_kind::<Decimal>("deposit", Some("lots"), None, None),
+            Err("could not parse amount".into())
+        );
+        assert_eq!(
+            parse_kind::<Decimal>("transfer", Some("1"), None, None),
+            Err("could not find to_client".into())
+        );
+        assert_eq!(
+            parse_kind::<Decimal>("refund", Some("1"), None, None),
+            Err(r#"found unknown transaction type "refund""#.into())
+        );
+    }
+
+    #[test]
+    fn test_id_newtypes() -> Result<(), EngineError> {
+        use TransactionKind::*;
+        assert_eq!("7".parse::<ClientId>(), Ok(ClientId(7)));
+        assert_eq!(TxId::from(9).to_string(), "9");
+        assert!("-1".parse::<TxId>().is_err());
+        let mut engine = Engine::new();
+        let deposit = Deposit {
+            amount: Decimal::new(5, 0),
+        };
+        engine.process(&Transaction::new(deposit.clone(), ClientId(2), TxId(1)))?;
+        engine.process(&Transaction::new(deposit, ClientId(1), TxId(2)))?;
+        // Clients are keyed by client id and transactions by tx id, whatever the
+        // numbers happen to be
+        let clients: Vec<_> = engine.clients().keys().copied().collect();
+        assert_eq!(clients, [ClientId(1), ClientId(2)]);
+        assert_eq!(engine.transactions()[&TxId(1)].client, ClientId(2));
+        assert_eq!(engine.transactions()[&TxId(2)].client, ClientId(1));
+        assert_eq!(engine.clients()[&ClientId(2)].history, [TxId(1)]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_string_record_without_amount() {
+        let tx = Transaction::<Decimal>::new(TransactionKind::Chargeback, ClientId(4), TxId(20));
+        let record = StringRecord::from(&tx);
+        assert_eq!(
+            record,
+            StringRecord::from(vec!["chargeback", "4", "20", ""])
+        );
+    }
+
+    #[test]
+    fn test_held_sources() -> Result<(), EngineError> {
+        let mut client_store = BTreeMap::new();
+        let mut tx_store = HashMap::new();
+        let txs = [
+            (
+                TransactionKind::Deposit {
+                    amount: Decimal::new(10, 0),
+                },
+                1,
+                1,
+            ),
+            (
+                TransactionKind::Deposit {
+                    amount: Decimal::new(25, 1),
+                },
+                1,
+                2,
+            ),
+            (
+                TransactionKind::Deposit {
+                    amount: Decimal::new(4, 0),
+                },
+                1,
+                3,
+            ),
+            (
+                TransactionKind::Deposit {
+                    amount: Decimal::new(7, 0),
+                },
+                2,
+                4,
+            ),
+            (TransactionKind::Dispute { reason: None }, 1, 3),
+            (TransactionKind::Dispute { reason: None }, 1, 1),
+            (TransactionKind::Dispute { reason: None }, 2, 4),
+        ];
+        for (kind, client, tx) in txs {
+            handle(
+                &Transaction::new(kind, ClientId(client), TxId(tx)),
+                &mut client_store,
+                &mut tx_store,
+            )?;
+        }
+        let sources = held_sources(ClientId(1), &tx_store);
+        assert_eq!(
+            sources,
+            [
+                (TxId(1), Decimal::new(10, 0)),
+                (TxId(3), Decimal::new(4, 0))
+            ]
+        );
+        let held: Decimal = sources.iter().map(|(_, amount)| *amount).sum();
+        assert_eq!(held, client_store[&ClientId(1)].held);
+        assert!(held_sources(ClientId(3), &tx_store).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_chargeback_requires_dispute() -> Result<(), EngineError> {
+        let mut client_store = BTreeMap::new();
+        let mut tx_store = HashMap::new();
+        let deposit = TransactionKind::Deposit {
+            amount: Decimal::new(5, 0),
+        };
+        for (kind, tx) in [
+            (deposit.clone(), 1),
+            (TransactionKind::Dispute { reason: None }, 1),
+            (TransactionKind::Resolve, 1),
+            (deposit, 2),
+            (TransactionKind::Dispute { reason: None }, 2),
+        ] {
+            handle(
+                &Transaction::new(kind, ClientId(1), TxId(tx)),
+                &mut client_store,
+                &mut tx_store,
+            )?;
+        }
+        // dispute -> resolve -> chargeback is rejected
+        let result = handle(
+            &Transaction::new(TransactionKind::Chargeback, ClientId(1), TxId(1)),
+            &mut client_store,
+            &mut tx_store,
+        );
+        assert_eq!(
+            result,
+            Err(EngineError::NotDisputed(
+                TxId(1),
+                TransactionStatus::Resolved
+            ))
+        );
+        assert!(!client_store[&ClientId(1)].locked);
+        // and so is resolving it again, which would release the funds twice
+        let result = handle(
+            &Transaction::new(TransactionKind::Resolve, ClientId(1), TxId(1)),
+            &mut client_store,
+            &mut tx_store,
+        );
+        assert_eq!(
+            result,
+            Err(EngineError::NotDisputed(
+                TxId(1),
+                TransactionStatus::Resolved
+            ))
+        );
+        assert_eq!(client_store[&ClientId(1)].held, Decimal::new(5, 0));
+        // dispute -> chargeback goes through
+        handle(
+            &Transaction::new(TransactionKind::Chargeback, ClientId(1), TxId(2)),
+            &mut client_store,
+            &mut tx_store,
+        )?;
+        assert!(client_store[&ClientId(1)].locked);
+        assert_eq!(client_store[&ClientId(1)].available, Decimal::new(5, 0));
+        assert_eq!(client_store[&ClientId(1)].held, Decimal::ZERO);
+        Ok(())
+    }
+
+    #[test]
+    fn test_lock_on_chargeback() -> Result<(), Box<dyn std::error::Error>> {
+        let amount = |n| Decimal::new(n, 0);
+        let run = |lock| {
+            let mut engine = Engine::with_config(EngineConfig::default().lock_on_chargeback(lock));
+            let client = |builder: TransactionBuilder<Decimal>, tx| {
+                builder.client(ClientId(1)).tx_id(TxId(tx)).build()
+            };
+            engine.extend_strict([
+                client(TransactionBuilder::deposit(5), 1)?,
+                client(TransactionBuilder::deposit(3), 2)?,
+                client(TransactionBuilder::dispute(), 1)?,
+                client(TransactionBuilder::chargeback(), 1)?,
+            ])?;
+            Ok::<_, Box<dyn std::error::Error>>(engine)
+        };
+        let locked = run(true)?;
+        let flagged = run(false)?;
+        assert!(locked.clients()[&ClientId(1)].locked);
+        assert!(!flagged.clients()[&ClientId(1)].locked);
+        for engine in [&locked, &flagged] {
+            let client = &engine.clients()[&ClientId(1)];
+            assert_eq!((client.available, client.held), (amount(3), amount(0)));
+            assert_eq!(
+                engine.transactions()[&TxId(1)].status(),
+                TransactionStatus::Chargeback
+            );
+        }
+        // A client left unlocked can keep going
+        let mut flagged = flagged;
+        let withdrawal = TransactionBuilder::withdrawal(1)
+            .client(ClientId(1))
+            .tx_id(TxId(3));
+        flagged.process(&withdrawal.build()?)?;
+        assert_eq!(flagged.clients()[&ClientId(1)].available, amount(2));
+        Ok(())
+    }
+
+    #[test]
+    fn test_dispute_window() -> Result<(), Box<dyn std::error::Error>> {
+        let amount = |n| Decimal::new(n, 0);
+        let day = 24 * 60 * 60;
+        let mut engine = Engine::with_config(EngineConfig::default().dispute_window_secs(day));
+        let deposit = |n, tx| {
+            TransactionBuilder::<Decimal>::deposit(amount(n))
+                .client(ClientId(1))
+                .tx_id(TxId(tx))
+        };
+        engine.extend_strict([
+            deposit(5, 1).with_timestamp(0).build()?,
+            deposit(3, 2).with_timestamp(0).build()?,
+            deposit(2, 3).build()?,
+        ])?;
+        // Right at the end of the window is still in it
+        let dispute = |tx| {
+            TransactionBuilder::<Decimal>::dispute()
+                .client(ClientId(1))
+                .tx_id(TxId(tx))
+        };
+        engine.process(&dispute(1).with_timestamp(day).build()?)?;
+        assert_eq!(
+            engine.process(&dispute(2).with_timestamp(day + 1).build()?),
+            Err(EngineError::DisputeWindowExpired(TxId(2)))
+        );
+        // Without a timestamp on either side there's nothing to go by
+        engine.process(&dispute(3).with_timestamp(10 * day).build()?)?;
+        let client = &engine.clients()[&ClientId(1)];
+        assert_eq!((client.available, client.held), (amount(3), amount(7)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_unlock_client() -> Result<(), EngineError> {
+        use TransactionKind::*;
+        let amount = |n| Decimal::new(n, 0);
+        let mut engine = Engine::new();
+        engine.extend_strict([
+            Transaction::new(Deposit { amount: amount(5) }, ClientId(1), TxId(1)),
+            Transaction::new(Deposit { amount: amount(3) }, ClientId(1), TxId(2)),
+            Transaction::new(Dispute { reason: None }, ClientId(1), TxId(1)),
+            Transaction::new(Chargeback, ClientId(1), TxId(1)),
+        ])?;
+        let deposit = Transaction::new(Deposit { amount: amount(2) }, ClientId(1), TxId(3));
+        assert_eq!(
+            engine.process(&deposit),
+            Err(EngineError::ClientLocked(ClientId(1)))
+        );
+        assert_eq!(
+            engine.unlock_client(ClientId(2)),
+            Err(EngineError::ClientNotFound(ClientId(2)))
+        );
+
+        engine.unlock_client(ClientId(1))?;
+        engine.unlock_client(ClientId(1))?;
+        assert_eq!(engine.process(&deposit), Ok(Outcome::Applied));
+        let client = &engine.clients()[&ClientId(1)];
+        assert!(!client.locked);
+        assert_eq!(client.available, amount(5));
+
+        let [unlock] = engine.unlocks() else {
+            panic!("expected a single unlock, got {:?}", engine.unlocks());
+        };
+        assert_eq!(unlock.client, ClientId(1));
+        assert!(unlock.before.locked);
+        assert!(unlock.timestamp_seq < engine.audit().last().unwrap().timestamp_seq);
+        Ok(())
+    }
+
+    #[test]
+    fn test_dispute_after_resolve() -> Result<(), EngineError> {
+        let mut client_store = BTreeMap::new();
+        let mut tx_store = HashMap::new();
+        let deposit = TransactionKind::Deposit {
+            amount: Decimal::new(5, 0),
+        };
+        for kind in [
+            deposit,
+            TransactionKind::Dispute { reason: None },
+            TransactionKind::Resolve,
+        ] {
+            handle(
+                &Transaction::new(kind, ClientId(1), TxId(1)),
+                &mut client_store,
+                &mut tx_store,
+            )?;
+        }
+        let dispute = Transaction::new(
+            TransactionKind::Dispute { reason: None },
+            ClientId(1),
+            TxId(1),
+        );
+        assert_eq!(
+            handle(&dispute, &mut client_store, &mut tx_store),
+            Err(EngineError::AlreadyResolved(TxId(1)))
+        );
+        assert_eq!(client_store[&ClientId(1)].available, Decimal::new(5, 0));
+        assert_eq!(client_store[&ClientId(1)].held, Decimal::ZERO);
+        Ok(())
+    }
+
+    #[test]
+    fn test_dispute_after_chargeback() -> Result<(), EngineError> {
+        let mut client_store = BTreeMap::new();
+        let mut tx_store = HashMap::new();
+        let deposit = TransactionKind::Deposit {
+            amount: Decimal::new(5, 0),
+        };
+        for kind in [
+            deposit,
+            TransactionKind::Dispute { reason: None },
+            TransactionKind::Chargeback,
+        ] {
+            handle(
+                &Transaction::new(kind, ClientId(1), TxId(1)),
+                &mut client_store,
+                &mut tx_store,
+            )?;
+        }
+        // A chargeback locks the client, so that's what gets reported first
+        let dispute = Transaction::new(
+            TransactionKind::Dispute { reason: None },
+            ClientId(1),
+            TxId(1),
+        );
+        assert_eq!(
+            handle(&dispute, &mut client_store, &mut tx_store),
+            Err(EngineError::ClientLocked(ClientId(1)))
+        );
+        // Even for a client that isn't locked, a charged back transaction stays settled
+        client_store.get_mut(&ClientId(1)).unwrap().locked = false;
+        assert_eq!(
+            handle(&dispute, &mut client_store, &mut tx_store),
+            Err(EngineError::AlreadyChargedBack(TxId(1)))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_non_positive_amounts() -> Result<(), EngineError> {
+        let mut client_store = BTreeMap::new();
+        let mut tx_store = HashMap::new();
+        let deposit = TransactionKind::Deposit {
+            amount: Decimal::new(5, 0),
+        };
+        handle(
+            &Transaction::new(deposit, ClientId(1), TxId(1)),
+            &mut client_store,
+            &mut tx_store,
+        )?;
+        let zero_deposit = TransactionKind::Deposit {
+            amount: Decimal::ZERO,
+        };
+        assert_eq!(
+            handle(
+                &Transaction::new(zero_deposit, ClientId(1), TxId(2)),
+                &mut client_store,
+                &mut tx_store
+            ),
+            Err(EngineError::NonPositiveAmount(TxId(2), "0".to_string()))
+        );
+        let negative_withdrawal = TransactionKind::Withdrawal {
+            amount: Decimal::new(-1, 0),
+        };
+        assert_eq!(
+            handle(
+                &Transaction::new(negative_withdrawal, ClientId(1), TxId(3)),
+                &mut client_store,
+                &mut tx_store
+            ),
+            Err(EngineError::NonPositiveAmount(TxId(3), "-1".to_string()))
+        );
+        assert_eq!(client_store[&ClientId(1)].available, Decimal::new(5, 0));
+        assert_eq!(tx_store.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_engine_config() -> Result<(), EngineError> {
+        let deposit = |amount| TransactionKind::Deposit {
+            amount: Decimal::new(amount, 0),
+        };
+        let withdrawal = TransactionKind::Withdrawal {
+            amount: Decimal::new(8, 0),
+        };
+
+        let mut engine = Engine::new();
+        engine.process(&Transaction::new(deposit(5), ClientId(1), TxId(1)))?;
+        assert_eq!(
+            engine.process(&Transaction::new(withdrawal.clone(), ClientId(1), TxId(2))),
+            Err(EngineError::InsufficientFunds(ClientId(1)))
+        );
+
+        let config = EngineConfig::default()
+            .allow_negative_available(true)
+            .max_amount(Decimal::new(10, 0))
+            .initial_client_capacity(16);
+        let mut engine = Engine::with_config(config);
+        engine.process(&Transaction::new(deposit(5), ClientId(1), TxId(1)))?;
+        engine.process(&Transaction::new(withdrawal, ClientId(1), TxId(2)))?;
+        assert_eq!(
+            engine.clients()[&ClientId(1)].available,
+            Decimal::new(-3, 0)
+        );
+        assert_eq!(
+            engine.process(&Transaction::new(deposit(11), ClientId(1), TxId(3))),
+            Err(EngineError::AmountExceedsLimit(TxId(3), "11".to_string()))
+        );
+        assert_eq!(engine.transactions().len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_amount_boundary() -> Result<(), EngineError> {
+        use TransactionKind::*;
+        let limit = Decimal::new(1_000_000, 2);
+        let cent = Decimal::new(1, 2);
+        let mut engine = Engine::with_config(EngineConfig::default().max_amount(limit));
+        // Right at the limit is fine, a cent over isn't
+        engine.process(&Transaction::new(
+            Deposit { amount: limit },
+            ClientId(1),
+            TxId(1),
+        ))?;
+        assert_eq!(
+            engine.process(&Transaction::new(
+                Withdrawal {
+                    amount: limit + cent
+                },
+                ClientId(1),
+                TxId(2),
+            )),
+            Err(EngineError::AmountExceedsLimit(
+                TxId(2),
+                "10000.01".to_string()
+            ))
+        );
+        // A duplicate over the limit is rejected for its amount first
+        assert_eq!(
+            engine.process(&Transaction::new(
+                Deposit {
+                    amount: limit + cent
+                },
+                ClientId(1),
+                TxId(1),
+            )),
+            Err(EngineError::AmountExceedsLimit(
+                TxId(1),
+                "10000.01".to_string()
+            ))
+        );
+        // And so is a transfer
+        assert_eq!(
+            engine.process(&Transaction::new(
+                Transfer {
+                    to_client: ClientId(2),
+                    amount: limit + cent
+                },
+                ClientId(1),
+                TxId(3),
+            )),
+            Err(EngineError::AmountExceedsLimit(
+                TxId(3),
+                "10000.01".to_string()
+            ))
+        );
+        engine.process(&Transaction::new(
+            Withdrawal { amount: limit },
+            ClientId(1),
+            TxId(4),
+        ))?;
+        assert_eq!(engine.clients()[&ClientId(1)].available, Decimal::ZERO);
+        Ok(())
+    }
+
+    // A stable rendering of everything an engine holds, statuses included
+    fn dump(engine: &Engine<Decimal>) -> String {
+        let mut clients: Vec<_> = engine.clients().iter().collect();
+        clients.sort_unstable_by_key(|(id, _)| **id);
+        let mut txs: Vec<_> = engine.transactions().iter().collect();
+        txs.sort_unstable_by_key(|(id, _)| **id);
+        format!("{:?}\n{:?}", clients, txs)
+    }
+
+    #[test]
+    fn test_errors_leave_engine_unchanged() -> Result<(), EngineError> {
+        use TransactionKind::*;
+        let amount = |n| Decimal::new(n, 0);
+        let config = EngineConfig::default().max_amount(amount(100));
+        let mut engine = Engine::with_config(config);
+        let setup = [
+            (Deposit { amount: amount(10) }, 1, 1),
+            (Deposit { amount: amount(10) }, 1, 2),
+            (Deposit { amount: amount(10) }, 1, 3),
+            (Dispute { reason: None }, 1, 2),
+            (Dispute { reason: None }, 1, 3),
+            (Resolve, 1, 3),
+            (Deposit { amount: amount(10) }, 2, 4),
+            (Dispute { reason: None }, 2, 4),
+            (Chargeback, 2, 4),
+        ];
+        for (kind, client, tx) in setup {
+            engine.process(&Transaction::new(kind, ClientId(client), TxId(tx)))?;
+        }
+        let failing = [
+            (
+                Deposit { amount: amount(1) },
+                2,
+                5,
+                EngineError::ClientLocked(ClientId(2)),
+            ),
+            (
+                Deposit { amount: amount(1) },
+                1,
+                1,
+                EngineError::DuplicateTransaction(TxId(1)),
+            ),
+            (
+                Withdrawal { amount: amount(1) },
+                3,
+                6,
+                EngineError::InsufficientFunds(ClientId(3)),
+            ),
+            (
+                Withdrawal { amount: amount(50) },
+                1,
+                6,
+                EngineError::InsufficientFunds(ClientId(1)),
+            ),
+            (
+                Deposit { amount: amount(0) },
+                3,
+                6,
+                EngineError::NonPositiveAmount(TxId(6), "0".to_string()),
+            ),
+            (
+                Deposit {
+                    amount: amount(101),
+                },
+                3,
+                6,
+                EngineError::AmountExceedsLimit(TxId(6), "101".to_string()),
+            ),
+            (
+                Dispute { reason: None },
+                3,
+                9,
+                EngineError::TransactionNotFound(TxId(9)),
+            ),
+            (
+                Dispute { reason: None },
+                3,
+                1,
+                EngineError::ClientMismatch(TxId(1)),
+            ),
+            (
+                Dispute { reason: None },
+                1,
+                2,
+                EngineError::AlreadyDisputed(TxId(2)),
+            ),
+            (
+                Dispute { reason: None },
+                1,
+                3,
+                EngineError::AlreadyResolved(TxId(3)),
+            ),
+            (
+                Resolve,
+                1,
+                1,
+                EngineError::NotDisputed(TxId(1), TransactionStatus::Started),
+            ),
+            (
+                Chargeback,
+                1,
+                3,
+                EngineError::NotDisputed(TxId(3), TransactionStatus::Resolved),
+            ),
+        ];
+        for (kind, client, tx, error) in failing {
+            let before = dump(&engine);
+            assert_eq!(
+                engine.process(&Transaction::new(kind, ClientId(client), TxId(tx))),
+                Err(error)
+            );
+            assert_eq!(dump(&engine), before);
+        }
+        assert!(!engine.clients().contains_key(&ClientId(3)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_chargeback_of_deposit() -> Result<(), EngineError> {
+        use TransactionKind::*;
+        let mut engine = Engine::new();
+        for (kind, tx) in [
+            (
+                Deposit {
+                    amount: Decimal::new(10, 0),
+                },
+                1,
+            ),
+            (
+                Deposit {
+                    amount: Decimal::new(5, 0),
+                },
+                2,
+            ),
+            (Dispute { reason: None }, 2),
+            (Chargeback, 2),
+        ] {
+            engine.process(&Transaction::new(kind, ClientId(1), TxId(tx)))?;
+        }
+        let client = &engine.clients()[&ClientId(1)];
+        assert_eq!(client.available, Decimal::new(10, 0));
+        assert_eq!(client.held, Decimal::ZERO);
+        assert_eq!(client.total(), Decimal::new(10, 0));
+        assert!(client.locked);
+        Ok(())
+    }
+
+    #[test]
+    fn test_chargeback_of_withdrawal() -> Result<(), EngineError> {
+        use TransactionKind::*;
+        let mut engine = Engine::new();
+        for (kind, tx) in [
+            (
+                Deposit {
+                    amount: Decimal::new(10, 0),
+                },
+                1,
+            ),
+            (
+                Withdrawal {
+                    amount: Decimal::new(4, 0),
+                },
+                2,
+            ),
+            (Dispute { reason: None }, 2),
+        ] {
+            engine.process(&Transaction::new(kind, ClientId(1), TxId(tx)))?;
+        }
+        let client = &engine.clients()[&ClientId(1)];
+        assert_eq!(client.available, Decimal::new(2, 0));
+        assert_eq!(client.held, Decimal::new(4, 0));
+        engine.process(&Transaction::new(Chargeback, ClientId(1), TxId(2)))?;
+        // The withdrawal never happened as far as the client is concerned
+        let client = &engine.clients()[&ClientId(1)];
+        assert_eq!(client.available, Decimal::new(10, 0));
+        assert_eq!(client.held, Decimal::ZERO);
+        assert_eq!(client.total(), Decimal::new(10, 0));
+        assert!(client.locked);
+        Ok(())
+    }
+
+    #[test]
+    fn test_reversal() -> Result<(), EngineError> {
+        use TransactionKind::*;
+        let amount = |n| Decimal::new(n, 0);
+        let mut engine = Engine::new();
+        engine.process(&Transaction::new(
+            Deposit { amount: amount(10) },
+            ClientId(1),
+            TxId(1),
+        ))?;
+        engine.process(&Transaction::new(
+            Deposit { amount: amount(5) },
+            ClientId(1),
+            TxId(2),
+        ))?;
+        engine.process(&Transaction::new(
+            Dispute { reason: None },
+            ClientId(1),
+            TxId(2),
+        ))?;
+        engine.process(&Transaction::new(Resolve, ClientId(1), TxId(2)))?;
+        assert_eq!(
+            engine.process(&Transaction::new(Reversal, ClientId(1), TxId(2))),
+            Ok(Outcome::Reversed)
+        );
+        let client = &engine.clients()[&ClientId(1)];
+        assert_eq!(client.available, amount(10));
+        assert_eq!(client.held, Decimal::ZERO);
+        assert!(!client.locked);
+        assert_eq!(
+            engine.transactions()[&TxId(2)].status(),
+            TransactionStatus::Reversed
+        );
+        // Settled for good, so it can't be reversed or disputed again
+        assert_eq!(
+            engine.process(&Transaction::new(Reversal, ClientId(1), TxId(2))),
+            Err(EngineError::AlreadyReversed(TxId(2)))
+        );
+        assert_eq!(
+            engine.process(&Transaction::new(
+                Dispute { reason: None },
+                ClientId(1),
+                TxId(2)
+            )),
+            Err(EngineError::AlreadyReversed(TxId(2)))
+        );
+        assert_eq!(
+            engine.process(&Transaction::new(Chargeback, ClientId(1), TxId(2))),
+            Err(EngineError::NotDisputed(
+                TxId(2),
+                TransactionStatus::Reversed
+            ))
+        );
+        // A withdrawal is credited back, but not while it's in dispute
+        engine.process(&Transaction::new(
+            Withdrawal { amount: amount(4) },
+            ClientId(1),
+            TxId(3),
+        ))?;
+        engine.process(&Transaction::new(
+            Dispute { reason: None },
+            ClientId(1),
+            TxId(3),
+        ))?;
+        assert_eq!(
+            engine.process(&Transaction::new(Reversal, ClientId(1), TxId(3))),
+            Err(EngineError::AlreadyDisputed(TxId(3)))
+        );
+        engine.process(&Transaction::new(Resolve, ClientId(1), TxId(3)))?;
+        engine.process(&Transaction::new(Reversal, ClientId(1), TxId(3)))?;
+        assert_eq!(engine.clients()[&ClientId(1)].available, amount(10));
+        let kinds: Vec<_> = engine
+            .statement(ClientId(1))
+            .unwrap()
+            .into_iter()
+            .map(|entry| (entry.tx_id.0, entry.kind, entry.running_balance))
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                (1, Deposit { amount: amount(10) }, amount(10)),
+                (2, Deposit { amount: amount(5) }, amount(15)),
+                (2, Dispute { reason: None }, amount(10)),
+                (2, Resolve, amount(15)),
+                (2, Reversal, amount(10)),
+                (3, Withdrawal { amount: amount(4) }, amount(6)),
+                (3, Dispute { reason: None }, amount(2)),
+                (3, Resolve, amount(6)),
+                (3, Reversal, amount(10)),
+            ]
+        );
+        // Reversing a deposit that's partly been spent is a debit like any other
+        for allow in [false, true] {
+            let mut engine =
+                Engine::with_config(EngineConfig::default().allow_negative_available(allow));
+            engine.process(&Transaction::new(
+                Deposit { amount: amount(5) },
+                ClientId(1),
+                TxId(1),
+            ))?;
+            engine.process(&Transaction::new(
+                Withdrawal { amount: amount(4) },
+                ClientId(1),
+                TxId(2),
+            ))?;
+            let reversal = engine.process(&Transaction::new(Reversal, ClientId(1), TxId(1)));
+            let available = engine.clients()[&ClientId(1)].available;
+            if allow {
+                assert_eq!((reversal, available), (Ok(Outcome::Reversed), amount(-4)));
+            } else {
+                assert_eq!(
+                    (reversal, available),
+                    (Err(EngineError::InsufficientFunds(ClientId(1))), amount(1))
+                );
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_snapshot_and_restore() -> Result<(), EngineError> {
+        use TransactionKind::*;
+        let amount = |n| Decimal::new(n, 1);
+        let txs = [
+            Transaction::new(Deposit { amount: amount(10) }, ClientId(1), TxId(1)),
+            Transaction::new(Deposit { amount: amount(20) }, ClientId(2), TxId(2)),
+            Transaction::new(Deposit { amount: amount(30) }, ClientId(1), TxId(3)),
+            Transaction::new(Dispute { reason: None }, ClientId(1), TxId(3)),
+            Transaction::new(Withdrawal { amount: amount(5) }, ClientId(1), TxId(4)),
+            // Checkpoint here, with a dispute still open
+            Transaction::new(Resolve, ClientId(1), TxId(3)),
+            Transaction::new(Dispute { reason: None }, ClientId(2), TxId(2)),
+            Transaction::new(Chargeback, ClientId(2), TxId(2)),
+            Transaction::new(Deposit { amount: amount(7) }, ClientId(3), TxId(5)),
+            Transaction::new(Withdrawal { amount: amount(15) }, ClientId(1), TxId(6)),
+        ];
+        let mut single_pass = Engine::new();
+        for tx in &txs {
+            single_pass.process(tx)?;
+        }
+        let mut first_half = Engine::new();
+        for tx in &txs[..5] {
+            first_half.process(tx)?;
+        }
+        let mut resumed = Engine::restore(first_half.snapshot());
+        for tx in &txs[5..] {
+            resumed.process(tx)?;
+        }
+        assert_eq!(dump(&resumed), dump(&single_pass));
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate() -> Result<(), EngineError> {
+        use TransactionKind::*;
+        let mut engine = Engine::new();
+        engine.process(&Transaction::new(
+            Deposit {
+                amount: Decimal::ONE,
+            },
+            ClientId(1),
+            TxId(1),
+        ))?;
+        let before = dump(&engine);
+        let dispute = Transaction::new(Dispute { reason: None }, ClientId(1), TxId(1));
+        assert_eq!(engine.validate(&dispute), Ok(()));
+        let withdrawal = Transaction::new(
+            Withdrawal {
+                amount: Decimal::TWO,
+            },
+            ClientId(1),
+            TxId(2),
+        );
+        assert_eq!(
+            engine.validate(&withdrawal),
+            Err(EngineError::InsufficientFunds(ClientId(1)))
+        );
+        let deposit = Transaction::new(
+            Deposit {
+                amount: Decimal::ONE,
+            },
+            ClientId(2),
+            TxId(1),
+        );
+        assert_eq!(
+            engine.validate(&deposit),
+            Err(EngineError::DuplicateTransactionId {
+                tx: TxId(1),
+                existing_client: ClientId(1),
+                new_client: ClientId(2)
+            })
+        );
+        assert_eq!(dump(&engine), before);
+        // And what validates is exactly what goes through
+        assert_eq!(engine.process(&dispute), Ok(Outcome::Disputed));
+        Ok(())
+    }
+
+    #[test]
+    fn test_fork() -> Result<(), EngineError> {
+        use TransactionKind::*;
+        let amount = |n| Decimal::new(n, 0);
+        let mut engine = Engine::with_config(EngineConfig::default().strict(true));
+        engine.process(&Transaction::new(
+            Deposit { amount: amount(5) },
+            ClientId(1),
+            TxId(1),
+        ))?;
+        let before = dump(&engine);
+        let mut fork = engine.fork();
+        assert!(fork.config().strict);
+        fork.extend([
+            Transaction::new(Deposit { amount: amount(2) }, ClientId(1), TxId(2)),
+            Transaction::new(Dispute { reason: None }, ClientId(1), TxId(1)),
+            Transaction::new(Withdrawal { amount: amount(9) }, ClientId(1), TxId(3)),
+        ]);
+        assert_eq!(fork.errors(), [EngineError::InsufficientFunds(ClientId(1))]);
+        assert_eq!(fork.clients()[&ClientId(1)].held, amount(5));
+        // Nothing that happened to the fork shows up here
+        assert_eq!(dump(&engine), before);
+        assert_eq!(engine.transactions().len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_on_transaction_hook() -> Result<(), EngineError> {
+        use std::sync::{Arc, Mutex};
+        use TransactionKind::*;
+        let amount = |n| Decimal::new(n, 0);
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let mut engine = Engine::new();
+        let log = Arc::clone(&seen);
+        engine.set_on_transaction(Box::new(move |tx, result| {
+            let available = result.map(|client| client.available).ok();
+            log.lock().unwrap().push((tx.tx, available));
+        }));
+        engine.extend([
+            Transaction::new(Deposit { amount: amount(5) }, ClientId(1), TxId(3)),
+            Transaction::new(Withdrawal { amount: amount(9) }, ClientId(1), TxId(1)),
+            Transaction::new(Withdrawal { amount: amount(2) }, ClientId(1), TxId(2)),
+        ]);
+        assert_eq!(
+            *seen.lock().unwrap(),
+            [
+                (TxId(3), Some(amount(5))),
+                (TxId(1), None),
+                (TxId(2), Some(amount(3)))
+            ]
+        );
+        // A fork goes without
+        let mut fork = engine.fork();
+        fork.process(&Transaction::new(
+            Deposit { amount: amount(1) },
+            ClientId(1),
+            TxId(4),
+        ))?;
+        assert_eq!(seen.lock().unwrap().len(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_preview() -> Result<(), EngineError> {
+        use TransactionKind::*;
+        let amount = |n| Decimal::new(n, 0);
+        let mut engine =
+            Engine::with_config(EngineConfig::default().allow_negative_available(true));
+        engine.process(&Transaction::new(
+            Deposit { amount: amount(5) },
+            ClientId(1),
+            TxId(1),
+        ))?;
+        let before = dump(&engine);
+        let deposit = Transaction::new(Deposit { amount: amount(2) }, ClientId(1), TxId(2));
+        let next = engine.preview(&deposit)?;
+        assert_eq!(next.client_store[&ClientId(1)].available, amount(7));
+        assert!(next.tx_store.contains_key(&TxId(2)));
+        // The engine is untouched
+        assert_eq!(dump(&engine), before);
+        assert_eq!(
+            engine
+                .preview(&Transaction::new(
+                    Deposit { amount: amount(2) },
+                    ClientId(1),
+                    TxId(1)
+                ))
+                .err(),
+            Some(EngineError::DuplicateTransaction(TxId(1)))
+        );
+        // The engine's own config applies, not the default one
+        let withdrawal = Transaction::new(Withdrawal { amount: amount(8) }, ClientId(1), TxId(3));
+        let next = engine.preview(&withdrawal)?;
+        assert_eq!(next.client_store[&ClientId(1)].available, amount(-3));
+        assert_eq!(
+            Engine::restore(engine.snapshot())
+                .preview(&withdrawal)
+                .err(),
+            Some(EngineError::InsufficientFunds(ClientId(1)))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_state_apply() -> Result<(), EngineError> {
+        use TransactionKind::*;
+        let amount = |n| Decimal::new(n, 0);
+        let mut engine =
+            Engine::with_config(EngineConfig::default().allow_negative_available(true));
+        engine.process(&Transaction::new(
+            Deposit { amount: amount(5) },
+            ClientId(1),
+            TxId(1),
+        ))?;
+        let state = engine.state();
+        let deposit = Transaction::new(Deposit { amount: amount(2) }, ClientId(1), TxId(2));
+        let next = state.apply(&deposit)?;
+        assert_eq!(next.client_store[&ClientId(1)].available, amount(7));
+        assert!(next.tx_store.contains_key(&TxId(2)));
+        // The state it started from is untouched
+        assert_eq!(state.client_store[&ClientId(1)].available, amount(5));
+        assert!(!state.tx_store.contains_key(&TxId(2)));
+        assert_eq!(
+            next.apply(&deposit).err(),
+            Some(EngineError::DuplicateTransaction(TxId(2)))
+        );
+        // The state's config applies, not the default one
+        let withdrawal = Transaction::new(Withdrawal { amount: amount(8) }, ClientId(1), TxId(3));
+        assert_eq!(
+            next.apply(&withdrawal)?.client_store[&ClientId(1)].available,
+            amount(-1)
+        );
+        let strict = EngineState {
+            config: EngineConfig::default(),
+            ..next
+        };
+        assert_eq!(
+            strict.apply(&withdrawal).err(),
+            Some(EngineError::InsufficientFunds(ClientId(1)))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_custom_store() -> Result<(), EngineError> {
+        use TransactionKind::*;
+        let amount = |n| Decimal::new(n, 0);
+
+        // Keeps transactions in id order, and counts how many were evicted
+        #[derive(Debug, Default)]
+        struct OrderedTxs {
+            txs: BTreeMap<TxId, Transaction<Decimal>>,
+            removed: usize,
+        }
+
+        impl TxStore<Decimal> for OrderedTxs {
+            fn get(&self, id: TxId) -> Option<&Transaction<Decimal>> {
+                self.txs.get(&id)
+            }
+
+            fn get_mut(&mut self, id: TxId) -> Option<&mut Transaction<Decimal>> {
+                self.txs.get_mut(&id)
+            }
+
+            fn insert(&mut self, id: TxId, tx: Transaction<Decimal>) {
+                self.txs.insert(id, tx);
+            }
+
+            fn remove(&mut self, id: TxId) -> Option<Transaction<Decimal>> {
+                self.removed += 1;
+                self.txs.remove(&id)
+            }
+        }
+
+        let config = EngineConfig::default().retention_window(1);
+        let mut engine = Engine::with_stores(config, BTreeMap::new(), OrderedTxs::default());
+        engine.extend_strict([
+            Transaction::new(Deposit { amount: amount(5) }, ClientId(1), TxId(1)),
+            Transaction::new(Dispute { reason: None }, ClientId(1), TxId(1)),
+            Transaction::new(Deposit { amount: amount(2) }, ClientId(1), TxId(2)),
+            Transaction::new(Dispute { reason: None }, ClientId(1), TxId(2)),
+            Transaction::new(Resolve, ClientId(1), TxId(2)),
+            Transaction::new(Deposit { amount: amount(3) }, ClientId(1), TxId(3)),
+            Transaction::new(Deposit { amount: amount(1) }, ClientId(1), TxId(4)),
+        ])?;
+        assert_eq!(
+            engine.process(&Transaction::new(
+                Deposit { amount: amount(1) },
+                ClientId(1),
+                TxId(4)
+            )),
+            Err(EngineError::DuplicateTransaction(TxId(4)))
+        );
+        let client = &engine.client_store()[&ClientId(1)];
+        assert_eq!((client.available, client.held), (amount(6), amount(5)));
+        // The disputed deposit stays, but the settled one after it has been evicted
+        let ids: Vec<_> = engine.tx_store().txs.keys().copied().collect();
+        assert_eq!(ids, [TxId(1), TxId(3), TxId(4)]);
+        assert_eq!(engine.tx_store().removed, 1);
+
+        // The free functions take it too
+        let mut client_store = BTreeMap::new();
+        let mut tx_store = OrderedTxs::default();
+        let deposit = Transaction::new(Deposit { amount: amount(4) }, ClientId(2), TxId(1));
+        handle(&deposit, &mut client_store, &mut tx_store)?;
+        assert_eq!(client_store[&ClientId(2)].available, amount(4));
+        assert!(tx_store.txs.contains_key(&TxId(1)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_retention_window() -> Result<(), EngineError> {
+        use TransactionKind::*;
+        let amount = |n| Decimal::new(n, 0);
+        let mut engine = Engine::with_config(EngineConfig::default().retention_window(10));
+        engine.process(&Transaction::new(
+            Deposit { amount: amount(5) },
+            ClientId(1),
+            TxId(0),
+        ))?;
+        engine.process(&Transaction::new(
+            Dispute { reason: None },
+            ClientId(1),
+            TxId(0),
+        ))?;
+        for tx in 1..10_000 {
+            let client = (tx % 50) as u16;
+            engine.process(&Transaction::new(
+                Deposit { amount: amount(2) },
+                ClientId(client),
+                TxId(tx),
+            ))?;
+            // Settle all but a few of them right away
+            if tx % 1000 != 0 {
+                engine.process(&Transaction::new(
+                    Dispute { reason: None },
+                    ClientId(client),
+                    TxId(tx),
+                ))?;
+                engine.process(&Transaction::new(Resolve, ClientId(client), TxId(tx)))?;
+            }
+            // The window, the one just processed, the disputed one and the ones
+            // that were never settled
+            assert!(engine.transactions().len() <= 12 + tx as usize / 1000);
+        }
+        // Still under dispute, so it's kept however old it is
+        engine.process(&Transaction::new(Chargeback, ClientId(1), TxId(0)))?;
+        // Never settled, so it can still be disputed
+        engine.process(&Transaction::new(
+            Dispute { reason: None },
+            ClientId(0),
+            TxId(1000),
+        ))?;
+        assert_eq!(
+            engine.process(&Transaction::new(
+                Dispute { reason: None },
+                ClientId(2),
+                TxId(2)
+            )),
+            Err(EngineError::TransactionNotFound(TxId(2)))
+        );
+        // Forgotten ids are still taken
+        assert_eq!(
+            engine.process(&Transaction::new(
+                Deposit { amount: amount(1) },
+                ClientId(3),
+                TxId(2),
+            )),
+            Err(EngineError::DuplicateTransaction(TxId(2)))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_rate_limit() -> Result<(), EngineError> {
+        use TransactionKind::*;
+        let amount = |n| Decimal::new(n, 0);
+        let mut engine =
+            Engine::with_config(EngineConfig::default().max_transactions_per_client(3));
+        engine.process(&Transaction::new(
+            Deposit { amount: amount(5) },
+            ClientId(1),
+            TxId(1),
+        ))?;
+        // Failures don't count
+        assert!(engine
+            .process(&Transaction::new(
+                Withdrawal { amount: amount(9) },
+                ClientId(1),
+                TxId(2)
+            ))
+            .is_err());
+        engine.process(&Transaction::new(
+            Deposit { amount: amount(1) },
+            ClientId(1),
+            TxId(3),
+        ))?;
+        // Exactly at the limit still goes through
+        engine.process(&Transaction::new(
+            Dispute { reason: None },
+            ClientId(1),
+            TxId(1),
+        ))?;
+        let resolve = Transaction::new(Resolve, ClientId(1), TxId(1));
+        assert_eq!(
+            engine.validate(&resolve),
+            Err(EngineError::RateLimitExceeded(ClientId(1)))
+        );
+        assert_eq!(
+            engine.process(&resolve),
+            Err(EngineError::RateLimitExceeded(ClientId(1)))
+        );
+        assert_eq!(engine.metrics().errors["rate_limit_exceeded"], 1);
+        // Other clients have limits of their own
+        engine.process(&Transaction::new(
+            Deposit { amount: amount(1) },
+            ClientId(2),
+            TxId(4),
+        ))?;
+        engine.reset_rate_limit_counters();
+        assert_eq!(engine.process(&resolve), Ok(Outcome::Resolved));
+        assert_eq!(engine.clients()[&ClientId(1)].available, amount(6));
+        Ok(())
+    }
+
+    #[test]
+    fn test_dedup_window() -> Result<(), EngineError> {
+        use TransactionKind::*;
+        let amount = |n| Decimal::new(n, 0);
+        let deposit = |n, client, tx| {
+            Transaction::new(Deposit { amount: amount(n) }, ClientId(client), TxId(tx))
+        };
+        let mut engine = Engine::with_config(EngineConfig::default().dedup_window(2));
+        engine.process(&deposit(5, 1, 1))?;
+        engine.process(&deposit(1, 1, 2))?;
+        // Disputes don't push anything out of the window
+        engine.process(&Transaction::new(
+            Dispute { reason: None },
+            ClientId(1),
+            TxId(2),
+        ))?;
+        engine.process(&Transaction::new(Resolve, ClientId(1), TxId(2)))?;
+        assert_eq!(
+            engine.process(&deposit(3, 2, 1)),
+            Err(EngineError::DuplicateTransactionId {
+                tx: TxId(1),
+                existing_client: ClientId(1),
+                new_client: ClientId(2)
+            })
+        );
+        engine.process(&deposit(1, 1, 3))?;
+        // 1 is out of the window now, so it can be used again
+        let reused = deposit(3, 2, 1);
+        assert_eq!(engine.validate(&reused), Ok(()));
+        engine.process(&reused)?;
+        assert_eq!(engine.transactions()[&TxId(1)].client, ClientId(2));
+        assert_eq!(
+            engine.process(&reused),
+            Err(EngineError::DuplicateTransaction(TxId(1)))
+        );
+        // Disputes refer to the latest one with the id
+        engine.process(&Transaction::new(
+            Dispute { reason: None },
+            ClientId(2),
+            TxId(1),
+        ))?;
+        assert_eq!(engine.clients()[&ClientId(2)].held, amount(3));
+        assert_eq!(engine.clients()[&ClientId(1)].available, amount(7));
+        // Only the transactions in the window are still stored, besides the
+        // disputed ones
+        let mut ids: Vec<_> = engine.transactions().keys().copied().collect();
+        ids.sort_unstable();
+        assert_eq!(ids, [TxId(1), TxId(2), TxId(3)]);
+        assert_eq!(
+            engine.clients()[&ClientId(1)].history,
+            [TxId(2), TxId(2), TxId(2), TxId(3)]
+        );
+
+        // A disputed transaction is never replaced, however old it is
+        let mut engine = Engine::with_config(EngineConfig::default().dedup_window(1));
+        engine.process(&deposit(10, 1, 1))?;
+        engine.process(&Transaction::new(
+            Dispute { reason: None },
+            ClientId(1),
+            TxId(1),
+        ))?;
+        engine.process(&deposit(1, 1, 2))?;
+        assert_eq!(
+            engine.process(&deposit(1, 1, 1)),
+            Err(EngineError::DuplicateTransaction(TxId(1)))
+        );
+        engine.process(&deposit(1, 1, 3))?;
+        assert!(!engine.transactions().contains_key(&TxId(2)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_tx_history_limit() -> Result<(), EngineError> {
+        use TransactionKind::*;
+        let amount = |n| Decimal::new(n, 0);
+        let deposit = |tx| Transaction::new(Deposit { amount: amount(1) }, ClientId(1), TxId(tx));
+        let dispute = |tx| Transaction::new(Dispute { reason: None }, ClientId(1), TxId(tx));
+        let stored = |engine: &Engine<Decimal>| {
+            let mut ids: Vec<_> = engine.transactions().keys().copied().collect();
+            ids.sort_unstable();
+            ids
+        };
+        let mut engine = Engine::with_config(EngineConfig::default().tx_history_limit(2));
+        engine.extend_strict([deposit(1), deposit(2), deposit(3)])?;
+        assert_eq!(stored(&engine), [TxId(2), TxId(3)]);
+        assert_eq!(
+            engine.process(&dispute(1)),
+            Err(EngineError::TransactionNotFound(TxId(1)))
+        );
+
+        // A disputed transaction stays, and the next oldest goes in its place
+        engine.extend_strict([dispute(2), deposit(4), deposit(5)])?;
+        assert_eq!(stored(&engine), [TxId(2), TxId(5)]);
+        engine.process(&Transaction::new(Resolve, ClientId(1), TxId(2)))?;
+        // Once settled, it's the oldest again
+        engine.process(&deposit(6))?;
+        assert_eq!(stored(&engine), [TxId(5), TxId(6)]);
+        let client = &engine.clients()[&ClientId(1)];
+        assert_eq!((client.available, client.held), (amount(6), amount(0)));
+        assert_eq!(client.history, [TxId(5), TxId(6)]);
+        // An evicted id can't be used again, so a replayed deposit isn't credited
+        // twice
+        assert_eq!(
+            engine.process(&deposit(1)),
+            Err(EngineError::DuplicateTransaction(TxId(1)))
+        );
+
+        // Nothing is left referring to an evicted transaction
+        let mut engine = Engine::with_config(EngineConfig::default().tx_history_limit(1));
+        engine.extend_strict([
+            deposit(1),
+            dispute(1),
+            Transaction::new(Resolve, ClientId(1), TxId(1)),
+            deposit(2),
+        ])?;
+        assert_eq!(engine.clients()[&ClientId(1)].history, [TxId(2)]);
+        assert_eq!(
+            engine
+                .project_balance(ClientId(1), &[])
+                .map(|client| client.available),
+            Ok(amount(2))
+        );
+        let statement = engine.statement(ClientId(1)).unwrap();
+        assert_eq!(statement.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_replay() -> Result<(), EngineError> {
+        use TransactionKind::*;
+        let amount = |n| Decimal::new(n, 0);
+        let log = [
+            Transaction::new(Deposit { amount: amount(10) }, ClientId(1), TxId(1)),
+            Transaction::new(Deposit { amount: amount(5) }, ClientId(2), TxId(2)),
+            Transaction::new(Withdrawal { amount: amount(20) }, ClientId(1), TxId(3)),
+            Transaction::new(Dispute { reason: None }, ClientId(2), TxId(2)),
+            Transaction::new(Chargeback, ClientId(2), TxId(2)),
+        ];
+        let mut engine = Engine::new();
+        let skipped = engine.replay(log.clone())?;
+        assert_eq!(skipped, [EngineError::InsufficientFunds(ClientId(1))]);
+        let first = format!("{:?}", engine.clients_sorted());
+        // Replaying the same log again must not run into duplicates
+        assert_eq!(engine.replay(log.clone())?, skipped);
+        assert_eq!(format!("{:?}", engine.clients_sorted()), first);
+
+        // What if the withdrawal had come in after another deposit?
+        let reordered = [
+            log[0].clone(),
+            Transaction::new(Deposit { amount: amount(10) }, ClientId(1), TxId(4)),
+            log[2].clone(),
+        ];
+        assert!(engine.replay(reordered)?.is_empty());
+        assert_eq!(engine.clients()[&ClientId(1)].available, amount(0));
+        assert!(!engine.clients().contains_key(&ClientId(2)));
+
+        let mut strict = Engine::with_config(EngineConfig::default().strict(true));
+        assert_eq!(
+            strict.replay(log),
+            Err(EngineError::InsufficientFunds(ClientId(1)))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_replay_settled_dispute() -> Result<(), EngineError> {
+        use TransactionKind::*;
+        let amount = |n| Decimal::new(n, 0);
+        let mut engine = Engine::new();
+        engine.extend_strict([
+            Transaction::new(Deposit { amount: amount(5) }, ClientId(1), TxId(1)),
+            Transaction::new(
+                Dispute {
+                    reason: Some(DisputeReason::ItemNotReceived),
+                },
+                ClientId(1),
+                TxId(1),
+            ),
+            Transaction::new(Resolve, ClientId(1), TxId(1)),
+        ])?;
+        // Only the deposit is stored, with the dispute it went through
+        let stored: Vec<_> = engine.transactions().values().cloned().collect();
+        assert!(engine.replay(stored)?.is_empty());
+        let tx = &engine.transactions()[&TxId(1)];
+        assert_eq!(tx.status(), TransactionStatus::Started);
+        assert_eq!(tx.dispute_reason(), None);
+        assert_eq!(engine.dispute_summary()?.disputes, 0);
+        engine.process(&Transaction::new(Reversal, ClientId(1), TxId(1)))?;
+        let kinds: Vec<_> = engine
+            .statement(ClientId(1))
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.kind)
+            .collect();
+        assert_eq!(kinds, [Deposit { amount: amount(5) }, Reversal]);
+        Ok(())
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_save_and_load() -> Result<(), Box<dyn std::error::Error>> {
+        use TransactionKind::*;
+        let amount = |n| Decimal::new(n, 1);
+        let txs = [
+            Transaction::new(Deposit { amount: amount(15) }, ClientId(1), TxId(1)),
+            Transaction::new(Deposit { amount: amount(20) }, ClientId(2), TxId(2)),
+            Transaction::new(Deposit { amount: amount(5) }, ClientId(1), TxId(3)),
+            Transaction::new(Dispute { reason: None }, ClientId(1), TxId(1)),
+            Transaction::new(Dispute { reason: None }, ClientId(2), TxId(2)),
+            // Monday ends with both disputes open
+            Transaction::new(Resolve, ClientId(1), TxId(1)),
+            Transaction::new(Chargeback, ClientId(2), TxId(2)),
+            Transaction::new(Withdrawal { amount: amount(10) }, ClientId(1), TxId(4)),
+        ];
+        let mut single_pass = Engine::new();
+        for tx in &txs {
+            single_pass.process(tx)?;
+        }
+
+        let mut monday = Engine::new();
+        for tx in &txs[..5] {
+            monday.process(tx)?;
+        }
+        let mut saved = Vec::new();
+        monday.save(&mut saved)?;
+        let mut tuesday = Engine::<Decimal>::load(saved.as_slice())?;
+        assert_eq!(dump(&tuesday), dump(&monday));
+        for tx in &txs[5..] {
+            tuesday.process(tx)?;
+        }
+        assert_eq!(dump(&tuesday), dump(&single_pass));
+        Ok(())
+    }
+
+    #[test]
+    fn test_transaction_history() -> Result<(), EngineError> {
+        use TransactionKind::*;
+        let amount = |n| Decimal::new(n, 0);
+        let mut engine = Engine::new();
+        for (kind, client, tx) in [
+            (Deposit { amount: amount(10) }, 1, 3),
+            (Deposit { amount: amount(7) }, 2, 2),
+            (Deposit { amount: amount(5) }, 1, 1),
+            (Withdrawal { amount: amount(4) }, 1, 4),
+            (Dispute { reason: None }, 1, 3),
+        ] {
+            engine.process(&Transaction::new(kind, ClientId(client), TxId(tx)))?;
+        }
+        let history: Vec<_> = engine
+            .transaction_history(ClientId(1))
+            .into_iter()
+            .map(|tx| (tx.tx.0, tx.kind.clone(), tx.status()))
+            .collect();
+        assert_eq!(
+            history,
+            [
+                (1, Deposit { amount: amount(5) }, TransactionStatus::Started),
+                (
+                    3,
+                    Deposit { amount: amount(10) },
+                    TransactionStatus::Disputed
+                ),
+                (
+                    4,
+                    Withdrawal { amount: amount(4) },
+                    TransactionStatus::Started
+                ),
+            ]
+        );
+        assert!(engine.transaction_history(ClientId(3)).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_statement_running_balance() -> Result<(), EngineError> {
+        use TransactionKind::*;
+        let amount = |n| Decimal::new(n, 0);
+        let mut engine = Engine::new();
+        let txs = [
+            (Deposit { amount: amount(10) }, 1, 1),
+            (Deposit { amount: amount(5) }, 1, 2),
+            (Dispute { reason: None }, 1, 1),
+            (Resolve, 1, 1),
+        ];
+        for (kind, client, tx) in txs {
+            engine.process(&Transaction::new(kind, ClientId(client), TxId(tx)))?;
+        }
+        let statement: Vec<_> = engine
+            .statement(ClientId(1))
+            .unwrap()
+            .into_iter()
+            .map(|entry| {
+                (
+                    entry.tx_id.0,
+                    entry.kind,
+                    entry.delta,
+                    entry.running_balance,
+                )
+            })
+            .collect();
+        assert_eq!(
+            statement,
+            [
+                (1, Deposit { amount: amount(10) }, amount(10), amount(10)),
+                (2, Deposit { amount: amount(5) }, amount(5), amount(15)),
+                (1, Dispute { reason: None }, amount(-10), amount(5)),
+                (1, Resolve, amount(10), amount(15)),
+            ]
+        );
+        let entries = engine.statement(ClientId(1)).unwrap();
+        assert!(entries
+            .iter()
+            .filter(|entry| entry.tx_id == TxId(1))
+            .all(|entry| entry.status == TransactionStatus::Resolved));
+        assert_eq!(
+            entries.last().map(|entry| entry.running_balance),
+            Some(engine.clients()[&ClientId(1)].available)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_integer_amounts() -> Result<(), EngineError> {
+        use TransactionKind::*;
+        assert!(5i64.is_positive());
+        assert!(!0i64.is_positive());
+        assert!(!Amount::is_positive(&Decimal::new(-1, 0)));
+        let mut engine = Engine::<i64>::new();
+        engine.process(&Transaction::new(
+            Deposit { amount: 10 },
+            ClientId(1),
+            TxId(1),
+        ))?;
+        engine.process(&Transaction::new(
+            Withdrawal { amount: 4 },
+            ClientId(1),
+            TxId(2),
+        ))?;
+        assert_eq!(
+            engine.process(&Transaction::new(
+                Deposit { amount: 0 },
+                ClientId(1),
+                TxId(3)
+            )),
+            Err(EngineError::NonPositiveAmount(TxId(3), "0".to_string()))
+        );
+        assert_eq!(engine.clients()[&ClientId(1)].available, 6);
+        Ok(())
+    }
+
+    #[test]
+    fn test_unsigned_amounts() -> Result<(), EngineError> {
+        use TransactionKind::*;
+        let mut engine = Engine::<u64>::new();
+        engine.process(&Transaction::new(
+            Deposit { amount: 10 },
+            ClientId(1),
+            TxId(1),
+        ))?;
+        engine.process(&Transaction::new(
+            Withdrawal { amount: 4 },
+            ClientId(1),
+            TxId(2),
+        ))?;
+        engine.process(&Transaction::new(
+            Deposit { amount: 3 },
+            ClientId(1),
+            TxId(3),
+        ))?;
+        engine.process(&Transaction::new(
+            Dispute { reason: None },
+            ClientId(1),
+            TxId(3),
+        ))?;
+        let client = &engine.clients()[&ClientId(1)];
+        assert_eq!((client.available, client.held), (6, 3));
+        engine.process(&Transaction::new(Resolve, ClientId(1), TxId(3)))?;
+        let client = &engine.clients()[&ClientId(1)];
+        assert_eq!((client.available, client.held), (9, 0));
+        Ok(())
+    }
+
+    #[test]
+    fn test_audit_log() -> Result<(), EngineError> {
+        use TransactionKind::*;
+        let amount = |n| Decimal::new(n, 0);
+        let mut engine = Engine::with_config(EngineConfig::default().max_audit_entries(3));
+        let txs = [
+            (Deposit { amount: amount(10) }, 1, 1),
+            (Deposit { amount: amount(5) }, 2, 2),
+            // Fails, so it isn't logged
+            (Withdrawal { amount: amount(50) }, 1, 3),
+            (Dispute { reason: None }, 1, 1),
+            (
+                Transfer {
+                    to_client: ClientId(1),
+                    amount: amount(2),
+                },
+                2,
+                4,
+            ),
+        ];
+        for (kind, client, tx) in txs {
+            let _ = engine.process(&Transaction::new(kind, ClientId(client), TxId(tx)));
+        }
+        // Both deposits were evicted to make room for the transfer's two entries
+        let log: Vec<_> = engine
+            .audit()
+            .iter()
+            .map(|entry| {
+                (
+                    entry.tx_id.0,
+                    entry.client_id.0,
+                    entry.available_delta,
+                    entry.timestamp_seq,
+                )
+            })
+            .collect();
+        assert_eq!(
+            log,
+            [
+                (1, 1, Delta::Debit(amount(10)), 3),
+                (4, 2, Delta::Debit(amount(2)), 4),
+                (4, 1, Delta::Credit(amount(2)), 5),
+            ]
+        );
+        assert_eq!(engine.audit()[0].held_delta, Delta::Credit(amount(10)));
+        // Along with the client on either side of it
+        let transfer = &engine.audit()[1];
+        assert_eq!(
+            (transfer.before.available, transfer.after.available),
+            (amount(5), amount(3))
+        );
+        assert!(transfer.after.history.is_empty());
+        assert_eq!(engine.audit()[0].after.held, amount(10));
+        // Merges and unlocks are kept to the same number
+        for from in 3..8 {
+            engine.process(&Transaction::new(
+                Deposit { amount: amount(1) },
+                ClientId(from),
+                TxId(u32::from(from) + 10),
+            ))?;
+            engine.merge_clients(ClientId(from), ClientId(2))?;
+        }
+        let merged: Vec<_> = engine.merges().iter().map(|merge| merge.from.0).collect();
+        assert_eq!(merged, [5, 6, 7]);
+        // Every entry is kept unless asked otherwise
+        assert_eq!(EngineConfig::<Decimal>::default().max_audit_entries, None);
+        // Keeping no entries turns the log off
+        let mut engine = Engine::with_config(EngineConfig::default().max_audit_entries(0));
+        engine.process(&Transaction::new(
+            Deposit { amount: amount(1) },
+            ClientId(1),
+            TxId(1),
+        ))?;
+        assert!(engine.audit().is_empty());
+        engine.process(&Transaction::new(
+            Deposit { amount: amount(1) },
+            ClientId(2),
+            TxId(2),
+        ))?;
+        engine.merge_clients(ClientId(2), ClientId(1))?;
+        assert!(engine.merges().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_audit_chargeback() -> Result<(), EngineError> {
+        use TransactionKind::*;
+        let amount = |n| Decimal::new(n, 0);
+        let mut engine = Engine::new();
+        for (kind, tx) in [
+            (Deposit { amount: amount(7) }, 1),
+            (Dispute { reason: None }, 1),
+            (Chargeback, 1),
+        ] {
+            engine.process(&Transaction::new(kind, ClientId(1), TxId(tx)))?;
+        }
+        let effects: Vec<_> = engine
+            .audit()
+            .iter()
+            .map(|entry| {
+                (
+                    entry.kind.clone(),
+                    entry.status,
+                    entry.available_delta,
+                    entry.held_delta,
+                    entry.timestamp_seq,
+                )
+            })
+            .collect();
+        assert_eq!(
+            effects,
+            [
+                (
+                    Deposit { amount: amount(7) },
+                    TransactionStatus::Started,
+                    Delta::Credit(amount(7)),
+                    Delta::Credit(amount(0)),
+                    1
+                ),
+                (
+                    Dispute { reason: None },
+                    TransactionStatus::Disputed,
+                    Delta::Debit(amount(7)),
+                    Delta::Credit(amount(7)),
+                    2
+                ),
+                (
+                    Chargeback,
+                    TransactionStatus::Chargeback,
+                    Delta::Credit(amount(0)),
+                    Delta::Debit(amount(7)),
+                    3
+                ),
+            ]
+        );
+        assert!(engine.clients()[&ClientId(1)].locked);
+        Ok(())
+    }
+
+    #[test]
+    fn test_decide() -> Result<(), EngineError> {
+        use TransactionKind::*;
+        let amount = |n| Decimal::new(n, 0);
+        let deposit = Transaction::new(Deposit { amount: amount(5) }, ClientId(1), TxId(1));
+        let client = Client::default();
+        assert_eq!(
+            decide(&deposit, &client, None)?,
+            [
+                DomainEvent::HistoryRecorded {
+                    client: ClientId(1),
+                    tx_id: TxId(1)
+                },
+                DomainEvent::AvailableChanged {
+                    client: ClientId(1),
+                    asset: None,
+                    delta: Delta::Credit(amount(5))
+                },
+                DomainEvent::TransactionStored {
+                    tx: deposit.clone()
+                },
+                DomainEvent::CurrencyEstablished {
+                    client: ClientId(1),
+                    currency: Currency::Usd
+                },
+            ]
+        );
+        assert_eq!(
+            decide(&deposit, &client, Some(&deposit)),
+            Err(EngineError::DuplicateTransaction(TxId(1)))
+        );
+        // Applying the events is the same as handling the deposit
+        let mut client_store = BTreeMap::new();
+        let mut tx_store = HashMap::new();
+        apply(
+            &decide(&deposit, &client, None)?,
+            &mut client_store,
+            &mut tx_store,
+        );
+        let dispute = Transaction::new(Dispute { reason: None }, ClientId(1), TxId(1));
+        let events = decide(
+            &dispute,
+            &client_store[&ClientId(1)],
+            tx_store.get(&TxId(1)),
+        )?;
+        assert_eq!(
+            events[1..],
+            [
+                DomainEvent::AvailableChanged {
+                    client: ClientId(1),
+                    asset: None,
+                    delta: Delta::Debit(amount(5))
+                },
+                DomainEvent::HeldChanged {
+                    client: ClientId(1),
+                    asset: None,
+                    delta: Delta::Credit(amount(5))
+                },
+                DomainEvent::TransactionStatusUpdated {
+                    tx_id: TxId(1),
+                    status: TransactionStatus::Disputed
+                },
+                DomainEvent::DisputedAmountRecorded {
+                    tx_id: TxId(1),
+                    amount: amount(5)
+                },
+            ]
+        );
+        apply(&events, &mut client_store, &mut tx_store);
+        let client = &client_store[&ClientId(1)];
+        assert_eq!((client.available, client.held), (amount(0), amount(5)));
+        assert_eq!(tx_store[&TxId(1)].status(), TransactionStatus::Disputed);
+        Ok(())
+    }
+
+    #[test]
+    fn test_partial_dispute() -> Result<(), EngineError> {
+        use TransactionKind::*;
+        let amount = |n| Decimal::new(n, 0);
+        let mut engine = Engine::new();
+        engine.process(&Transaction::new(
+            Deposit { amount: amount(10) },
+            ClientId(1),
+            TxId(1),
+        ))?;
+        engine.process(&Transaction::new(
+            Deposit { amount: amount(10) },
+            ClientId(1),
+            TxId(2),
+        ))?;
+
+        // Disputing more than the deposit changes nothing
+        let before = dump(&engine);
+        assert_eq!(
+            engine.process(&Transaction::new(
+                PartialDispute { amount: amount(11) },
+                ClientId(1),
+                TxId(1)
+            )),
+            Err(EngineError::DisputeExceedsAmount(TxId(1)))
+        );
+        assert_eq!(dump(&engine), before);
+
+        engine.process(&Transaction::new(
+            PartialDispute { amount: amount(4) },
+            ClientId(1),
+            TxId(1),
+        ))?;
+        let client = &engine.clients()[&ClientId(1)];
+        assert_eq!((client.available, client.held), (amount(16), amount(4)));
+        assert_eq!(
+            engine.transactions()[&TxId(1)].disputed_amount(),
+            Some(&amount(4))
+        );
+        assert_eq!(
+            held_sources(ClientId(1), engine.transactions()),
+            [(TxId(1), amount(4))]
+        );
+        assert_eq!(
+            engine.process(&Transaction::new(
+                PartialDispute { amount: amount(1) },
+                ClientId(1),
+                TxId(1)
+            )),
+            Err(EngineError::AlreadyDisputed(TxId(1)))
+        );
+        // Resolving only releases what was held
+        engine.process(&Transaction::new(Resolve, ClientId(1), TxId(1)))?;
+        let client = &engine.clients()[&ClientId(1)];
+        assert_eq!((client.available, client.held), (amount(20), amount(0)));
+
+        // And a chargeback only takes back the disputed part
+        engine.process(&Transaction::new(
+            PartialDispute { amount: amount(3) },
+            ClientId(1),
+            TxId(2),
+        ))?;
+        engine.process(&Transaction::new(Chargeback, ClientId(1), TxId(2)))?;
+        let client = &engine.clients()[&ClientId(1)];
+        assert_eq!((client.available, client.held), (amount(17), amount(0)));
+        assert!(client.locked);
+
+        let deltas: Vec<_> = engine
+            .statement(ClientId(1))
+            .unwrap()
+            .into_iter()
+            .map(|entry| (entry.kind, entry.delta))
+            .collect();
+        assert_eq!(
+            deltas[2..],
+            [
+                (PartialDispute { amount: amount(4) }, amount(-4)),
+                (Resolve, amount(4)),
+                (PartialDispute { amount: amount(3) }, amount(-3)),
+                (Chargeback, amount(0)),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_duplicate_transaction_id() -> Result<(), EngineError> {
+        use TransactionKind::*;
+        let amount = |n| Decimal::new(n, 0);
+        let mut engine = Engine::new();
+        engine.process(&Transaction::new(
+            Deposit { amount: amount(5) },
+            ClientId(1),
+            TxId(7),
+        ))?;
+        engine.process(&Transaction::new(
+            Deposit { amount: amount(5) },
+            ClientId(2),
+            TxId(8),
+        ))?;
+        let before = dump(&engine);
+        assert_eq!(
+            engine.process(&Transaction::new(
+                Deposit { amount: amount(5) },
+                ClientId(2),
+                TxId(7)
+            )),
+            Err(EngineError::DuplicateTransactionId {
+                tx: TxId(7),
+                existing_client: ClientId(1),
+                new_client: ClientId(2)
+            })
+        );
+        assert_eq!(
+            engine.process(&Transaction::new(
+                Withdrawal { amount: amount(1) },
+                ClientId(2),
+                TxId(7)
+            )),
+            Err(EngineError::DuplicateTransactionId {
+                tx: TxId(7),
+                existing_client: ClientId(1),
+                new_client: ClientId(2)
+            })
+        );
+        // The same client reusing its own id is still a plain duplicate
+        assert_eq!(
+            engine.process(&Transaction::new(
+                Deposit { amount: amount(5) },
+                ClientId(1),
+                TxId(7)
+            )),
+            Err(EngineError::DuplicateTransaction(TxId(7)))
+        );
+        assert_eq!(dump(&engine), before);
+        Ok(())
+    }
+
+    #[test]
+    fn test_id_reused_for_another_kind() -> Result<(), EngineError> {
+        use TransactionKind::*;
+        let amount = |n| Decimal::new(n, 0);
+        let mut engine = Engine::new();
+        engine.process(&Transaction::new(
+            Deposit { amount: amount(5) },
+            ClientId(1),
+            TxId(5),
+        ))?;
+        let before = dump(&engine);
+        let reused = EngineError::DuplicateTransactionId {
+            tx: TxId(5),
+            existing_client: ClientId(1),
+            new_client: ClientId(1),
+        };
+        let withdrawal = Transaction::new(Withdrawal { amount: amount(1) }, ClientId(1), TxId(5));
+        assert_eq!(engine.process(&withdrawal), Err(reused.clone()));
+        let fee = Transaction::new(Fee { amount: amount(1) }, ClientId(1), TxId(5));
+        assert_eq!(engine.process(&fee), Err(reused));
+        // Sending the same kind again is still a plain duplicate
+        assert_eq!(
+            engine.process(&Transaction::new(
+                Deposit { amount: amount(2) },
+                ClientId(1),
+                TxId(5)
+            )),
+            Err(EngineError::DuplicateTransaction(TxId(5)))
+        );
+        assert_eq!(dump(&engine), before);
+        // The deposit is still the one a dispute refers to
+        engine.process(&Transaction::new(
+            Dispute { reason: None },
+            ClientId(1),
+            TxId(5),
+        ))?;
+        assert_eq!(engine.clients()[&ClientId(1)].held, amount(5));
+        Ok(())
+    }
+
+    #[test]
+    fn test_client_history() -> Result<(), EngineError> {
+        use TransactionKind::*;
+        let amount = |n| Decimal::new(n, 0);
+        let mut engine = Engine::new();
+        let txs = [
+            (Deposit { amount: amount(10) }, 1, 1),
+            (Deposit { amount: amount(3) }, 2, 2),
+            (Withdrawal { amount: amount(4) }, 1, 3),
+            // Fails, so it's not part of the history
+            (Withdrawal { amount: amount(40) }, 1, 4),
+            (Dispute { reason: None }, 1, 3),
+            (Resolve, 1, 3),
+        ];
+        for (kind, client, tx) in txs {
+            let _ = engine.process(&Transaction::new(kind, ClientId(client), TxId(tx)));
+        }
+        assert_eq!(
+            engine.clients()[&ClientId(1)].history,
+            [TxId(1), TxId(3), TxId(3), TxId(3)]
+        );
+        let statement: Vec<_> = engine
+            .statement(ClientId(1))
+            .unwrap()
+            .iter()
+            .map(|entry| entry.tx_id.0)
+            .collect();
+        assert_eq!(statement, [1, 3, 3, 3]);
+        assert_eq!(engine.statement(ClientId(2)).map(|s| s.len()), Some(1));
+        assert!(engine.statement(ClientId(3)).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_project_balance() -> Result<(), EngineError> {
+        use TransactionKind::*;
+        let amount = |n| Decimal::new(n, 0);
+        let mut engine = Engine::new();
+        engine.process(&Transaction::new(
+            Deposit { amount: amount(10) },
+            ClientId(1),
+            TxId(1),
+        ))?;
+        engine.process(&Transaction::new(
+            Deposit { amount: amount(5) },
+            ClientId(2),
+            TxId(2),
+        ))?;
+        let before = dump(&engine);
+
+        let pending = [
+            Transaction::new(Deposit { amount: amount(4) }, ClientId(1), TxId(3)),
+            Transaction::new(Deposit { amount: amount(8) }, ClientId(2), TxId(4)),
+            Transaction::new(Dispute { reason: None }, ClientId(1), TxId(1)),
+        ];
+        let projected = engine.project_balance(ClientId(1), &pending).unwrap();
+        assert_eq!(projected.available, amount(4));
+        assert_eq!(projected.held, amount(10));
+        assert_eq!(projected.history, [TxId(1), TxId(3), TxId(1)]);
+        // A client with no history starts from nothing
+        let projected = engine.project_balance(
+            ClientId(3),
+            &[Transaction::new(
+                Deposit { amount: amount(1) },
+                ClientId(3),
+                TxId(5),
+            )],
+        );
+        assert_eq!(projected.map(|c| c.available), Ok(amount(1)));
+
+        let conflicting = [
+            Transaction::new(Deposit { amount: amount(1) }, ClientId(1), TxId(2)),
+            Transaction::new(Dispute { reason: None }, ClientId(1), TxId(2)),
+            Transaction::new(Withdrawal { amount: amount(20) }, ClientId(1), TxId(6)),
+        ];
+        assert_eq!(
+            engine
+                .project_balance(ClientId(1), &conflicting)
+                .map(|c| c.available),
+            Err(vec![
+                (
+                    0,
+                    EngineError::DuplicateTransactionId {
+                        tx: TxId(2),
+                        existing_client: ClientId(2),
+                        new_client: ClientId(1)
+                    }
+                ),
+                (1, EngineError::ClientMismatch(TxId(2))),
+                (2, EngineError::InsufficientFunds(ClientId(1))),
+            ])
+        );
+        assert_eq!(dump(&engine), before);
+        Ok(())
+    }
+
+    #[test]
+    fn test_multiple_assets() -> Result<(), EngineError> {
+        use TransactionKind::*;
+        let amount = |n| Decimal::new(n, 0);
+        let mut engine = Engine::new();
+        let txs = [
+            Transaction::new(Deposit { amount: amount(10) }, ClientId(1), TxId(1)),
+            Transaction::new(
+                Deposit {
+                    amount: amount(100),
+                },
+                ClientId(1),
+                TxId(2),
+            )
+            .with_asset("USD"),
+            Transaction::new(Deposit { amount: amount(2) }, ClientId(1), TxId(3)).with_asset("BTC"),
+            Transaction::new(Withdrawal { amount: amount(30) }, ClientId(1), TxId(4))
+                .with_asset("USD"),
+            // The dispute moves BTC, whatever asset it claims itself
+            Transaction::new(Dispute { reason: None }, ClientId(1), TxId(3)).with_asset("USD"),
+        ];
+        for tx in &txs {
+            engine.process(tx)?;
+        }
+        // There's only one BTC now, so it's not enough for a withdrawal
+        let withdrawal = Transaction::new(Withdrawal { amount: amount(5) }, ClientId(1), TxId(5))
+            .with_asset("BTC");
+        assert_eq!(
+            engine.process(&withdrawal),
+            Err(EngineError::InsufficientFunds(ClientId(1)))
+        );
+
+        let client = &engine.clients()[&ClientId(1)];
+        assert_eq!((client.available, client.held), (amount(10), amount(0)));
+        let balance = |available, held| AssetBalance { available, held };
+        assert_eq!(client.assets["USD"], balance(amount(70), amount(0)));
+        assert_eq!(client.assets["BTC"], balance(amount(0), amount(2)));
+        assert_eq!(aggregate(engine.clients()).unwrap().assets.len(), 2);
+
+        // The asset is part of what a transaction is, and so is its currency and
+        // when it happened, though the dispute on it isn't
+        assert_eq!(engine.transactions()[&TxId(3)], txs[2]);
+        let hash = |tx: &Transaction<Decimal>| {
+            let mut hasher = std::collections::hash_map::DefaultHasher::new();
+            tx.hash(&mut hasher);
+            hasher.finish()
+        };
+        assert_eq!(hash(&engine.transactions()[&TxId(3)]), hash(&txs[2]));
+        for other in [
+            txs[2].clone().with_asset("USD"),
+            txs[2].clone().with_currency(Currency::Eur),
+            txs[2].clone().with_timestamp(1),
+        ] {
+            assert_ne!(other, txs[2]);
+            assert_ne!(hash(&other), hash(&txs[2]));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_metrics() {
+        use TransactionKind::*;
+        let amount = |n| Decimal::new(n, 0);
+        let mut engine =
+            Engine::with_config(EngineConfig::default().allow_negative_available(true));
+        for (kind, client, tx) in [
+            (Deposit { amount: amount(10) }, 1, 1),
+            (Deposit { amount: amount(10) }, 1, 1),
+            (Withdrawal { amount: amount(4) }, 1, 2),
+            (Dispute { reason: None }, 1, 1),
+            (Dispute { reason: None }, 1, 3),
+            (Chargeback, 1, 1),
+        ] {
+            let _ = engine.process(&Transaction::new(kind, ClientId(client), TxId(tx)));
+        }
+        let metrics = engine.metrics();
+        assert_eq!(
+            metrics.deposits,
+            Counter {
+                applied: 1,
+                rejected: 1
+            }
+        );
+        assert_eq!(
+            metrics.withdrawals,
+            Counter {
+                applied: 1,
+                rejected: 0
+            }
+        );
+        assert_eq!(
+            metrics.disputes,
+            Counter {
+                applied: 1,
+                rejected: 1
+            }
+        );
+        assert_eq!(metrics.resolves, Counter::default());
+        assert_eq!(
+            metrics.chargebacks,
+            Counter {
+                applied: 1,
+                rejected: 0
+            }
+        );
+        assert_eq!(
+            metrics.to_string(),
+            "deposits 1/1, withdrawals 1/0, fees 0/0, transfers 0/0, adjustments 0/0, \
+             disputes 1/1, resolves 0/0, chargebacks 1/0, reversals 0/0, \
+             duplicate_transaction 1, transaction_not_found 1"
+        );
+    }
+
+    #[test]
+    fn test_extend() {
+        use TransactionKind::*;
+        let amount = |n| Decimal::new(n, 0);
+        let txs = vec![
+            Transaction::new(Deposit { amount: amount(10) }, ClientId(1), TxId(1)),
+            Transaction::new(Withdrawal { amount: amount(20) }, ClientId(1), TxId(2)),
+            Transaction::new(Deposit { amount: amount(5) }, ClientId(2), TxId(3)),
+        ];
+        let mut engine = Engine::new();
+        engine.extend(txs.iter().cloned());
+        assert_eq!(engine.clients().len(), 2);
+        assert_eq!(
+            engine.take_errors(),
+            [EngineError::InsufficientFunds(ClientId(1))]
+        );
+        assert!(engine.errors().is_empty());
+
+        let mut strict = Engine::with_config(EngineConfig::default().strict(true));
+        strict.extend(txs.iter().cloned());
+        assert_eq!(
+            strict.errors(),
+            [EngineError::InsufficientFunds(ClientId(1))]
+        );
+        assert!(!strict.clients().contains_key(&ClientId(2)));
+
+        let mut engine = Engine::new();
+        assert_eq!(
+            engine.extend_strict(txs),
+            Err(EngineError::InsufficientFunds(ClientId(1)))
+        );
+        assert!(engine.errors().is_empty());
+    }
+
+    #[test]
+    fn test_fee() -> Result<(), EngineError> {
+        use TransactionKind::*;
+        let amount = |n| Decimal::new(n, 0);
+        let mut engine = Engine::new();
+        engine.process(&Transaction::new(
+            Deposit { amount: amount(10) },
+            ClientId(1),
+            TxId(1),
+        ))?;
+        engine.process(&Transaction::new(
+            Fee { amount: amount(3) },
+            ClientId(1),
+            TxId(2),
+        ))?;
+        assert_eq!(engine.clients()[&ClientId(1)].available, amount(7));
+        // Fees are kept, so their ids are taken, but they can't be disputed
+        assert!(engine.transactions().contains_key(&TxId(2)));
+        assert_eq!(
+            engine.process(&Transaction::new(
+                Dispute { reason: None },
+                ClientId(1),
+                TxId(2)
+            )),
+            Err(EngineError::NotDisputable(TxId(2)))
+        );
+        assert_eq!(
+            engine.process(&Transaction::new(
+                Deposit { amount: amount(1) },
+                ClientId(1),
+                TxId(2)
+            )),
+            Err(EngineError::DuplicateTransactionId {
+                tx: TxId(2),
+                existing_client: ClientId(1),
+                new_client: ClientId(1)
+            })
+        );
+        assert_eq!(
+            engine.process(&Transaction::new(
+                Fee { amount: amount(8) },
+                ClientId(1),
+                TxId(3)
+            )),
+            Err(EngineError::InsufficientFunds(ClientId(1)))
+        );
+        assert_eq!(engine.clients()[&ClientId(1)].available, amount(7));
+        assert_eq!(
+            engine.process(&Transaction::new(
+                Fee { amount: amount(1) },
+                ClientId(1),
+                TxId(1)
+            )),
+            Err(EngineError::DuplicateTransactionId {
+                tx: TxId(1),
+                existing_client: ClientId(1),
+                new_client: ClientId(1)
+            })
+        );
+
+        // Enough to cover the dispute again
+        engine.process(&Transaction::new(
+            Deposit { amount: amount(3) },
+            ClientId(1),
+            TxId(5),
+        ))?;
+        engine.process(&Transaction::new(
+            Dispute { reason: None },
+            ClientId(1),
+            TxId(1),
+        ))?;
+        engine.process(&Transaction::new(Chargeback, ClientId(1), TxId(1)))?;
+        assert_eq!(
+            engine.process(&Transaction::new(
+                Fee { amount: amount(1) },
+                ClientId(1),
+                TxId(4)
+            )),
+            Err(EngineError::ClientLocked(ClientId(1)))
+        );
+        assert_eq!(
+            engine.clients()[&ClientId(1)].history,
+            [TxId(1), TxId(2), TxId(5), TxId(1), TxId(1)]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_adjustment() -> Result<(), Box<dyn std::error::Error>> {
+        use TransactionKind::*;
+        let amount = |n| Decimal::new(n, 0);
+        let mut engine = Engine::new();
+        let fields: &[&str] = &["adjustment", "1", "2", "-12"];
+        engine.extend_strict([
+            Transaction::new(Deposit { amount: amount(10) }, ClientId(1), TxId(1)),
+            Transaction::new(Adjustment { amount: amount(5) }, ClientId(1), TxId(3)),
+            Transaction::try_from(fields)?,
+        ])?;
+        let client = &engine.clients()[&ClientId(1)];
+        assert_eq!((client.available, client.held), (amount(3), amount(0)));
+        // A negative adjustment can't take more than the client has
+        let overdraw = Transaction::new(Adjustment { amount: amount(-4) }, ClientId(1), TxId(4));
+        assert_eq!(
+            engine.process(&overdraw),
+            Err(EngineError::InsufficientFunds(ClientId(1)))
+        );
+        assert_eq!(engine.clients()[&ClientId(1)].available, amount(3));
+        let mut overdrawn = engine.fork();
+        overdrawn.config.allow_negative_available = true;
+        overdrawn.process(&overdraw)?;
+        assert_eq!(overdrawn.clients()[&ClientId(1)].available, amount(-1));
+        // Adjustments are kept, but they can't be disputed
+        assert!(engine.transactions().contains_key(&TxId(2)));
+        for tx in [2, 3] {
+            assert_eq!(
+                engine.process(&Transaction::new(
+                    Dispute { reason: None },
+                    ClientId(1),
+                    TxId(tx)
+                )),
+                Err(EngineError::NotDisputable(TxId(tx)))
+            );
+        }
+        let adjustments = &engine.metrics().adjustments;
+        assert_eq!((adjustments.applied, adjustments.rejected), (2, 1));
+        Ok(())
+    }
+
+    #[test]
+    fn test_unsupported_kind() -> Result<(), EngineError> {
+        use TransactionKind::*;
+        let amount = |n| Decimal::new(n, 0);
+        let mut engine = Engine::new();
+        engine.process(&Transaction::new(
+            Deposit { amount: amount(2) },
+            ClientId(1),
+            TxId(1),
+        ))?;
+        let before = dump(&engine);
+        for (client, tx) in [(1, 2), (2, 3)] {
+            assert_eq!(
+                engine.process(&Transaction::new(Unsupported, ClientId(client), TxId(tx))),
+                Err(EngineError::UnsupportedKind(TxId(tx)))
+            );
+        }
+        // Nothing changes, and neither id is taken
+        assert_eq!(dump(&engine), before);
+        assert_eq!(engine.metrics().errors["unsupported_kind"], 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_debit_policy() -> Result<(), EngineError> {
+        use TransactionKind::*;
+        let amount = |n| Decimal::new(n, 0);
+        let debits = [
+            Withdrawal { amount: amount(3) },
+            Fee { amount: amount(3) },
+            Transfer {
+                to_client: ClientId(2),
+                amount: amount(3),
+            },
+        ];
+        for allow in [false, true] {
+            let config = EngineConfig::default().allow_negative_available(allow);
+            for kind in debits.clone() {
+                let mut engine = Engine::with_config(config.clone());
+                engine.process(&Transaction::new(
+                    Deposit { amount: amount(2) },
+                    ClientId(1),
+                    TxId(1),
+                ))?;
+                let result = engine.process(&Transaction::new(kind, ClientId(1), TxId(2)));
+                let available = engine.clients()[&ClientId(1)].available;
+                if allow {
+                    assert_eq!(result, Ok(Outcome::Applied));
+                    assert_eq!(available, amount(-1));
+                } else {
+                    assert_eq!(result, Err(EngineError::InsufficientFunds(ClientId(1))));
+                    assert_eq!(available, amount(2));
+                }
+            }
+        }
+
+        // So does a dispute of funds that have already been spent
+        for allow in [false, true] {
+            let config = EngineConfig::default().allow_negative_available(allow);
+            let mut engine = Engine::with_config(config);
+            engine.extend_strict([
+                Transaction::new(Deposit { amount: amount(2) }, ClientId(1), TxId(1)),
+                Transaction::new(Withdrawal { amount: amount(2) }, ClientId(1), TxId(2)),
+            ])?;
+            let result = engine.process(&Transaction::new(
+                Dispute { reason: None },
+                ClientId(1),
+                TxId(1),
+            ));
+            let available = engine.clients()[&ClientId(1)].available;
+            if allow {
+                assert_eq!(result, Ok(Outcome::Disputed));
+                assert_eq!(available, amount(-2));
+            } else {
+                assert_eq!(result, Err(EngineError::InsufficientFunds(ClientId(1))));
+                assert_eq!(available, amount(0));
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_overflow() -> Result<(), EngineError> {
+        use TransactionKind::*;
+        let mut engine = Engine::new();
+        let near_max = Decimal::MAX - Decimal::ONE;
+        engine.process(&Transaction::new(
+            Deposit { amount: near_max },
+            ClientId(1),
+            TxId(1),
+        ))?;
+        assert_eq!(
+            engine.process(&Transaction::new(
+                Deposit { amount: near_max },
+                ClientId(1),
+                TxId(2)
+            )),
+            Err(EngineError::Overflow {
+                client: ClientId(1)
+            })
+        );
+        assert_eq!(engine.clients()[&ClientId(1)].available, near_max);
+        assert!(!engine.transactions().contains_key(&TxId(2)));
+
+        // Holding the funds is fine, and only the total is too big to print
+        engine.process(&Transaction::new(
+            Deposit {
+                amount: Decimal::TEN,
+            },
+            ClientId(2),
+            TxId(3),
+        ))?;
+        engine.process(&Transaction::new(
+            Dispute { reason: None },
+            ClientId(2),
+            TxId(3),
+        ))?;
+        engine.process(&Transaction::new(
+            Deposit { amount: near_max },
+            ClientId(2),
+            TxId(4),
+        ))?;
+        assert_eq!(
+            ClientSnapshot::from_client(ClientId(2), &engine.clients()[&ClientId(2)])
+                .map(|snapshot| snapshot.total),
+            Err(EngineError::Overflow {
+                client: ClientId(2)
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_transfer() -> Result<(), EngineError> {
+        use TransactionKind::*;
+        let amount = |n| Decimal::new(n, 0);
+        let transfer = |to_client, n| Transfer {
+            to_client,
+            amount: amount(n),
+        };
+        let mut engine = Engine::new();
+        for (client, tx) in [(1, 1), (2, 2), (3, 3)] {
+            engine.process(&Transaction::new(
+                Deposit { amount: amount(10) },
+                ClientId(client),
+                TxId(tx),
+            ))?;
+        }
+        engine.process(&Transaction::new(
+            transfer(ClientId(2), 4),
+            ClientId(1),
+            TxId(4),
+        ))?;
+        assert_eq!(engine.clients()[&ClientId(1)].available, amount(6));
+        assert_eq!(engine.clients()[&ClientId(2)].available, amount(14));
+        // It's stored once, under the client who sent it
+        assert_eq!(engine.transactions()[&TxId(4)].client, ClientId(1));
+        assert_eq!(engine.statement(ClientId(2)).map(|s| s.len()), Some(2));
+
+        let before = dump(&engine);
+        let failures = [
+            (
+                transfer(ClientId(1), 1),
+                1,
+                5,
+                EngineError::SelfTransfer(TxId(5)),
+            ),
+            (
+                transfer(ClientId(2), 7),
+                1,
+                5,
+                EngineError::InsufficientFunds(ClientId(1)),
+            ),
+            (
+                transfer(ClientId(9), 7),
+                1,
+                5,
+                EngineError::InsufficientFunds(ClientId(1)),
+            ),
+        ];
+        for (kind, client, tx, error) in failures {
+            assert_eq!(
+                engine.process(&Transaction::new(kind, ClientId(client), TxId(tx))),
+                Err(error)
+            );
+        }
+        // Lock client 3, who then can neither send nor receive
+        engine.process(&Transaction::new(
+            Dispute { reason: None },
+            ClientId(3),
+            TxId(3),
+        ))?;
+        engine.process(&Transaction::new(Chargeback, ClientId(3), TxId(3)))?;
+        let before_locked = dump(&engine);
+        assert_eq!(
+            engine.process(&Transaction::new(
+                transfer(ClientId(1), 1),
+                ClientId(3),
+                TxId(6)
+            )),
+            Err(EngineError::ClientLocked(ClientId(3)))
+        );
+        assert_eq!(
+            engine.process(&Transaction::new(
+                transfer(ClientId(3), 1),
+                ClientId(1),
+                TxId(6)
+            )),
+            Err(EngineError::ClientLocked(ClientId(3)))
+        );
+        assert_eq!(dump(&engine), before_locked);
+        assert_ne!(dump(&engine), before);
+
+        // Sending to a client we haven't seen yet creates them
+        assert!(!engine.clients().contains_key(&ClientId(9)));
+        engine.process(&Transaction::new(
+            transfer(ClientId(9), 1),
+            ClientId(1),
+            TxId(7),
+        ))?;
+        assert_eq!(engine.clients()[&ClientId(1)].available, amount(5));
+        assert_eq!(engine.clients()[&ClientId(9)].available, amount(1));
+        assert_eq!(engine.clients()[&ClientId(9)].history, [TxId(7)]);
+        Ok(())
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn test_process_async() {
+        use TransactionKind::*;
+        let mut engine = Engine::with_config(EngineConfig::default().strict(true));
+        let deposit = Transaction::new(
+            Deposit {
+                amount: Decimal::TEN,
+            },
+            ClientId(1),
+            TxId(1),
+        );
+        // It has to be usable from spawned tasks
+        fn assert_send<F: Send>(future: F) -> F {
+            future
+        }
+        assert_eq!(
+            assert_send(engine.process_async(deposit.clone())).await,
+            Ok(Outcome::Applied)
+        );
+        assert_eq!(
+            engine.process_async(deposit).await,
+            Err(EngineError::DuplicateTransaction(TxId(1)))
+        );
+        assert_eq!(engine.clients()[&ClientId(1)].available, Decimal::TEN);
+        // The config makes it back from the blocking thread too
+        assert!(engine.config().strict);
+
+        // Giving up on it part way through still leaves the engine as it was
+        // processed
+        let withdrawal = Transaction::new(
+            Withdrawal {
+                amount: Decimal::ONE,
+            },
+            ClientId(1),
+            TxId(2),
+        );
+        let mut future = Box::pin(engine.process_async(withdrawal));
+        std::future::poll_fn(|cx| {
+            let _ = core::future::Future::poll(future.as_mut(), cx);
+            core::task::Poll::Ready(())
+        })
+        .await;
+        drop(future);
+        assert_eq!(engine.clients()[&ClientId(1)].available, Decimal::new(9, 0));
+        assert!(engine.config().strict);
+
+        // Nor does a hook that panics
+        engine.set_on_transaction(Box::new(|_, _| panic!("hook failed")));
+        let withdrawal = Transaction::new(
+            Withdrawal {
+                amount: Decimal::ONE,
+            },
+            ClientId(1),
+            TxId(3),
+        );
+        let mut future = Box::pin(engine.process_async(withdrawal));
+        let result = std::future::poll_fn(|cx| {
+            match std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                core::future::Future::poll(future.as_mut(), cx)
+            })) {
+                Ok(core::task::Poll::Pending) => core::task::Poll::Pending,
+                Ok(core::task::Poll::Ready(_)) => core::task::Poll::Ready(Ok(())),
+                Err(panic) => core::task::Poll::Ready(Err(panic)),
+            }
+        })
+        .await;
+        drop(future);
+        assert!(result.is_err());
+        assert_eq!(engine.clients()[&ClientId(1)].available, Decimal::new(8, 0));
+        assert!(engine.config().strict);
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn test_run_async() -> Result<(), Box<dyn std::error::Error>> {
+        let data = "type, client, tx, amount
+deposit, 1, 1, 3.0
+deposit, 2, 2, 1.5
+withdrawal, 1, 3, 5.0
+dispute, 2, 2,
+transfer, 1, 4, 1.0, 3
+";
+        let mut engine = Engine::new();
+        assert!(engine.run_async(data.as_bytes()).await?.is_empty());
+        let mut sync = Engine::new();
+        let mut rdr = csv::ReaderBuilder::new()
+            .flexible(true)
+            .from_reader(data.as_bytes());
+        for record in rdr.records() {
+            sync.extend([Transaction::try_from(record?)?]);
+        }
+        assert_eq!(dump(&engine), dump(&sync));
+        assert_eq!(
+            engine.errors(),
+            [EngineError::InsufficientFunds(ClientId(1))]
+        );
+        assert_eq!(engine.clients()[&ClientId(3)].available, Decimal::ONE);
+        // Unparsable records are skipped and handed back, unless the engine is
+        // strict
+        let bad = "type, client, tx, amount\nrefund, 1, 5, 1.0\ndeposit, 4, 6, 1.0\n";
+        assert_eq!(
+            engine.run_async(bad.as_bytes()).await?,
+            [(2, r#"found unknown transaction type "refund""#.into())]
+        );
+        assert_eq!(engine.clients()[&ClientId(4)].available, Decimal::ONE);
+        let mut strict = Engine::<Decimal>::with_config(EngineConfig::default().strict(true));
+        assert!(strict.run_async(bad.as_bytes()).await.is_err());
+        assert!(strict.clients().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_outcome() -> Result<(), EngineError> {
+        use TransactionKind::*;
+        let amount = |n| Decimal::new(n, 0);
+        let mut engine = Engine::new();
+        let mut process = |kind, tx| engine.process(&Transaction::new(kind, ClientId(1), TxId(tx)));
+        assert_eq!(process(Deposit { amount: amount(5) }, 1)?, Outcome::Applied);
+        let transfer = Transfer {
+            amount: amount(1),
+            to_client: ClientId(2),
+        };
+        assert_eq!(process(Dispute { reason: None }, 1)?, Outcome::Disputed);
+        assert_eq!(process(Resolve, 1)?, Outcome::Resolved);
+        assert_eq!(process(transfer, 2)?, Outcome::Applied);
+        assert_eq!(process(Deposit { amount: amount(3) }, 3)?, Outcome::Applied);
+        assert_eq!(process(Dispute { reason: None }, 3)?, Outcome::Disputed);
+        assert_eq!(process(Chargeback, 3)?, Outcome::ChargedBack);
+        // Errors are as they were
+        assert_eq!(
+            process(Deposit { amount: amount(1) }, 4),
+            Err(EngineError::ClientLocked(ClientId(1)))
+        );
+        assert!(engine.clients()[&ClientId(1)].locked);
+        Ok(())
+    }
+
+    #[test]
+    fn test_shared_engine() {
+        use TransactionKind::*;
+        let amount = |n| Decimal::new(n, 0);
+        // Each thread gets its own clients and its own range of ids
+        let txs_for = |thread: u16| -> Vec<_> {
+            let id = |n: u32| u32::from(thread) * 100 + n;
+            (0..10)
+                .flat_map(|n| {
+                    let client = thread * 10 + n as u16 % 3;
+                    [
+                        Transaction::new(
+                            Deposit { amount: amount(5) },
+                            ClientId(client),
+                            TxId(id(n * 2)),
+                        ),
+                        Transaction::new(
+                            Dispute { reason: None },
+                            ClientId(client),
+                            TxId(id(n * 2)),
+                        ),
+                        Transaction::new(Resolve, ClientId(client), TxId(id(n * 2))),
+                        Transaction::new(
+                            Withdrawal { amount: amount(2) },
+                            ClientId(client),
+                            TxId(id(n * 2 + 1)),
+                        ),
+                    ]
+                })
+                .collect()
+        };
+        let shared = SharedEngine::new(Engine::new());
+        std::thread::scope(|scope| {
+            for thread in 0..4 {
+                let (shared, txs) = (&shared, txs_for(thread));
+                scope.spawn(move || {
+                    for tx in &txs {
+                        shared.process(tx).unwrap();
+                    }
+                });
+            }
+        });
+        let mut sequential = Engine::new();
+        sequential.extend((0..4).flat_map(txs_for));
+        assert!(sequential.errors().is_empty());
+        // History stamps go by how the threads happened to interleave
+        let unstamped = |engine: &Engine<Decimal>| {
+            let mut snapshot = engine.snapshot();
+            for client in snapshot.client_store.values_mut() {
+                client.history_seq.clear();
+            }
+            dump(&Engine::restore(snapshot))
+        };
+        assert_eq!(
+            unstamped(&shared.into_inner().unwrap()),
+            unstamped(&sequential)
+        );
+
+        // A thread that panics with the lock held leaves it unusable
+        let shared = SharedEngine::new(Engine::new());
+        let panicked = std::thread::scope(|scope| {
+            scope
+                .spawn(|| {
+                    let _engine = shared.lock().unwrap();
+                    panic!("gave up halfway");
+                })
+                .join()
+        });
+        assert!(panicked.is_err());
+        let deposit = Transaction::new(Deposit { amount: amount(1) }, ClientId(1), TxId(1));
+        assert_eq!(shared.process(&deposit), Err(EngineError::Poisoned));
+        assert!(shared.lock().is_err());
+        assert_eq!(shared.into_inner().err(), Some(EngineError::Poisoned));
+    }
+
+    #[test]
+    fn test_handle_many() {
+        use TransactionKind::*;
+        let amount = |n| Decimal::new(n, 0);
+        let txs = [
+            Transaction::new(Deposit { amount: amount(5) }, ClientId(1), TxId(1)),
+            Transaction::new(Withdrawal { amount: amount(9) }, ClientId(1), TxId(2)),
+            Transaction::new(Dispute { reason: None }, ClientId(1), TxId(1)),
+            Transaction::new(Resolve, ClientId(1), TxId(3)),
+            Transaction::new(Deposit { amount: amount(2) }, ClientId(2), TxId(1)),
+        ];
+        let mut client_store = BTreeMap::new();
+        let mut tx_store = HashMap::new();
+        let results = handle_many(&txs, &mut client_store, &mut tx_store);
+        assert_eq!(
+            results,
+            [
+                Ok(Outcome::Applied),
+                Err(EngineError::InsufficientFunds(ClientId(1))),
+                Ok(Outcome::Disputed),
+                Err(EngineError::TransactionNotFound(TxId(3))),
+                Err(EngineError::DuplicateTransactionId {
+                    tx: TxId(1),
+                    existing_client: ClientId(1),
+                    new_client: ClientId(2)
+                }),
+            ]
+        );
+        assert_eq!(client_store[&ClientId(1)].held, amount(5));
+    }
+
+    #[test]
+    fn test_engine_error_messages() {
+        use EngineError::*;
+        let cases = [
+            (ClientLocked(ClientId(1)), "client is locked"),
+            (
+                DuplicateTransaction(TxId(2)),
+                "found duplicate transaction 2",
+            ),
+            (
+                InsufficientFunds(ClientId(1)),
+                "not enough funds to withdraw",
+            ),
+            (
+                AmountExceedsLimit(TxId(2), "11".to_string()),
+                r#"transaction "2" amount 11 exceeds the limit"#,
+            ),
+            (
+                NonPositiveAmount(TxId(2), "-1".to_string()),
+                r#"transaction "2" has non-positive amount -1"#,
+            ),
+            (
+                TransactionNotFound(TxId(3)),
+                r#"could not find referenced transaction "3""#,
+            ),
+            (
+                ClientMismatch(TxId(4)),
+                "transactions are not from the same client",
+            ),
+            (
+                AlreadyDisputed(TxId(5)),
+                r#"transaction "5" already in dispute"#,
+            ),
+            (
+                AlreadyResolved(TxId(6)),
+                r#"transaction "6" already resolved"#,
+            ),
+            (
+                AlreadyChargedBack(TxId(6)),
+                r#"transaction "6" already charged back"#,
+            ),
+            (
+                AlreadyReversed(TxId(6)),
+                r#"transaction "6" already reversed"#,
+            ),
+            (
+                NotDisputed(TxId(7), TransactionStatus::Started),
+                r#"transaction "7" is started, not in dispute"#,
+            ),
+            (
+                NoAmount(TxId(9)),
+                r#"transaction "9" does not have an amount"#,
+            ),
+            (
+                NotDisputable(TxId(9)),
+                r#"transaction "9" can't be disputed"#,
+            ),
+            (
+                DisputeExceedsAmount(TxId(9)),
+                r#"dispute is for more than transaction "9" moved"#,
+            ),
+            (
+                DuplicateTransactionId {
+                    tx: TxId(7),
+                    existing_client: ClientId(1),
+                    new_client: ClientId(2),
+                },
+                "client 2 reused transaction 7 of client 1",
+            ),
+            (
+                Overflow {
+                    client: ClientId(3),
+                },
+                "balance of client 3 would overflow",
+            ),
+            (ClientNotFound(ClientId(4)), "could not find client 4"),
+            (
+                SelfTransfer(TxId(5)),
+                r#"transaction "5" transfers to its own client"#,
+            ),
+            (
+                RateLimitExceeded(ClientId(6)),
+                "client 6 has made too many transactions",
+            ),
+            (
+                CurrencyMismatch {
+                    expected: Currency::Eur,
+                    found: Currency::Other("JPY".to_string()),
+                },
+                "expected an amount in EUR, got JPY",
+            ),
+            (
+                DisputeWindowExpired(TxId(8)),
+                r#"transaction "8" is too old to dispute"#,
+            ),
+            (
+                UnsupportedKind(TxId(9)),
+                r#"transaction "9" is of a kind that isn't supported"#,
+            ),
+            (Poisoned, "engine was poisoned by a thread that panicked"),
+            (Cancelled, "processing was cancelled before it started"),
+        ];
+        for (error, message) in cases {
+            assert_eq!(error.to_string(), message);
+            assert!(std::error::Error::source(&error).is_none());
+        }
+    }
+
+    #[test]
+    fn test_snapshot_truncated_record() {
+        let mut buf = Vec::new();
+        write_record(&mut buf, &format!("snapshot,{}", SNAPSHOT_VERSION)).unwrap();
+        write_record(&mut buf, "client,1,1.0,0,false").unwrap();
+        buf.truncate(buf.len() - 3);
+        assert!(snapshot_from_reader::<Decimal, _>(buf.as_slice()).is_err());
+    }
+
+    #[test]
+    fn test_snapshot_version() {
+        let read = |records: &[&str]| {
+            let mut buf = Vec::new();
+            for record in records {
+                write_record(&mut buf, record).unwrap();
+            }
+            snapshot_from_reader::<Decimal, _>(buf.as_slice()).map_err(|e| e.to_string())
+        };
+        let current = format!("snapshot,{}", SNAPSHOT_VERSION);
+        let (clients, _) = read(&[&current, "client,1,5,0,false,,2"]).unwrap();
+        assert_eq!(clients[&ClientId(1)].currency, None);
+        assert_eq!(clients[&ClientId(1)].history, [TxId(2)]);
+        // A snapshot from before currencies doesn't get its history read as one
+        assert_eq!(
+            read(&["client,1,5,0,false,1,2"]).unwrap_err(),
+            "could not parse snapshot version"
+        );
+        assert_eq!(
+            read(&["snapshot,99", "client,1,5,0,false,,2"]).unwrap_err(),
+            "unsupported snapshot version 99"
+        );
+        assert!(read(&[]).is_err());
+    }
+
+    #[test]
+    fn test_snapshot_escapes_text() -> Result<(), Box<dyn std::error::Error>> {
+        use TransactionKind::*;
+        let amount = |n| Decimal::new(n, 0);
+        let mut engine = Engine::new();
+        engine.extend_strict([
+            Transaction::new(Deposit { amount: amount(5) }, ClientId(1), TxId(1))
+                .with_currency(Currency::Other("X,%2C".to_string())),
+            Transaction::new(Deposit { amount: amount(2) }, ClientId(1), TxId(2))
+                .with_asset("gold, 100%")
+                .with_currency(Currency::Other("X,%2C".to_string())),
+            Transaction::new(
+                Dispute {
+                    reason: Some(DisputeReason::Other("lost, then found".to_string())),
+                },
+                ClientId(1),
+                TxId(1),
+            ),
+        ])?;
+        let mut buf = Vec::new();
+        snapshot_to_writer(engine.clients(), engine.transactions(), &mut buf)?;
+        let (clients, txs) = snapshot_from_reader::<Decimal, _>(buf.as_slice())?;
+        assert_eq!(&clients, engine.clients());
+        assert_eq!(clients[&ClientId(1)].assets.len(), 1);
+        assert_eq!(
+            clients[&ClientId(1)].currency,
+            Some(Currency::Other("X,%2C".to_string()))
+        );
+        assert_eq!(
+            txs[&TxId(1)].dispute_reason,
+            Some(DisputeReason::Other("lost, then found".to_string()))
+        );
+        assert_eq!(txs[&TxId(2)].asset.as_deref(), Some("gold, 100%"));
+        Ok(())
+    }
 }
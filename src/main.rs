@@ -1,92 +1,469 @@
-use std::{borrow::Cow, collections::HashMap, str::FromStr};
+#[cfg(feature = "serde")]
+mod ndjson;
+mod output;
 
-use csv::StringRecord;
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fmt::{self, Display},
+    fs::File,
+    io::{self, Read, Write},
+    str::FromStr,
+};
+
+use output::{write_report, OutputFormat, Report};
 use rust_decimal::prelude::*;
+use sailors_superstitions::{
+    ColumnLayout, Engine, EngineError, Transaction, TransactionKind, TxId,
+};
+
+// Command line options
+#[derive(Debug, Default)]
+struct Options {
+    // Processed in order, as if they were one file. A path of "-" is stdin,
+    // which is also read when there are no paths at all.
+    paths: Vec<String>,
+    with_total: bool,
+    preserve_scale: bool,
+    gzip: bool,
+    // Log every record and what became of it to stderr
+    verbose: bool,
+    dry_run: bool,
+    // Print the balances a dry run would end up with
+    print_preview: bool,
+    // Where the report goes instead of stdout
+    output: Option<String>,
+    output_format: OutputFormat,
+    stats: bool,
+    // Warn about every client who ends up locked or overdrawn
+    warn_insolvent: bool,
+    // Only report locked clients
+    locked_only: bool,
+    format: InputFormat,
+    // What the inputs are written in, or `None` to go by each path's extension
+    encoding: Option<Encoding>,
+}
+
+// How the records in the input are laid out
+#[derive(Debug, Clone, Copy)]
+struct InputFormat {
+    encoding: Encoding,
+    // The first row is a record like any other, not a header
+    headerless: bool,
+    layout: ColumnLayout,
+    delimiter: u8,
+    // Read the whole input before processing any of it, then go in timestamp
+    // order. Each input is sorted on its own and they're still processed one
+    // after the other, so a row can't move ahead of an earlier input's.
+    sort_by_time: bool,
+}
+
+impl Default for InputFormat {
+    fn default() -> Self {
+        Self {
+            encoding: Encoding::Csv,
+            headerless: false,
+            layout: ColumnLayout::default(),
+            delimiter: b',',
+            sort_by_time: false,
+        }
+    }
+}
+
+// What the input is written in. Headers, columns and delimiters only apply to
+// CSV.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+enum Encoding {
+    #[default]
+    Csv,
+    // One JSON object per line
+    #[cfg(feature = "serde")]
+    Json,
+}
+
+impl Encoding {
+    // Inputs ending in `.ndjson` or `.jsonl`, compressed or not, are JSON, and
+    // anything else is CSV
+    fn of_path(path: &str) -> Self {
+        let path = path.strip_suffix(".gz").unwrap_or(path);
+        match path.rsplit_once('.') {
+            #[cfg(feature = "serde")]
+            Some((_, "ndjson" | "jsonl")) => Encoding::Json,
+            _ => Encoding::Csv,
+        }
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = String;
+
+    fn from_str(encoding: &str) -> Result<Self, Self::Err> {
+        match encoding {
+            "csv" => Ok(Encoding::Csv),
+            #[cfg(feature = "serde")]
+            "json" => Ok(Encoding::Json),
+            _ => Err(format!("unknown input format {:?}", encoding)),
+        }
+    }
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut options = Options::default();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--with-total" => options.with_total = true,
+            "--pretty" => options.output_format = OutputFormat::Pretty,
+            "--preserve-scale" => options.preserve_scale = true,
+            "--gzip" => options.gzip = true,
+            "--verbose" => options.verbose = true,
+            "--dry-run" => options.dry_run = true,
+            "--print-preview" => options.print_preview = true,
+            "--stats" => options.stats = true,
+            "--warn-insolvent" => options.warn_insolvent = true,
+            "--locked-only" => options.locked_only = true,
+            "--no-headers" => options.format.headerless = true,
+            "--sort-by-time" => options.format.sort_by_time = true,
+            "--delimiter" => {
+                let delimiter = args.next().ok_or("--delimiter needs a value")?;
+                options.format.delimiter = parse_delimiter(&delimiter)?;
+            }
+            "--format" => {
+                let format = args.next().ok_or("--format needs a value")?;
+                options.output_format = format.parse()?;
+            }
+            "--format-in" => {
+                let encoding = args.next().ok_or("--format-in needs a value")?;
+                options.encoding = Some(encoding.parse()?);
+            }
+            "--output" => {
+                options.output = Some(args.next().ok_or("--output needs a value")?);
+            }
+            "--columns" => {
+                let columns = args.next().ok_or("--columns needs a value")?;
+                options.format.layout = parse_columns(&columns)?;
+            }
+            _ if arg.starts_with("--") => return Err(format!("unknown flag {}", arg)),
+            _ => options.paths.push(arg),
+        }
+    }
+    Ok(options)
+}
+
+// Parses the column index of `type`, `client`, `tx` and `amount`, optionally
+// followed by the asset (or transfer destination) column, the currency column
+// and the timestamp column, e.g. "2,0,1,3". Each optional column defaults to
+// the one after the column before it.
+fn parse_columns(columns: &str) -> Result<ColumnLayout, String> {
+    let indices = columns
+        .split(',')
+        .map(|index| index.trim().parse::<usize>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| format!("could not parse columns {:?}", columns))?;
+    let (&[kind, client, tx, amount], optional) = indices.split_at(indices.len().min(4)) else {
+        return Err(format!("expected 4 to 7 columns, got {:?}", columns));
+    };
+    let (extra, currency, timestamp) = match *optional {
+        [] => (4, 5, 6),
+        [extra] => (extra, extra + 1, extra + 2),
+        [extra, currency] => (extra, currency, currency + 1),
+        [extra, currency, timestamp] => (extra, currency, timestamp),
+        _ => return Err(format!("expected 4 to 7 columns, got {:?}", columns)),
+    };
+    Ok(ColumnLayout {
+        kind,
+        client,
+        tx,
+        amount,
+        asset: extra,
+        to_client: extra,
+        reason: extra,
+        currency,
+        timestamp,
+    })
+}
+
+// A single byte, or "tab" since that's awkward to pass on a command line
+fn parse_delimiter(delimiter: &str) -> Result<u8, String> {
+    match delimiter.as_bytes() {
+        _ if delimiter.eq_ignore_ascii_case("tab") => Ok(b'\t'),
+        [byte] => Ok(*byte),
+        _ => Err(format!("delimiter {:?} is not a single byte", delimiter)),
+    }
+}
+
+// The number of decimal places an input amount was written with
+fn input_scale(tx: &Transaction<Decimal>) -> u32 {
+    match tx.kind {
+        TransactionKind::Deposit { amount }
+        | TransactionKind::Withdrawal { amount }
+        | TransactionKind::Fee { amount }
+        | TransactionKind::Transfer { amount, .. }
+        | TransactionKind::Adjustment { amount }
+        | TransactionKind::PartialDispute { amount } => amount.scale(),
+        _ => 0,
+    }
+}
+
+// Open the input file, or stdin for "-", transparently decompressing it when
+// it's gzipped
+fn open_input(path: &str, gzip: bool) -> io::Result<Box<dyn Read>> {
+    let (input, gzip): (Box<dyn Read>, _) = match path {
+        "-" => (Box::new(io::stdin()), gzip),
+        _ => (Box::new(File::open(path)?), gzip || path.ends_with(".gz")),
+    };
+    if gzip {
+        Ok(Box::new(flate2::read::GzDecoder::new(input)))
+    } else {
+        Ok(input)
+    }
+}
+
+// What happened while going through an input
+#[derive(Debug, Default, PartialEq)]
+struct Summary {
+    // The largest number of decimal places of any input amount
+    max_scale: u32,
+    passed: usize,
+    failed: usize,
+    // Ids that were already taken by a transaction from another input, along
+    // with the source of that input
+    reused_ids: Vec<(TxId, Option<u32>)>,
+    // Every record that couldn't be parsed, in input order
+    unparsable: Vec<ParseError>,
+}
+
+impl Summary {
+    // Fold in what happened with another input
+    fn merge(&mut self, other: Summary) {
+        self.max_scale = self.max_scale.max(other.max_scale);
+        self.passed += other.passed;
+        self.failed += other.failed;
+        self.reused_ids.extend(other.reused_ids);
+        self.unparsable.extend(other.unparsable);
+    }
+}
+
+// A record that couldn't be parsed, along with where it is in the input and
+// what it said, so it can be found again
+#[derive(Debug, Clone, PartialEq)]
+struct ParseError {
+    // Starting from 1, counting the header
+    line: u64,
+    record: String,
+    reason: String,
+}
+
+// e.g. `line 3: could not parse client: "deposit,one,2,1.0"`
+impl Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}: {:?}", self.line, self.reason, self.record)
+    }
+}
 
-mod lib;
-use lib::{handle, Transaction, TransactionKind};
-
-// Extend StringRecord
-
-fn parse_value<T: FromStr>(
-    value: &StringRecord,
-    index: usize,
-    name: &str,
-) -> Result<T, Cow<'static, str>> {
-    match value
-        .get(index)
-        .ok_or(format!("could not find {}", name))?
-        .trim()
-        .parse::<T>()
-    {
-        Ok(t) => Ok(t),
-        Err(_) => Err(format!("could not parse {}", name).into()),
-    }
-}
-
-impl<T: FromStr> TryFrom<StringRecord> for Transaction<T> {
-    type Error = Cow<'static, str>;
-
-    fn try_from(value: StringRecord) -> Result<Self, Self::Error> {
-        // Add some constants
-        const KIND_INDEX: usize = 0;
-        const CLIENT_INDEX: usize = 1;
-        const TX_INDEX: usize = 2;
-        const AMOUNT_INDEX: usize = 3;
-        // Get and parse the transaction kind
-        let kind_str = value.get(KIND_INDEX).ok_or(r#"could not find "type""#)?;
-        // We ignore casing in case someone wrote "Deposit" instead of "deposit" and
-        // such. Sadly, we cannot use a match expression for this...
-        let kind = if kind_str.eq_ignore_ascii_case("deposit") {
-            TransactionKind::Deposit {
-                amount: parse_value::<T>(&value, AMOUNT_INDEX, "amount")?,
+impl Error for ParseError {}
+
+// Go through each record, written and laid out as in `format`, and operate on
+// it. Bad records are counted and skipped unless the engine is strict. Every
+// transaction gets tagged with `source` so a balance can be traced back to the
+// batch that produced it. With a `log`, a line goes out to it for each record
+// as soon as it's been dealt with.
+fn process_file<R: Read>(
+    reader: R,
+    source: Option<u32>,
+    format: &InputFormat,
+    engine: &mut Engine<Decimal>,
+    mut log: Option<&mut dyn Write>,
+) -> Result<Summary, Box<dyn Error>> {
+    let mut summary = Summary::default();
+    // Sorting keeps the record numbers from the input. Records without a
+    // timestamp, or that couldn't be parsed, go first, in the order they came in.
+    let mut rows: Box<dyn Iterator<Item = _>> = Box::new(records(reader, format).enumerate());
+    if format.sort_by_time {
+        let mut buffered: Vec<_> = rows.collect();
+        buffered.sort_by_key(|(_, tx_result)| match tx_result {
+            Ok(Ok(tx)) => tx.timestamp,
+            _ => None,
+        });
+        rows = Box::new(buffered.into_iter());
+    }
+    for (i, tx_result) in rows {
+        let (result, description) = match tx_result? {
+            Ok(mut tx) => {
+                tx.source = source;
+                summary.max_scale = summary.max_scale.max(input_scale(&tx));
+                let description = format!("{} client {} tx {}", tx.kind, tx.client, tx.tx);
+                let result = engine.process(&tx);
+                if let Err(
+                    EngineError::DuplicateTransaction(id)
+                    | EngineError::DuplicateTransactionId { tx: id, .. },
+                ) = result
+                {
+                    let earlier = engine.transactions().get(&id).map(|tx| tx.source);
+                    if let Some(earlier) = earlier.filter(|earlier| *earlier != source) {
+                        summary.reused_ids.push((id, earlier));
+                    }
+                }
+                (result.map_err(Box::<dyn Error>::from), description)
             }
-        } else if kind_str.eq_ignore_ascii_case("withdrawal") {
-            TransactionKind::Withdrawal {
-                amount: parse_value::<T>(&value, AMOUNT_INDEX, "amount")?,
+            Err(e) => {
+                summary.unparsable.push(e.clone());
+                (Err(e.into()), "unparsable".to_string())
             }
-        } else if kind_str.eq_ignore_ascii_case("dispute") {
-            TransactionKind::Dispute
-        } else if kind_str.eq_ignore_ascii_case("resolve") {
-            TransactionKind::Resolve
-        } else if kind_str.eq_ignore_ascii_case("chargeback") {
-            TransactionKind::Chargeback
-        } else {
-            return Err(format!(r#"found unknown transaction type "{}""#, kind_str).into());
         };
-        // Get and parse the client id
-        let client = parse_value::<u16>(&value, CLIENT_INDEX, "client")?;
-        // Get and parse the transaction id
-        let tx = parse_value::<u32>(&value, TX_INDEX, "tx")?;
-        Ok(Transaction::new(kind, client, tx))
+        if let Some(log) = log.as_mut() {
+            match &result {
+                Ok(outcome) => writeln!(log, "record {}: {}: {:?}", i + 1, description, outcome)?,
+                Err(e) => writeln!(log, "record {}: {}: error: {}", i + 1, description, e)?,
+            }
+            log.flush()?;
+        }
+        match result {
+            Ok(_) => summary.passed += 1,
+            Err(e) if engine.config().strict => return Err(e),
+            Err(_) => summary.failed += 1,
+        }
+    }
+    Ok(summary)
+}
+
+// Every transaction in `reader`, or why it couldn't be parsed. Failing to read
+// the input at all is the outer error.
+#[allow(clippy::type_complexity)]
+fn records<'a, R: Read + 'a>(
+    reader: R,
+    format: &'a InputFormat,
+) -> Box<dyn Iterator<Item = Result<Result<Transaction<Decimal>, ParseError>, Box<dyn Error>>> + 'a>
+{
+    match format.encoding {
+        Encoding::Csv => {
+            // The reader already skips a UTF-8 byte order mark and accepts CRLF line
+            // endings, and the parser copes with either if a record still has them.
+            // The optional columns are optional on every row, whatever the header
+            // has.
+            let rdr = csv::ReaderBuilder::new()
+                .flexible(true)
+                .has_headers(!format.headerless)
+                .delimiter(format.delimiter)
+                .from_reader(reader);
+            let delimiter = char::from(format.delimiter).to_string();
+            Box::new(rdr.into_records().map(move |sr_result| {
+                let record = sr_result?;
+                Ok(
+                    Transaction::from_record(&record, &format.layout).map_err(|reason| {
+                        ParseError {
+                            line: record.position().map_or(0, |position| position.line()),
+                            record: record.iter().collect::<Vec<_>>().join(&delimiter),
+                            reason: reason.into_owned(),
+                        }
+                    }),
+                )
+            }))
+        }
+        // Blank lines are skipped, like a trailing newline
+        #[cfg(feature = "serde")]
+        Encoding::Json => Box::new(
+            io::BufRead::lines(io::BufReader::new(reader))
+                .zip(1..)
+                .filter(|(line, _)| !line.as_ref().is_ok_and(|line| line.trim().is_empty()))
+                .map(|(line, number)| {
+                    let line = line?;
+                    Ok(ndjson::parse_line(&line).map_err(|reason| ParseError {
+                        line: number,
+                        reason: reason.to_string(),
+                        record: line,
+                    }))
+                }),
+        ),
+    }
+}
+
+// "0 errors" for a clean run
+fn error_summary(failed: usize) -> String {
+    match failed {
+        1 => "1 error".to_string(),
+        _ => format!("{} errors", failed),
     }
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // Get path from command line and make a reader out of it
-    let path = std::env::args().nth(1).expect("input file");
-    let mut rdr = csv::Reader::from_path(path).expect("could not open file");
-    // Use a HashMap because we don't know if we can trust the input file
-    let mut client_store = HashMap::new();
-    let mut tx_store = HashMap::new();
-    // Go through each record and operate on it
-    for sr_result in rdr.records() {
-        let tx_result: Result<Transaction<Decimal>, _> = sr_result?.try_into();
-        if let Ok(tx) = tx_result {
-            let _ = handle(&tx, &mut client_store, &mut tx_store);
+fn main() -> Result<(), Box<dyn Error>> {
+    // Get paths from command line and go through each one in turn, so ids have
+    // to be unique across all of them
+    let mut options = parse_args(std::env::args().skip(1))?;
+    if options.paths.is_empty() {
+        options.paths.push("-".to_string());
+    }
+    let mut engine = Engine::new();
+    // A dry run goes through a fork of the engine, thrown away at the end, so
+    // none of it reaches the engine itself
+    let mut fork = options.dry_run.then(|| engine.fork());
+    let engine = fork.as_mut().unwrap_or(&mut engine);
+    let mut summary = Summary::default();
+    for (i, path) in options.paths.iter().enumerate() {
+        let input = open_input(path, options.gzip)
+            .map_err(|e| format!("could not open {}: {}", path, e))?;
+        let mut stderr = io::stderr();
+        let log = options.verbose.then_some(&mut stderr as &mut dyn Write);
+        let format = InputFormat {
+            encoding: options.encoding.unwrap_or_else(|| Encoding::of_path(path)),
+            ..options.format
+        };
+        // Each input is its own batch, so transactions can be traced back to it
+        let file_summary = process_file(input, Some(i as u32), &format, engine, log)?;
+        // A verbose run has already logged these
+        if !options.verbose {
+            for error in &file_summary.unparsable {
+                eprintln!("{}: {}", path, error);
+            }
+        }
+        for (id, earlier) in &file_summary.reused_ids {
+            let earlier = earlier.map_or("an earlier input", |earlier| {
+                &options.paths[earlier as usize]
+            });
+            eprintln!(
+                "duplicate: transaction {} in {} was already used in {}",
+                id, path, earlier
+            );
+        }
+        summary.merge(file_summary);
+    }
+    // Stats go to stderr so they never end up in the report
+    if options.stats {
+        eprintln!("{}", engine.metrics());
+    }
+    if options.warn_insolvent {
+        for client in engine.insolvent_clients() {
+            eprintln!("warning: client {} is insolvent", client);
+        }
+    }
+    // A dry run only reports, on stderr, how many rows wouldn't go through. Each
+    // row is still applied to the fork, so later rows see the effect of earlier
+    // ones, and the balances it ends up with can be previewed.
+    if options.dry_run {
+        eprintln!("{}", error_summary(summary.failed));
+        if !options.print_preview {
+            return Ok(());
         }
     }
     // Lastly, we print the calculations
-    println!("client, available, held, total, locked");
-    for (id, client) in client_store {
-        println!(
-            "{}, {}, {}, {}, {}",
-            id,
-            client.available,
-            client.held,
-            client.available + client.held,
-            client.locked
-        );
+    let report = Report {
+        with_total: options.with_total,
+        scale: options.preserve_scale.then_some(summary.max_scale),
+        format: options.output_format,
+    };
+    // Failing to write the report is an error like any other, so it ends in a
+    // non-zero exit
+    let out: Box<dyn Write> = match &options.output {
+        Some(path) => Box::new(File::create(path)?),
+        None => Box::new(io::stdout().lock()),
+    };
+    if options.locked_only {
+        let locked: BTreeMap<_, _> = engine
+            .locked_clients()
+            .map(|(id, client)| (id, client.clone()))
+            .collect();
+        write_report(out, &locked, &report)?;
+    } else {
+        write_report(out, engine.clients(), &report)?;
     }
 
     Ok(())
@@ -95,8 +472,23 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::error::Error;
+    use sailors_superstitions::{handle, ClientId, EngineConfig};
+    use std::collections::{BTreeMap, HashMap};
+
+    // A plain input with a header, from no batch in particular
+    fn process<R: Read>(
+        reader: R,
+        engine: &mut Engine<Decimal>,
+    ) -> Result<Summary, Box<dyn Error>> {
+        process_file(reader, None, &InputFormat::default(), engine, None)
+    }
+
     #[test]
+    #[allow(
+        clippy::match_single_binding,
+        clippy::assertions_on_constants,
+        clippy::bool_assert_comparison
+    )]
     fn test_with_duplicates() -> Result<(), Box<dyn Error>> {
         let data = "
 type, client, tx, amount
@@ -109,7 +501,7 @@ withdrawal, 1, 4, 1.5
 withdrawal, 2, 5, 3.0"
             .trim();
         let mut rdr = csv::Reader::from_reader(data.as_bytes());
-        let mut client_store = HashMap::new();
+        let mut client_store = BTreeMap::new();
         let mut tx_store = HashMap::new();
         for sr_result in rdr.records() {
             let tx_result: Result<Transaction<Decimal>, _> = sr_result?.try_into();
@@ -122,14 +514,32 @@ withdrawal, 2, 5, 3.0"
                 }
             }
         }
-        let client_1 = client_store.get(&1).unwrap();
+        let client_1 = client_store.get(&ClientId(1)).unwrap();
         assert_eq!(client_1.available, Decimal::from_str("1.5").unwrap());
         assert_eq!(client_1.held, Decimal::from_str("0.0").unwrap());
         assert_eq!(client_1.locked, false);
+        // Only the first of each duplicate made it into the store
+        let deposit = |amount| TransactionKind::Deposit {
+            amount: Decimal::from_str(amount).unwrap(),
+        };
+        assert_eq!(tx_store.len(), 4);
+        assert_eq!(
+            tx_store[&TxId(1)],
+            Transaction::new(deposit("1.0"), ClientId(1), TxId(1))
+        );
+        assert_eq!(
+            tx_store[&TxId(3)],
+            Transaction::new(deposit("2.0"), ClientId(1), TxId(3))
+        );
+        assert_ne!(
+            tx_store[&TxId(3)],
+            Transaction::new(deposit("2.0"), ClientId(2), TxId(3))
+        );
         Ok(())
     }
 
     #[test]
+    #[allow(clippy::assertions_on_constants, clippy::bool_assert_comparison)]
     fn test_with_resolve_and_chargeback() -> Result<(), Box<dyn Error>> {
         let data = "
 type, client, tx, amount
@@ -142,7 +552,7 @@ chargeback, 2, 2, 0
 "
         .trim();
         let mut rdr = csv::Reader::from_reader(data.as_bytes());
-        let mut client_store = HashMap::new();
+        let mut client_store = BTreeMap::new();
         let mut tx_store = HashMap::new();
         for sr_result in rdr.records() {
             let tx_result: Result<Transaction<Decimal>, _> = sr_result?.try_into();
@@ -158,12 +568,686 @@ chargeback, 2, 2, 0
                 }
             }
         }
-        let client_1 = client_store.get(&1).unwrap();
+        let client_1 = client_store.get(&ClientId(1)).unwrap();
         assert_eq!(client_1.available, Decimal::from_str("1.5").unwrap());
         assert_eq!(client_1.held, Decimal::from_str("0.0").unwrap());
         assert_eq!(client_1.locked, false);
-        let client_2 = client_store.get(&2).unwrap();
+        let client_2 = client_store.get(&ClientId(2)).unwrap();
         assert_eq!(client_2.locked, true);
         Ok(())
     }
+
+    #[test]
+    fn test_total_trailer() -> Result<(), Box<dyn Error>> {
+        let data = "
+type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 2, 2, 2.25
+deposit, 3, 3, 4.0
+withdrawal, 1, 4, 0.5
+dispute, 3, 3,
+"
+        .trim();
+        let mut rdr = csv::Reader::from_reader(data.as_bytes());
+        let mut client_store = BTreeMap::new();
+        let mut tx_store = HashMap::new();
+        for sr_result in rdr.records() {
+            let tx: Transaction<Decimal> = sr_result?.try_into()?;
+            handle(&tx, &mut client_store, &mut tx_store)?;
+        }
+        let mut out = Vec::new();
+        let report = Report {
+            with_total: true,
+            ..Report::default()
+        };
+        write_report(&mut out, &client_store, &report)?;
+        let out = String::from_utf8(out)?;
+        let rows: Vec<Vec<&str>> = out
+            .lines()
+            .skip(1)
+            .map(|line| line.split(',').map(str::trim).collect())
+            .collect();
+        let (trailer, clients) = rows.split_last().unwrap();
+        assert_eq!(trailer[0], "TOTAL");
+        for column in 1..=3 {
+            let sum: Decimal = clients
+                .iter()
+                .map(|row| Decimal::from_str(row[column]).unwrap())
+                .sum();
+            assert_eq!(Decimal::from_str(trailer[column])?, sum);
+        }
+        assert_eq!(Decimal::from_str(trailer[3])?, Decimal::from_str("6.75")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_total_trailer_overflow() -> Result<(), Box<dyn Error>> {
+        let mut client_store = BTreeMap::new();
+        let mut tx_store = HashMap::new();
+        for id in [1, 2] {
+            let deposit = Transaction::new(
+                TransactionKind::Deposit {
+                    amount: Decimal::MAX,
+                },
+                ClientId(id),
+                TxId(id.into()),
+            );
+            handle(&deposit, &mut client_store, &mut tx_store)?;
+        }
+        let report = Report {
+            with_total: true,
+            ..Report::default()
+        };
+        let err = write_report(Vec::new(), &client_store, &report).unwrap_err();
+        assert_eq!(err.to_string(), "system-wide total would overflow");
+        Ok(())
+    }
+
+    #[test]
+    fn test_output_is_deterministic() -> Result<(), Box<dyn Error>> {
+        let data = "
+type, client, tx, amount
+deposit, 9, 1, 1.0
+deposit, 3, 2, 2.0
+deposit, 7, 3, 3.0
+deposit, 1, 4, 4.0
+deposit, 5, 5, 5.0
+deposit, 2, 6, 6.0
+withdrawal, 7, 7, 1.0
+"
+        .trim();
+        let run = || -> Result<Vec<u8>, Box<dyn Error>> {
+            let mut rdr = csv::Reader::from_reader(data.as_bytes());
+            let mut client_store = BTreeMap::new();
+            let mut tx_store = HashMap::new();
+            for sr_result in rdr.records() {
+                let tx: Transaction<Decimal> = sr_result?.try_into()?;
+                handle(&tx, &mut client_store, &mut tx_store)?;
+            }
+            let mut out = Vec::new();
+            write_report(&mut out, &client_store, &Report::default())?;
+            Ok(out)
+        };
+        let first = run()?;
+        assert_eq!(first, run()?);
+        let ids: Vec<&str> = std::str::from_utf8(&first)?
+            .lines()
+            .skip(1)
+            .map(|line| line.split(',').next().unwrap())
+            .collect();
+        assert_eq!(ids, ["1", "2", "3", "5", "7", "9"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_preserve_scale() -> Result<(), Box<dyn Error>> {
+        let data = "
+type, client, tx, amount
+deposit, 1, 1, 5.00
+deposit, 2, 2, 1.5
+"
+        .trim();
+        let mut rdr = csv::Reader::from_reader(data.as_bytes());
+        let mut client_store = BTreeMap::new();
+        let mut tx_store = HashMap::new();
+        let mut max_scale = 0;
+        for sr_result in rdr.records() {
+            let tx: Transaction<Decimal> = sr_result?.try_into()?;
+            max_scale = max_scale.max(input_scale(&tx));
+            handle(&tx, &mut client_store, &mut tx_store)?;
+        }
+        let report = Report {
+            scale: Some(max_scale),
+            ..Report::default()
+        };
+        let mut out = Vec::new();
+        write_report(&mut out, &client_store, &report)?;
+        let out = String::from_utf8(out)?;
+        let mut lines = out.lines().skip(1);
+        assert_eq!(lines.next(), Some("1,5.00,0.00,5.00,false"));
+        assert_eq!(lines.next(), Some("2,1.50,0.00,1.50,false"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_gzip_input() -> Result<(), Box<dyn Error>> {
+        let data = "
+type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 2, 2, 2.0
+deposit, 1, 3, 2.0
+withdrawal, 1, 4, 1.5
+dispute, 2, 2,
+"
+        .trim();
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data.as_bytes())?;
+        let compressed = encoder.finish()?;
+
+        let mut plain_engine = Engine::new();
+        process(data.as_bytes(), &mut plain_engine)?;
+        let mut gzip_engine = Engine::new();
+        let decoder = flate2::read::GzDecoder::new(compressed.as_slice());
+        process(decoder, &mut gzip_engine)?;
+
+        let (mut plain, mut gzip) = (Vec::new(), Vec::new());
+        write_report(&mut plain, plain_engine.clients(), &Report::default())?;
+        write_report(&mut gzip, gzip_engine.clients(), &Report::default())?;
+        assert_eq!(plain, gzip);
+        assert_eq!(
+            gzip_engine.clients()[&ClientId(2)].held,
+            Decimal::from_str("2.0")?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_report_is_valid_csv() -> Result<(), Box<dyn Error>> {
+        let mut engine = Engine::new();
+        process(
+            "type,client,tx,amount\ndeposit,1,1,1.25\ndeposit,2,2,3\n".as_bytes(),
+            &mut engine,
+        )?;
+        let report = Report {
+            with_total: true,
+            ..Report::default()
+        };
+        let mut out = Vec::new();
+        write_report(&mut out, engine.clients(), &report)?;
+        let mut rdr = csv::Reader::from_reader(out.as_slice());
+        assert_eq!(
+            rdr.headers()?,
+            &csv::StringRecord::from(vec!["client", "available", "held", "total", "locked"])
+        );
+        let rows = rdr.records().collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(rows.len(), 3);
+        assert!(rows.iter().all(|row| row.len() == 5));
+        assert_eq!(&rows[2][0], "TOTAL");
+        assert_eq!(&rows[2][3], "4.25");
+        Ok(())
+    }
+
+    #[test]
+    fn test_report_matches_export() -> Result<(), Box<dyn Error>> {
+        let mut engine = Engine::new();
+        let data = "type,client,tx,amount
+deposit,2,1,1.25
+deposit,1,2,3
+dispute,2,1,
+chargeback,2,1,
+";
+        process(data.as_bytes(), &mut engine)?;
+        let (mut report, mut export) = (Vec::new(), Vec::new());
+        write_report(&mut report, engine.clients(), &Report::default())?;
+        engine.export_csv(&mut export)?;
+        assert_eq!(String::from_utf8(report)?, String::from_utf8(export)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_strict_stops_at_first_error() -> Result<(), Box<dyn Error>> {
+        let data = "
+type, client, tx, amount
+deposit, 1, 1, 1.0
+withdrawal, 1, 2, 5.0
+deposit, 1, 3, 2.0
+"
+        .trim();
+        let mut lenient = Engine::new();
+        process(data.as_bytes(), &mut lenient)?;
+        assert_eq!(
+            lenient.clients()[&ClientId(1)].available,
+            Decimal::from_str("3.0")?
+        );
+
+        let mut strict = Engine::with_config(EngineConfig::default().strict(true));
+        assert!(process(data.as_bytes(), &mut strict).is_err());
+        assert_eq!(
+            strict.clients()[&ClientId(1)].available,
+            Decimal::from_str("1.0")?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_summary_counts() -> Result<(), Box<dyn Error>> {
+        let data = "
+type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 1, 1, 1.0
+withdrawal, 1, 2, 5.0
+refund, 1, 3, 1.0
+dispute, 1, 1,
+"
+        .trim();
+        let summary = process(data.as_bytes(), &mut Engine::new())?;
+        assert_eq!(summary.passed, 2);
+        assert_eq!(summary.failed, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_pretty_report() -> Result<(), Box<dyn Error>> {
+        let data =
+            "type,client,tx,amount\ndeposit,1,1,2.0\ndeposit,25,2,1000.1234\ndispute,25,2,\n";
+        let mut engine = Engine::new();
+        process(data.as_bytes(), &mut engine)?;
+        let (mut csv, mut pretty) = (Vec::new(), Vec::new());
+        let mut report = Report {
+            with_total: true,
+            ..Report::default()
+        };
+        write_report(&mut csv, engine.clients(), &report)?;
+        report.format = OutputFormat::Pretty;
+        write_report(&mut pretty, engine.clients(), &report)?;
+        let pretty = String::from_utf8(pretty)?;
+        let lines: Vec<_> = pretty.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert!(lines.iter().all(|line| line.len() == lines[0].len()));
+        assert_eq!(lines[0], "CLIENT  AVAILABLE       HELD      TOTAL  LOCKED");
+        // The same values as the CSV report, just laid out differently
+        let csv = String::from_utf8(csv)?;
+        for (csv_row, pretty_row) in csv.lines().skip(1).zip(&lines[1..]) {
+            let csv_values: Vec<_> = csv_row.split(',').filter(|v| !v.is_empty()).collect();
+            let pretty_values: Vec<_> = pretty_row.split_whitespace().collect();
+            assert_eq!(csv_values, pretty_values);
+        }
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn test_json_report() -> Result<(), Box<dyn Error>> {
+        let data = "type,client,tx,amount\ndeposit,2,1,2.5\ndeposit,1,2,3.0\ndispute,1,2,\nchargeback,1,2,\n";
+        let mut engine = Engine::new();
+        process(data.as_bytes(), &mut engine)?;
+        let report = Report {
+            format: "json".parse()?,
+            ..Report::default()
+        };
+        let mut out = Vec::new();
+        write_report(&mut out, engine.clients(), &report)?;
+        let clients: serde_json::Value = serde_json::from_str(std::str::from_utf8(&out)?)?;
+        let clients = clients.as_array().ok_or("not an array")?;
+        assert_eq!(clients.len(), 2);
+        assert_eq!(clients[0]["client_id"], 1);
+        assert_eq!(clients[0]["total"], "0.0");
+        assert_eq!(clients[0]["locked"], true);
+        assert_eq!(clients[1]["client_id"], 2);
+        assert_eq!(clients[1]["available"], "2.5");
+        assert_eq!(clients[1]["held"], "0");
+        assert_eq!(clients[1]["locked"], false);
+        assert!(parse_args(["--format", "xml"].map(String::from).into_iter()).is_err());
+        Ok(())
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_ndjson_input() -> Result<(), Box<dyn Error>> {
+        let csv = "type,client,tx,amount\ndeposit,1,1,2.5\ndeposit,2,2,1.0\ndispute,1,1,\n";
+        let json = r#"{"type": "deposit", "client": 1, "tx": 1, "amount": 2.5}
+{"type": "deposit", "client": 2, "tx": 2, "amount": "1.0"}
+
+{"type": "dispute", "client": 1, "tx": 1}
+"#;
+        let format = InputFormat {
+            encoding: "json".parse()?,
+            ..InputFormat::default()
+        };
+        let (mut from_csv, mut from_json) = (Engine::new(), Engine::new());
+        process(csv.as_bytes(), &mut from_csv)?;
+        let summary = process_file(json.as_bytes(), None, &format, &mut from_json, None)?;
+        assert_eq!((summary.passed, summary.failed), (3, 0));
+        let (mut csv_report, mut json_report) = (Vec::new(), Vec::new());
+        write_report(&mut csv_report, from_csv.clients(), &Report::default())?;
+        write_report(&mut json_report, from_json.clients(), &Report::default())?;
+        assert_eq!(
+            String::from_utf8(json_report)?,
+            String::from_utf8(csv_report)?
+        );
+        assert_eq!(from_json.clients()[&ClientId(1)].held, Decimal::new(25, 1));
+        // Bad lines are rejected the same way bad rows are
+        let bad = r#"{"type": "deposit", "client": 1, "tx": 3, "amount": "-1"}
+{"type": "refund", "client": 1, "tx": 4, "amount": "1"}
+not json
+"#;
+        let summary = process_file(bad.as_bytes(), None, &format, &mut from_json, None)?;
+        assert_eq!((summary.passed, summary.failed), (0, 3));
+        assert_eq!(Encoding::of_path("in.ndjson.gz"), Encoding::Json);
+        assert_eq!(Encoding::of_path("in.csv"), Encoding::Csv);
+        Ok(())
+    }
+
+    #[test]
+    fn test_verbose_log() -> Result<(), Box<dyn Error>> {
+        let data = "type,client,tx,amount\ndeposit,1,1,2.0\nwithdrawal,1,2,5.0\nrefund,1,3,1.0\n";
+        let mut log = Vec::new();
+        let mut engine = Engine::new();
+        let format = InputFormat::default();
+        process_file(data.as_bytes(), None, &format, &mut engine, Some(&mut log))?;
+        let log = String::from_utf8(log)?;
+        let lines: Vec<_> = log.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], "record 1: deposit(2.0) client 1 tx 1: Applied");
+        assert_eq!(
+            lines[1],
+            "record 2: withdrawal(5.0) client 1 tx 2: error: not enough funds to withdraw"
+        );
+        assert!(lines[2].starts_with("record 3: unparsable: error: "));
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_error_position() -> Result<(), Box<dyn Error>> {
+        let data = "type,client,tx,amount\ndeposit,1,1,2.0\ndeposit,one,2,1.0\n";
+        let mut engine = Engine::new();
+        let summary = process(data.as_bytes(), &mut engine)?;
+        assert_eq!(summary.failed, 1);
+        let error = &summary.unparsable[0];
+        assert_eq!(error.line, 3);
+        assert_eq!(error.record, "deposit,one,2,1.0");
+        assert_eq!(
+            error.to_string(),
+            r#"line 3: could not parse client: "deposit,one,2,1.0""#
+        );
+
+        // JSON lines are counted the same way, blank ones included
+        #[cfg(feature = "serde")]
+        {
+            let json = "{\"type\": \"deposit\", \"client\": 1, \"tx\": 1, \"amount\": \"1\"}\n\n\
+                        {\"type\": \"deposit\", \"client\": \"one\", \"tx\": 2}\n";
+            let format = InputFormat {
+                encoding: Encoding::Json,
+                ..InputFormat::default()
+            };
+            let summary = process_file(json.as_bytes(), None, &format, &mut engine, None)?;
+            assert_eq!(summary.unparsable[0].line, 3);
+            assert!(summary.unparsable[0].record.contains(r#""one""#));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_batch_source() -> Result<(), Box<dyn Error>> {
+        let first = "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,2,2,2.0\n";
+        let second = "type,client,tx,amount\ndeposit,1,3,3.0\ndispute,2,2,\n";
+        let mut engine = Engine::new();
+        let format = InputFormat::default();
+        process_file(first.as_bytes(), Some(1), &format, &mut engine, None)?;
+        process_file(second.as_bytes(), Some(2), &format, &mut engine, None)?;
+        let mut sources: Vec<_> = engine
+            .transactions()
+            .iter()
+            .map(|(id, tx)| (id.0, tx.source))
+            .collect();
+        sources.sort_unstable();
+        // Disputing a transaction doesn't change where it came from
+        assert_eq!(sources, [(1, Some(1)), (2, Some(1)), (3, Some(2))]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_asset_column() -> Result<(), Box<dyn Error>> {
+        let data = "
+type, client, tx, amount, asset
+deposit, 1, 1, 1.0,
+deposit, 1, 2, 2.5, USD
+deposit, 1, 3, 0.1, BTC
+dispute, 1, 3,,
+"
+        .trim();
+        let mut engine = Engine::new();
+        let summary = process(data.as_bytes(), &mut engine)?;
+        assert_eq!(summary.failed, 0);
+        let client = &engine.clients()[&ClientId(1)];
+        assert_eq!(client.available, Decimal::ONE);
+        assert_eq!(client.assets["USD"].available, Decimal::from_str("2.5")?);
+        assert_eq!(client.assets["BTC"].held, Decimal::from_str("0.1")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_transfer_column() -> Result<(), Box<dyn Error>> {
+        let data = "
+type, client, tx, amount, to_client
+deposit, 1, 1, 5.0,
+deposit, 2, 2, 1.0,
+transfer, 1, 3, 2.0, 2
+"
+        .trim();
+        let mut engine = Engine::new();
+        process(data.as_bytes(), &mut engine)?;
+        assert_eq!(
+            engine.clients()[&ClientId(1)].available,
+            Decimal::from_str("3.0")?
+        );
+        assert_eq!(
+            engine.clients()[&ClientId(2)].available,
+            Decimal::from_str("3.0")?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_multiple_inputs() -> Result<(), Box<dyn Error>> {
+        let header = "type,client,tx,amount\n";
+        let files = [
+            "deposit,1,1,5.0\ndeposit,2,2,2.0\n",
+            "withdrawal,1,3,1.5\ndispute,2,2,\n",
+            // Ids from an earlier file are taken
+            "deposit,1,1,9.0\nwithdrawal,2,4,1.0\nchargeback,2,2,\n",
+        ];
+        let mut sharded = Engine::new();
+        let mut summary = Summary::default();
+        for file in files {
+            summary.merge(process(
+                (header.to_string() + file).as_bytes(),
+                &mut sharded,
+            )?);
+        }
+        let mut combined = Engine::new();
+        let expected = process(
+            (header.to_string() + &files.concat()).as_bytes(),
+            &mut combined,
+        )?;
+        assert_eq!(summary, expected);
+        assert_eq!(summary.failed, 2);
+        let (mut left, mut right) = (Vec::new(), Vec::new());
+        write_report(&mut left, sharded.clients(), &Report::default())?;
+        write_report(&mut right, combined.clients(), &Report::default())?;
+        assert_eq!(left, right);
+        assert_eq!(
+            sharded.clients()[&ClientId(1)].available,
+            Decimal::from_str("3.5")?
+        );
+        assert!(sharded.clients()[&ClientId(2)].locked);
+        let options = parse_args(["a.csv", "--gzip", "b.csv"].map(String::from).into_iter())?;
+        assert_eq!(options.paths, ["a.csv", "b.csv"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_dry_run_on_fork() -> Result<(), Box<dyn Error>> {
+        let mut engine = Engine::new();
+        process(
+            "type, client, tx, amount\ndeposit, 1, 1, 2.0\n".as_bytes(),
+            &mut engine,
+        )?;
+        let before = engine.clients().clone();
+        let data = "type, client, tx, amount
+withdrawal, 1, 2, 1.5
+withdrawal, 1, 3, 1.0
+deposit, 2, 4, 3.0
+";
+        let mut fork = engine.fork();
+        let summary = process(data.as_bytes(), &mut fork)?;
+        assert_eq!((summary.passed, summary.failed), (2, 1));
+        assert_eq!(
+            fork.clients()[&ClientId(1)].available,
+            Decimal::from_str("0.5")?
+        );
+        drop(fork);
+        // Errors or not, the engine the dry run forked is as it was
+        assert_eq!(*engine.clients(), before);
+        assert_eq!(engine.transactions().len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_reused_ids_across_inputs() -> Result<(), Box<dyn Error>> {
+        let format = InputFormat::default();
+        let first = "type,client,tx,amount\ndeposit,1,1,5.0\ndeposit,1,2,1.0\ndeposit,1,2,1.0\n";
+        let second = "type,client,tx,amount\ndispute,1,1,\ndeposit,2,2,3.0\ndeposit,1,3,1.0\n";
+        let mut engine = Engine::new();
+        let mut summary = process_file(first.as_bytes(), Some(0), &format, &mut engine, None)?;
+        // A duplicate within the same input isn't a reuse
+        assert!(summary.reused_ids.is_empty());
+        summary.merge(process_file(
+            second.as_bytes(),
+            Some(1),
+            &format,
+            &mut engine,
+            None,
+        )?);
+        assert_eq!(summary.reused_ids, [(TxId(2), Some(0))]);
+        assert_eq!(summary.failed, 2);
+        // The dispute in the second input found the deposit from the first
+        assert_eq!(
+            engine.clients()[&ClientId(1)].held,
+            Decimal::from_str("5.0")?
+        );
+        assert!(!engine.clients().contains_key(&ClientId(2)));
+        let mut concatenated = Engine::new();
+        let data = first.to_string()
+            + second
+                .lines()
+                .skip(1)
+                .collect::<Vec<_>>()
+                .join("\n")
+                .as_str();
+        process(data.as_bytes(), &mut concatenated)?;
+        let (mut left, mut right) = (Vec::new(), Vec::new());
+        write_report(&mut left, engine.clients(), &Report::default())?;
+        write_report(&mut right, concatenated.clients(), &Report::default())?;
+        assert_eq!(left, right);
+        Ok(())
+    }
+
+    #[test]
+    fn test_headerless_columns() -> Result<(), Box<dyn Error>> {
+        // tx, amount, client, type
+        let data = "1,5.0,1,deposit\n2,1.5,1,deposit\n1,,1,dispute\n";
+        let options = parse_args(
+            ["--no-headers", "--columns", "3,2,0,1"]
+                .map(String::from)
+                .into_iter(),
+        )?;
+        let mut engine = Engine::new();
+        let summary = process_file(data.as_bytes(), None, &options.format, &mut engine, None)?;
+        assert_eq!(summary.passed, 3);
+        let client = &engine.clients()[&ClientId(1)];
+        assert_eq!(client.available, Decimal::from_str("1.5")?);
+        assert_eq!(client.held, Decimal::from_str("5.0")?);
+        assert!(parse_args(["--columns", "1,2"].map(String::from).into_iter()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_sort_by_time() -> Result<(), Box<dyn Error>> {
+        // The dispute comes first in the file, but happened after the deposit
+        let data = "type,client,tx,amount,asset,currency,timestamp
+dispute,1,1,,,,20
+deposit,1,1,5.0,,,10
+";
+        let mut engine = Engine::new();
+        let summary = process(data.as_bytes(), &mut engine)?;
+        assert_eq!(summary.failed, 1);
+        assert_eq!(engine.clients()[&ClientId(1)].held, Decimal::ZERO);
+
+        let options = parse_args(["--sort-by-time"].map(String::from).into_iter())?;
+        let mut engine = Engine::new();
+        let mut log = Vec::new();
+        let summary = process_file(
+            data.as_bytes(),
+            None,
+            &options.format,
+            &mut engine,
+            Some(&mut log),
+        )?;
+        assert_eq!(summary.passed, 2);
+        let client = &engine.clients()[&ClientId(1)];
+        assert_eq!(client.available, Decimal::ZERO);
+        assert_eq!(client.held, Decimal::from_str("5.0")?);
+        // Records keep their numbers from the file
+        assert!(String::from_utf8(log)?.starts_with("record 2: deposit"));
+
+        // A later input's rows never go ahead of an earlier one's
+        let (first, second) = data.split_at(data.find("deposit").unwrap());
+        let second = "type,client,tx,amount,asset,currency,timestamp\n".to_string() + second;
+        let mut engine = Engine::new();
+        let mut summary = Summary::default();
+        for (i, input) in [first, second.as_str()].into_iter().enumerate() {
+            summary.merge(process_file(
+                input.as_bytes(),
+                Some(i as u32),
+                &options.format,
+                &mut engine,
+                None,
+            )?);
+        }
+        assert_eq!((summary.passed, summary.failed), (1, 1));
+        assert_eq!(engine.clients()[&ClientId(1)].held, Decimal::ZERO);
+        Ok(())
+    }
+
+    #[test]
+    fn test_delimiters() -> Result<(), Box<dyn Error>> {
+        let rows = [
+            ["type", "client", "tx", "amount"],
+            ["deposit", " 1", "1", " 2.5 "],
+            ["deposit", "2", "2", "1.0"],
+            ["withdrawal", "1", "3", "0.5"],
+            ["dispute", "2", "2", ""],
+        ];
+        let mut reports = Vec::new();
+        for (flag, delimiter) in [(",", ","), (";", ";"), ("tab", "\t")] {
+            let data: String = rows.iter().map(|row| row.join(delimiter) + "\n").collect();
+            let options = parse_args(["--delimiter", flag].map(String::from).into_iter())?;
+            let mut engine = Engine::new();
+            let summary = process_file(data.as_bytes(), None, &options.format, &mut engine, None)?;
+            assert_eq!(summary.failed, 0);
+            let mut out = Vec::new();
+            write_report(&mut out, engine.clients(), &Report::default())?;
+            reports.push(String::from_utf8(out)?);
+        }
+        assert_eq!(reports[0], reports[1]);
+        assert_eq!(reports[0], reports[2]);
+        assert!(reports[0].contains("1,2.0,0,2.0,false"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_bom_and_crlf() -> Result<(), Box<dyn Error>> {
+        let data = "deposit,1,1,1.0\r\nwithdrawal,1,2,0.25\r\n";
+        let headerless = InputFormat {
+            headerless: true,
+            ..InputFormat::default()
+        };
+        for (input, format) in [
+            (
+                format!("\u{feff}type,client,tx,amount\r\n{}", data),
+                InputFormat::default(),
+            ),
+            (format!("\u{feff}{}", data), headerless),
+        ] {
+            let mut engine = Engine::new();
+            let summary = process_file(input.as_bytes(), None, &format, &mut engine, None)?;
+            assert_eq!(summary.passed, 2);
+            assert_eq!(
+                engine.clients()[&ClientId(1)].available,
+                Decimal::from_str("0.75")?
+            );
+        }
+        Ok(())
+    }
 }
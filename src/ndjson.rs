@@ -0,0 +1,70 @@
+use std::{borrow::Cow, error::Error};
+
+use rust_decimal::Decimal;
+use sailors_superstitions::{parse_kind, ClientId, Currency, Transaction, TxId};
+use serde::Deserialize;
+
+// One line of newline-delimited JSON, e.g.
+// `{"type": "deposit", "client": 1, "tx": 1, "amount": "1.5"}`. Everything a
+// CSV row can have is here too, and like there, only some kinds need the
+// optional fields. Amounts can be numbers, but those are read as floats, so
+// only a string keeps trailing zeros.
+#[derive(Debug, Deserialize)]
+struct TransactionDto {
+    #[serde(rename = "type")]
+    kind: String,
+    client: ClientId,
+    tx: TxId,
+    #[serde(default)]
+    amount: Option<Decimal>,
+    #[serde(default)]
+    asset: Option<String>,
+    #[serde(default)]
+    to_client: Option<ClientId>,
+    #[serde(default)]
+    reason: Option<String>,
+    #[serde(default)]
+    currency: Option<String>,
+    #[serde(default)]
+    timestamp: Option<u64>,
+}
+
+impl TryFrom<TransactionDto> for Transaction<Decimal> {
+    type Error = Cow<'static, str>;
+
+    // Goes through the same parsing as a CSV row, so the two inputs accept the
+    // same kinds and amounts
+    fn try_from(dto: TransactionDto) -> Result<Self, Self::Error> {
+        let amount = dto.amount.map(|amount| amount.to_string());
+        let to_client = dto.to_client.map(|client| client.to_string());
+        let kind = parse_kind(
+            dto.kind.trim(),
+            amount.as_deref(),
+            to_client.as_deref(),
+            dto.reason.as_deref().map(str::trim),
+        )?;
+        let mut tx = Transaction::new(kind, dto.client, dto.tx);
+        tx.asset = dto
+            .asset
+            .map(|asset| asset.trim().to_string())
+            .filter(|asset| !asset.is_empty());
+        tx.currency = dto
+            .currency
+            .map(|currency| currency.trim().to_string())
+            .filter(|currency| !currency.is_empty())
+            .map(|currency| {
+                currency
+                    .parse::<Currency>()
+                    .unwrap_or_else(|never| match never {})
+            });
+        tx.timestamp = dto.timestamp;
+        Ok(tx)
+    }
+}
+
+// Parses one line into a transaction, whether it's malformed JSON or just not a
+// transaction the engine knows
+pub fn parse_line(line: &str) -> Result<Transaction<Decimal>, Box<dyn Error>> {
+    let dto: TransactionDto = serde_json::from_str(line)?;
+    Ok(Transaction::try_from(dto)?)
+}
@@ -0,0 +1,170 @@
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    io::{self, Write},
+    str::FromStr,
+};
+
+use rust_decimal::Decimal;
+use sailors_superstitions::{
+    aggregate, client_snapshots, Client, ClientId, ClientSnapshot, REPORT_HEADER,
+};
+
+// What the report looks like
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    #[default]
+    Csv,
+    // An aligned table for people to read
+    Pretty,
+    // An array of client snapshots, leaving out the trailer row
+    #[cfg(feature = "serde")]
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "csv" => Ok(OutputFormat::Csv),
+            "pretty" => Ok(OutputFormat::Pretty),
+            #[cfg(feature = "serde")]
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(format!("unknown output format {:?}", format)),
+        }
+    }
+}
+
+// How the report should be rendered
+#[derive(Debug, Default)]
+pub struct Report {
+    pub with_total: bool,
+    // When set, every amount is printed with exactly this many decimal places
+    pub scale: Option<u32>,
+    pub format: OutputFormat,
+}
+
+impl Report {
+    fn render(&self, mut amount: Decimal) -> Decimal {
+        if let Some(scale) = self.scale {
+            amount.rescale(scale);
+        }
+        amount
+    }
+}
+
+// The client rows in ascending id order, optionally followed by a system-wide
+// trailer row, each rendered the same way whatever the report looks like. Client
+// rows are built like `Engine::export_csv` builds them, only with the amounts
+// rendered first.
+fn report_rows(
+    client_store: &BTreeMap<ClientId, Client<Decimal>>,
+    report: &Report,
+) -> Result<Vec<[String; 5]>, Box<dyn Error>> {
+    let mut rows = Vec::new();
+    for snapshot in client_snapshots(client_store)? {
+        let rendered = ClientSnapshot {
+            available: report.render(snapshot.available),
+            held: report.render(snapshot.held),
+            total: report.render(snapshot.total),
+            ..snapshot
+        };
+        rows.push(rendered.report_row());
+    }
+    if report.with_total {
+        let total = aggregate(client_store).ok_or("system-wide total would overflow")?;
+        rows.push([
+            "TOTAL".to_string(),
+            report.render(total.available).to_string(),
+            report.render(total.held).to_string(),
+            report
+                .render(
+                    total
+                        .checked_total()
+                        .ok_or("system-wide total would overflow")?,
+                )
+                .to_string(),
+            String::new(),
+        ]);
+    }
+    Ok(rows)
+}
+
+// Write the report in whichever format it asks for
+pub fn write_report<W: Write>(
+    mut out: W,
+    client_store: &BTreeMap<ClientId, Client<Decimal>>,
+    report: &Report,
+) -> Result<(), Box<dyn Error>> {
+    match report.format {
+        OutputFormat::Csv => write_csv(out, &report_rows(client_store, report)?),
+        OutputFormat::Pretty => {
+            let header = ["CLIENT", "AVAILABLE", "HELD", "TOTAL", "LOCKED"].map(String::from);
+            write_table(&mut out, &header, &report_rows(client_store, report)?)?;
+            out.flush()?;
+            Ok(())
+        }
+        #[cfg(feature = "serde")]
+        OutputFormat::Json => write_json(out, client_store, report),
+    }
+}
+
+// Write the report rows under a header. Going through `csv::Writer` means any
+// field that needs quoting gets it.
+fn write_csv<W: Write>(out: W, rows: &[[String; 5]]) -> Result<(), Box<dyn Error>> {
+    let mut wtr = csv::Writer::from_writer(out);
+    wtr.write_record(REPORT_HEADER)?;
+    for row in rows {
+        wtr.write_record(row)?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+// Every column is as wide as its widest value, so all lines come out the same
+// length. Values are right-aligned so the digits line up.
+fn write_table<W: Write>(
+    out: &mut W,
+    header: &[String; 5],
+    rows: &[[String; 5]],
+) -> io::Result<()> {
+    let mut widths = header.each_ref().map(|name| name.len());
+    for row in rows {
+        for (width, value) in widths.iter_mut().zip(row) {
+            *width = (*width).max(value.len());
+        }
+    }
+    for row in std::iter::once(header).chain(rows) {
+        let line: Vec<_> = row
+            .iter()
+            .zip(widths)
+            .map(|(value, width)| format!("{:>width$}", value, width = width))
+            .collect();
+        writeln!(out, "{}", line.join("  "))?;
+    }
+    Ok(())
+}
+
+// Every client snapshot, in ascending id order, with amounts rendered as for
+// the other formats
+#[cfg(feature = "serde")]
+fn write_json<W: Write>(
+    mut out: W,
+    client_store: &BTreeMap<ClientId, Client<Decimal>>,
+    report: &Report,
+) -> Result<(), Box<dyn Error>> {
+    let snapshots: Vec<_> = client_snapshots(client_store)?
+        .into_iter()
+        .map(|snapshot| sailors_superstitions::ClientSnapshot {
+            available: report.render(snapshot.available),
+            held: report.render(snapshot.held),
+            total: report.render(snapshot.total),
+            ..snapshot
+        })
+        .collect();
+    serde_json::to_writer(&mut out, &snapshots)?;
+    writeln!(out)?;
+    out.flush()?;
+    Ok(())
+}
@@ -0,0 +1,141 @@
+use std::{
+    fs,
+    io::Write,
+    process::{Command, Stdio},
+};
+
+// Runs the binary on `path` and returns whatever it printed
+fn run(path: &str) -> Vec<u8> {
+    let output = Command::new(env!("CARGO_BIN_EXE_sailors-superstitions"))
+        .arg(path)
+        .output()
+        .expect("could not run the binary");
+    assert!(output.status.success());
+    output.stdout
+}
+
+// Runs the binary with `args`, feeding it `input` on stdin, and returns
+// whatever it printed to stdout and stderr
+fn run_with_stdin(args: &[&str], input: &str) -> (Vec<u8>, Vec<u8>) {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_sailors-superstitions"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("could not run the binary");
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    (output.stdout, output.stderr)
+}
+
+#[test]
+fn test_reads_stdin() {
+    let data = "type, client, tx, amount\ndeposit, 1, 1, 2.0\nwithdrawal, 1, 2, 0.5\n";
+    let expected = b"client,available,held,total,locked\n1,1.5,0,1.5,false\n";
+    assert_eq!(run_with_stdin(&[], data).0, expected);
+    assert_eq!(run_with_stdin(&["-"], data).0, expected);
+}
+
+#[test]
+fn test_optional_columns_per_row() {
+    // Only the transfer has a fifth column, which the header doesn't name
+    let data = "type, client, tx, amount
+deposit, 1, 1, 2.0
+transfer, 1, 2, 0.5, 2
+withdrawal, 1, 3, 0.5
+";
+    let (stdout, stderr) = run_with_stdin(&[], data);
+    assert!(stderr.is_empty());
+    assert_eq!(
+        stdout,
+        b"client,available,held,total,locked\n1,1.0,0,1.0,false\n2,0.5,0,0.5,false\n"
+    );
+}
+
+#[test]
+fn test_dry_run() {
+    let data = "type, client, tx, amount\ndeposit, 1, 1, 2.0\nwithdrawal, 1, 2, 5.0\n";
+    let (stdout, stderr) = run_with_stdin(&["--dry-run"], data);
+    assert!(stdout.is_empty());
+    assert_eq!(stderr, b"1 error\n");
+    // The preview is exactly what a real run prints
+    let (stdout, stderr) = run_with_stdin(&["--dry-run", "--print-preview"], data);
+    assert_eq!(stderr, b"1 error\n");
+    assert_eq!(stdout, run_with_stdin(&[], data).0);
+}
+
+#[test]
+fn test_output_file() {
+    let data = "type, client, tx, amount\ndeposit, 1, 1, 2.0\n";
+    let path = std::env::temp_dir().join(format!("report-{}.csv", std::process::id()));
+    let path = path.to_str().unwrap();
+    let (stdout, _) = run_with_stdin(&["--output", path], data);
+    assert!(stdout.is_empty());
+    let report = fs::read(path).unwrap();
+    fs::remove_file(path).unwrap();
+    assert_eq!(report, run_with_stdin(&[], data).0);
+
+    // A report that can't be written is a failed run
+    let status = Command::new(env!("CARGO_BIN_EXE_sailors-superstitions"))
+        .args(["--output", "/nonexistent/report.csv"])
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .expect("could not run the binary");
+    assert!(!status.success());
+}
+
+#[test]
+fn test_runs_are_byte_identical() {
+    // Plenty of clients, so a hash-ordered output would almost surely differ
+    let mut data = String::from("type, client, tx, amount\n");
+    for tx in 0..500u32 {
+        data.push_str(&format!("deposit, {}, {}, 1.5\n", (tx * 7919) % 400, tx));
+    }
+    let path = std::env::temp_dir().join(format!("deterministic-{}.csv", std::process::id()));
+    fs::write(&path, data).unwrap();
+    let path = path.to_str().unwrap();
+
+    let first = run(path);
+    let second = run(path);
+    fs::remove_file(path).unwrap();
+    assert_eq!(first, second);
+    assert!(first.starts_with(b"client,available,held,total,locked\n0,"));
+}
+
+#[test]
+fn test_warn_insolvent() {
+    let data = "type, client, tx, amount\ndeposit, 1, 1, 2.0\ndeposit, 2, 2, 1.0\ndispute, 2, 2,\nchargeback, 2, 2,\n";
+    let (stdout, stderr) = run_with_stdin(&["--warn-insolvent"], data);
+    assert_eq!(stderr, b"warning: client 2 is insolvent\n");
+    assert_eq!(stdout, run_with_stdin(&[], data).0);
+}
+
+#[test]
+fn test_locked_only() {
+    let data = "type, client, tx, amount\ndeposit, 1, 1, 2.0\ndeposit, 2, 2, 1.0\ndeposit, 3, 3, 4.0\ndispute, 2, 2,\nchargeback, 2, 2,\n";
+    let (stdout, _) = run_with_stdin(&["--locked-only"], data);
+    assert_eq!(
+        String::from_utf8_lossy(&stdout),
+        "client,available,held,total,locked\n2,0.0,0.0,0.0,true\n"
+    );
+}
+
+#[test]
+fn test_missing_file() {
+    let output = Command::new(env!("CARGO_BIN_EXE_sailors-superstitions"))
+        .arg("does-not-exist.csv")
+        .output()
+        .expect("could not run the binary");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("could not open does-not-exist.csv"));
+    assert!(!stderr.contains("panicked"));
+}
@@ -0,0 +1,196 @@
+use proptest::prelude::*;
+use rust_decimal::Decimal;
+use sailors_superstitions::{
+    ClientId, Engine, EngineError, Outcome, Transaction, TransactionKind, TransactionStatus, TxId,
+};
+
+// Anything goes: any kind, for any of a handful of clients, reusing ids freely
+fn arb_transaction() -> impl Strategy<Value = Transaction<Decimal>> {
+    let amount = (-100i64..10_000).prop_map(|n| Decimal::new(n, 2));
+    let kind = prop_oneof![
+        amount
+            .clone()
+            .prop_map(|amount| TransactionKind::Deposit { amount }),
+        amount.prop_map(|amount| TransactionKind::Withdrawal { amount }),
+        Just(TransactionKind::Dispute { reason: None }),
+        Just(TransactionKind::Resolve),
+        Just(TransactionKind::Chargeback),
+    ];
+    (kind, 0u16..4, 0u32..20)
+        .prop_map(|(kind, client, tx)| Transaction::new(kind, ClientId(client), TxId(tx)))
+}
+
+fn arb_transaction_sequence(max_len: usize) -> impl Strategy<Value = Vec<Transaction<Decimal>>> {
+    prop::collection::vec(arb_transaction(), 0..max_len)
+}
+
+// One step of a valid sequence. References are indices into the transactions
+// made so far, so they always land on one that exists.
+#[derive(Debug, Clone)]
+enum Step {
+    Deposit(u16, Decimal),
+    Withdrawal(u16, Decimal),
+    Dispute(usize),
+    Resolve(usize),
+    Chargeback(usize),
+}
+
+fn arb_step() -> impl Strategy<Value = Step> {
+    let amount = (1i64..10_000).prop_map(|n| Decimal::new(n, 2));
+    prop_oneof![
+        3 => (0u16..4, amount.clone()).prop_map(|(client, amount)| Step::Deposit(client, amount)),
+        2 => (0u16..4, amount).prop_map(|(client, amount)| Step::Withdrawal(client, amount)),
+        1 => any::<usize>().prop_map(Step::Dispute),
+        1 => any::<usize>().prop_map(Step::Resolve),
+        1 => any::<usize>().prop_map(Step::Chargeback),
+    ]
+}
+
+// Deposits and withdrawals get fresh ids, a client's first transaction is
+// always a deposit, and disputes and such only refer to transactions that exist
+fn arb_valid_sequence(max_len: usize) -> impl Strategy<Value = Vec<Transaction<Decimal>>> {
+    prop::collection::vec(arb_step(), 0..max_len).prop_map(|steps| {
+        let mut made: Vec<(u16, u32)> = Vec::new();
+        let mut txs = Vec::new();
+        for step in steps {
+            let next = made.len() as u32;
+            let reference = |i: usize| made.get(i % made.len().max(1)).copied();
+            let tx = match step {
+                Step::Deposit(client, amount) => {
+                    made.push((client, next));
+                    Transaction::new(
+                        TransactionKind::Deposit { amount },
+                        ClientId(client),
+                        TxId(next),
+                    )
+                }
+                Step::Withdrawal(client, amount) => {
+                    if !made.iter().any(|(c, _)| *c == client) {
+                        continue;
+                    }
+                    made.push((client, next));
+                    Transaction::new(
+                        TransactionKind::Withdrawal { amount },
+                        ClientId(client),
+                        TxId(next),
+                    )
+                }
+                Step::Dispute(i) | Step::Resolve(i) | Step::Chargeback(i) => {
+                    let Some((client, tx)) = reference(i) else {
+                        continue;
+                    };
+                    let kind = match step {
+                        Step::Dispute(_) => TransactionKind::Dispute { reason: None },
+                        Step::Resolve(_) => TransactionKind::Resolve,
+                        _ => TransactionKind::Chargeback,
+                    };
+                    Transaction::new(kind, ClientId(client), TxId(tx))
+                }
+            };
+            txs.push(tx);
+        }
+        txs
+    })
+}
+
+fn run(txs: &[Transaction<Decimal>]) -> (Engine<Decimal>, Vec<Result<Outcome, EngineError>>) {
+    let mut engine = Engine::new();
+    let results = txs.iter().map(|tx| engine.process(tx)).collect();
+    (engine, results)
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(1000))]
+
+    // Generated ids stay under 20, and client 100 is never locked
+    #[test]
+    fn dispute_of_unknown_tx_errors(txs in arb_transaction_sequence(50), tx in 20u32..) {
+        let (mut engine, _) = run(&txs);
+        let kinds = [TransactionKind::Dispute { reason: None }, TransactionKind::Resolve, TransactionKind::Chargeback];
+        for kind in kinds {
+            prop_assert_eq!(
+                engine.process(&Transaction::new(kind, ClientId(100), TxId(tx))),
+                Err(EngineError::TransactionNotFound(TxId(tx)))
+            );
+        }
+    }
+
+    #[test]
+    fn chargeback_after_resolve_errors(txs in arb_transaction_sequence(50)) {
+        let (mut engine, _) = run(&txs);
+        let resolved: Vec<_> = engine
+            .transactions()
+            .values()
+            .filter(|tx| tx.status() == TransactionStatus::Resolved)
+            .map(|tx| (tx.client, tx.tx))
+            .collect();
+        for (client, tx) in resolved {
+            prop_assert!(engine
+                .process(&Transaction::new(TransactionKind::Chargeback, client, tx))
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn failed_transactions_change_nothing(txs in arb_transaction_sequence(50)) {
+        let mut engine = Engine::new();
+        for tx in &txs {
+            let before = format!("{:?}", engine.clients());
+            if engine.process(tx).is_err() {
+                prop_assert_eq!(before, format!("{:?}", engine.clients()));
+            }
+        }
+    }
+
+    #[test]
+    fn held_is_never_negative(txs in arb_transaction_sequence(50)) {
+        let (engine, _) = run(&txs);
+        for client in engine.clients().values() {
+            prop_assert!(client.held >= Decimal::ZERO);
+        }
+    }
+
+    // Without disputes, nothing can take a client's available funds below zero
+    #[test]
+    fn available_is_never_negative_without_disputes(txs in arb_valid_sequence(50)) {
+        let txs: Vec<_> = txs
+            .into_iter()
+            .filter(|tx| {
+                matches!(
+                    tx.kind,
+                    TransactionKind::Deposit { .. } | TransactionKind::Withdrawal { .. }
+                )
+            })
+            .collect();
+        let (engine, results) = run(&txs);
+        for client in engine.clients().values() {
+            prop_assert!(client.available >= Decimal::ZERO);
+        }
+        // Every deposit in a valid sequence goes through
+        for (tx, result) in txs.iter().zip(&results) {
+            if matches!(tx.kind, TransactionKind::Deposit { .. }) {
+                prop_assert!(result.is_ok());
+            }
+        }
+    }
+
+    // A client's total is what they deposited minus what they withdrew, leaving
+    // out anything that was charged back
+    #[test]
+    fn totals_add_up(txs in arb_valid_sequence(50)) {
+        let (engine, _) = run(&txs);
+        for (id, client) in engine.clients() {
+            let expected: Decimal = engine
+                .transactions()
+                .values()
+                .filter(|tx| tx.client == *id && tx.status() != TransactionStatus::Chargeback)
+                .map(|tx| match tx.kind {
+                    TransactionKind::Deposit { amount } => amount,
+                    TransactionKind::Withdrawal { amount } => -amount,
+                    _ => Decimal::ZERO,
+                })
+                .sum();
+            prop_assert_eq!(client.total(), expected);
+        }
+    }
+}